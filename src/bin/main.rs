@@ -3,6 +3,7 @@ mod util;
 use std::{
     ops::Deref,
     str,
+    sync::atomic::{AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
@@ -16,10 +17,90 @@ use std::io::Write;
 use magic_wormhole::{transfer, transit::RelayUrl, Wormhole};
 use std::str::FromStr;
 
+/* Exit codes, so that scripts can tell the different failures apart */
+const EXIT_ERROR: i32 = 1;
+const EXIT_REJECTED: i32 = 2;
+const EXIT_CONNECTION: i32 = 3;
+const EXIT_WRONG_CODE: i32 = 4;
+const EXIT_TRANSFER: i32 = 5;
+const EXIT_SECURITY: i32 = 6;
+const EXIT_INTERRUPTED: i32 = 130;
+
+/** The transfer was rejected, either by us or by the other side */
+#[derive(Debug, thiserror::Error)]
+#[error("The transfer was rejected")]
+struct Rejected;
+
+/** We got Ctrl-C or SIGTERM */
+#[derive(Debug, thiserror::Error)]
+#[error("Interrupted")]
+struct Interrupted;
+
 #[async_std::main]
-async fn main() -> eyre::Result<()> {
-    color_eyre::install()?;
+async fn main() {
+    if let Err(error) = color_eyre::install() {
+        eprintln!("{:?}", error);
+    }
+    if let Err(error) = util::catch_interrupts(EXIT_INTERRUPTED) {
+        eprintln!("Failed to catch interrupts: {}", error);
+    }
+
+    let result = run().await;
+    if let Err(error) = util::write_transcript() {
+        eprintln!("Failed to write the transcript: {}", error);
+    }
+    match result {
+        Ok(()) => util::emit("result", serde_json::json!({ "success": true })),
+        Err(error) => {
+            let exit_code = exit_code(&error);
+            util::emit(
+                "result",
+                serde_json::json!({
+                    "success": false,
+                    "error": format!("{:#}", error),
+                    "exit_code": exit_code,
+                }),
+            );
+            eprintln!("Error: {:?}", error);
+            std::process::exit(exit_code);
+        },
+    }
+}
+
+/** Map an error to the exit code of the most specific failure class it belongs to */
+fn exit_code(error: &eyre::Report) -> i32 {
+    use magic_wormhole::{transfer::TransferError, SecurityError, WormholeError};
+
+    let any =
+        |predicate: fn(&(dyn std::error::Error + 'static)) -> bool| error.chain().any(predicate);
+
+    if any(|cause| cause.is::<Interrupted>()) {
+        EXIT_INTERRUPTED
+    } else if any(|cause| {
+        cause.is::<Rejected>()
+            || matches!(
+                cause.downcast_ref(),
+                Some(TransferError::OfferRejected { .. })
+            )
+    }) {
+        EXIT_REJECTED
+    } else if any(|cause| {
+        matches!(cause.downcast_ref(), Some(SecurityError::PakeFailed))
+            || cause.is::<magic_wormhole::ParseCodeError>()
+    }) {
+        EXIT_WRONG_CODE
+    } else if any(|cause| cause.is::<SecurityError>()) {
+        EXIT_SECURITY
+    } else if any(|cause| cause.is::<WormholeError>()) {
+        EXIT_CONNECTION
+    } else if any(|cause| cause.is::<TransferError>()) {
+        EXIT_TRANSFER
+    } else {
+        EXIT_ERROR
+    }
+}
 
+async fn run() -> eyre::Result<()> {
     /* Define some common arguments first */
 
     let relay_server_arg = Arg::with_name("relay-server")
@@ -28,12 +109,20 @@ async fn main() -> eyre::Result<()> {
         .takes_value(true)
         .multiple(true)
         .value_name("tcp:HOSTNAME:PORT")
-        .help("Use a custom relay server (specify multiple times for multiple relays)");
+        .help("Use a custom relay server (specify multiple times for multiple relays). Append ?token=TOKEN if it requires one; the token is not sent to the other side, so it needs the same option to use that relay. Relays reachable over WebSocket are given as wss://HOSTNAME[:PORT][/PATH]");
     let rendezvous_server_arg = Arg::with_name("rendezvous-server")
         .long("rendezvous-server")
         .takes_value(true)
         .value_name("ws:URL")
         .help("Use a custom rendezvous server. Both sides need to use the same value in order to find each other.");
+    let json_arg = Arg::with_name("json").long("json").help(
+        "Print machine-readable events as JSON lines on stdout. Everything else goes to stderr",
+    );
+    let transcript_arg = Arg::with_name("transcript")
+        .long("transcript")
+        .takes_value(true)
+        .value_name("FILE")
+        .help("Record all protocol messages into FILE (as JSON), with secrets redacted. Useful for bug reports");
     let log_arg = Arg::with_name("log")
         .long("log")
         .help("Enable logging to stdout, for debugging purposes");
@@ -60,6 +149,7 @@ async fn main() -> eyre::Result<()> {
         .help("Rename the received file or folder, overriding the name suggested by the sender.");
     let file_path = Arg::with_name("file-path")
         .long("out-dir")
+        .visible_alias("output-dir")
         .takes_value(true)
         .value_name("PATH")
         .required(true)
@@ -82,6 +172,22 @@ async fn main() -> eyre::Result<()> {
         .arg(relay_server_arg.clone())
         .arg(rendezvous_server_arg.clone())
         .arg(file_name.clone())
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("LEVEL")
+                .help("Send folders as compressed .tar.gz archives, optionally with a level from 0 (fastest) to 9 (smallest, default 6). \
+                       Without this, folders are sent uncompressed, which is best for already compressed content like videos."),
+        )
+        .arg(
+            Arg::with_name("skip-identical")
+                .long("skip-identical")
+                .help("Don't send the file if the receiver already has an identical copy. This reads the file twice, \
+                       which pays off for large files that rarely change."),
+        )
         .arg(
             Arg::with_name("file")
                 .index(1)
@@ -142,8 +248,13 @@ async fn main() -> eyre::Result<()> {
         .arg(
             Arg::with_name("noconfirm")
                 .long("noconfirm")
-                .visible_alias("yes")
-                .help("Accept file transfer without asking for confirmation"),
+                .visible_aliases(&["yes", "accept-file"])
+                .help("Accept file transfer without asking for confirmation. Existing files won't be overwritten."),
+        )
+        .arg(
+            Arg::with_name("fsync")
+                .long("fsync")
+                .help("Make sure the received file is safely on disk before reporting success. Useful for removable media."),
         )
         .arg(file_rename)
         .arg(file_path)
@@ -151,10 +262,24 @@ async fn main() -> eyre::Result<()> {
             Arg::with_name("code")
                 .index(1)
                 .value_name("CODE")
-                .help("Provide the code now rather than typing it interactively"),
+                .help("Provide the code now rather than typing it interactively. May also be a wormhole-transfer: link"),
         )
         .arg(relay_server_arg)
         .arg(rendezvous_server_arg);
+    let bench_command = SubCommand::with_name("bench")
+        .about("Measure the speed of the encrypted transit layer on this machine")
+        .after_help(
+            "Both sides of a transit connection run in this process and talk over the loopback \
+            interface. No server is contacted, so the results only depend on this machine.",
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .value_name("MiB")
+                .default_value("256")
+                .help("How much data to transfer"),
+        );
 
     /* The Clap application */
     let clap = App::new(crate_name!())
@@ -169,16 +294,30 @@ async fn main() -> eyre::Result<()> {
         .after_help(
             "Run a subcommand with `--help` to know how it's used.\n\
                      To send files, use `wormhole send <PATH>`.\n\
-                     To receive files, use `wormhole receive <CODE>`.",
+                     To receive files, use `wormhole receive <CODE>`.\n\n\
+                     Exit codes: 1 generic error, 2 transfer rejected, 3 connection failed, \
+                     4 wrong code, 5 transfer failed, 6 possible attack, 130 interrupted.",
         )
         .subcommand(send_command)
         .subcommand(send_many_command)
         .subcommand(receive_command)
+        .subcommand(bench_command)
         .subcommand(SubCommand::with_name("help").setting(AppSettings::Hidden))
-        .arg(log_arg);
+        .arg(log_arg)
+        .arg(json_arg)
+        .arg(transcript_arg);
     let matches = clap.get_matches();
 
-    let mut term = Term::stdout();
+    let mut term = if matches.is_present("json") {
+        util::enable_json_output();
+        Term::stderr()
+    } else {
+        Term::stdout()
+    };
+
+    if let Some(path) = matches.value_of_os("transcript") {
+        util::enable_transcript(path.into());
+    }
 
     if matches.is_present("log") {
         env_logger::builder()
@@ -219,7 +358,7 @@ async fn main() -> eyre::Result<()> {
 
     if let Some(matches) = matches.subcommand_matches("send") {
         let file_path = matches.value_of_os("file").unwrap();
-        let file_name = file_name(file_path)?;
+        let mut file_name = file_name(file_path)?;
 
         eyre::ensure!(
             std::path::Path::new(file_path).exists(),
@@ -227,13 +366,44 @@ async fn main() -> eyre::Result<()> {
             file_path
         );
 
-        let (mut wormhole, _code, relay_server) =
-            parse_and_connect(&mut term, matches, true).await?;
+        let compression = if matches.is_present("compress") {
+            let level = matches
+                .value_of("compress")
+                .map(u32::from_str)
+                .transpose()?
+                .unwrap_or(6);
+            eyre::ensure!(level <= 9, "The compression level must be between 0 and 9");
+            if std::path::Path::new(file_path).is_dir() && !matches.is_present("file-name") {
+                file_name.push(".gz");
+            }
+            transfer::FolderCompression::Gzip(level)
+        } else {
+            transfer::FolderCompression::Store
+        };
+
+        let (mut wormhole, _code, relay_servers, _) =
+            interruptible(parse_and_connect(&mut term, matches, true)).await?;
 
-        send(&mut wormhole, &relay_server, file_path, &file_name).await?;
-        wormhole.close().await?;
+        let result = interruptible(send(
+            &mut wormhole,
+            &relay_servers,
+            file_path,
+            &file_name,
+            compression,
+            matches.is_present("skip-identical"),
+        ))
+        .await;
+        let stats = match result {
+            Ok(stats) => stats,
+            Err(error) => return Err(abort(wormhole, error).await),
+        };
+        wormhole.close().await.into_result()?;
+        if let Some(stats) = stats {
+            print_peer_stats(&mut term, &stats)?;
+        }
     } else if let Some(matches) = matches.subcommand_matches("send-many") {
-        let (wormhole, code, relay_server) = parse_and_connect(&mut term, matches, true).await?;
+        let (wormhole, code, relay_servers, app_config) =
+            interruptible(parse_and_connect(&mut term, matches, true)).await?;
         let timeout =
             Duration::from_secs(u64::from_str(matches.value_of("timeout").unwrap())? * 60);
         let max_tries = u64::from_str(matches.value_of("tries").unwrap())?;
@@ -242,7 +412,8 @@ async fn main() -> eyre::Result<()> {
         let file_name = file_name(file_path)?;
 
         send_many(
-            relay_server,
+            app_config,
+            relay_servers,
             &code,
             file_path,
             &file_name,
@@ -255,17 +426,26 @@ async fn main() -> eyre::Result<()> {
     } else if let Some(matches) = matches.subcommand_matches("receive") {
         let file_path = matches.value_of_os("file-path").unwrap();
 
-        let (mut wormhole, _code, relay_server) =
-            parse_and_connect(&mut term, matches, false).await?;
+        let (mut wormhole, _code, relay_servers, _) =
+            interruptible(parse_and_connect(&mut term, matches, false)).await?;
 
-        receive(
+        let result = interruptible(receive(
+            &mut term,
             &mut wormhole,
-            &relay_server,
+            &relay_servers,
             file_path,
             matches.value_of_os("file-name"),
-        )
-        .await?;
-        wormhole.close().await?;
+            matches.is_present("noconfirm"),
+            matches.is_present("fsync"),
+        ))
+        .await;
+        if let Err(error) = result {
+            return Err(abort(wormhole, error).await);
+        }
+        wormhole.close().await.into_result()?;
+    } else if let Some(matches) = matches.subcommand_matches("bench") {
+        let size = u64::from_str(matches.value_of("size").unwrap())? * 1024 * 1024;
+        interruptible(bench(&mut term, size)).await?;
     } else if let Some(_matches) = matches.subcommand_matches("help") {
         println!("Use --help to get help");
         std::process::exit(1);
@@ -276,42 +456,115 @@ async fn main() -> eyre::Result<()> {
     Ok(())
 }
 
+/**
+ * Run `future` until it is done, or until we get interrupted
+ *
+ * Interrupting drops the future, which cancels whatever it was doing (including transit connections).
+ */
+async fn interruptible<T>(
+    future: impl std::future::Future<Output = eyre::Result<T>>,
+) -> eyre::Result<T> {
+    use async_std::prelude::FutureExt;
+
+    future
+        .race(async {
+            util::interrupted().await;
+            Err(Interrupted.into())
+        })
+        .await
+}
+
+/**
+ * Close the wormhole after an error, and pass the error on
+ *
+ * This releases the nameplate and closes the mailbox right away, instead of leaving them
+ * to expire on the server.
+ */
+async fn abort(wormhole: Wormhole, error: eyre::Report) -> eyre::Report {
+    let status = wormhole.close_with_mood(magic_wormhole::Mood::Errory).await;
+    if let Err(close_error) = status.into_result() {
+        log::debug!("Failed to close the wormhole cleanly: {}", close_error);
+    }
+    error
+}
+
+/** Report the progress of connecting as `status` events, see [`magic_wormhole::WormholeEvent`] */
+fn emit_status(event: &magic_wormhole::WormholeEvent) {
+    util::emit("status", serde_json::to_value(event).unwrap());
+}
+
 /**
  * Parse the necessary command line arguments to establish an initial server connection.
  * This is used over and over again by the different subcommands.
  *
  * If this `is_send` and the code is not specified via the CLI, then a code will be allocated.
  * Otherwise, the user will be prompted interactively to enter it.
+ *
+ * Also returns the app config the wormhole was connected with, for reconnecting to the same server.
  */
 async fn parse_and_connect(
     term: &mut Term,
     matches: &clap::ArgMatches<'_>,
     is_send: bool,
-) -> eyre::Result<(Wormhole, magic_wormhole::Code, RelayUrl)> {
-    let relay_server: RelayUrl = matches
-        .value_of("relay-server")
-        .unwrap_or(magic_wormhole::transit::DEFAULT_RELAY_SERVER)
-        .parse()
-        .unwrap();
-    let rendezvous_server = matches
+) -> eyre::Result<(
+    Wormhole,
+    magic_wormhole::Code,
+    Vec<RelayUrl>,
+    magic_wormhole::AppConfig<transfer::AppVersion>,
+)> {
+    let relay_servers: Vec<RelayUrl> = matches
+        .values_of("relay-server")
+        .map(|values| values.collect())
+        .unwrap_or_else(|| vec![magic_wormhole::transit::DEFAULT_RELAY_SERVER])
+        .into_iter()
+        .map(|url| url.parse().map_err(|err| eyre::eyre!("{}: {}", err, url)))
+        .collect::<Result<_, _>>()?;
+    let mut rendezvous_server = matches
         .value_of("rendezvous-server")
         .unwrap_or(magic_wormhole::rendezvous::DEFAULT_RENDEZVOUS_SERVER)
         .to_string();
-    let code = matches
-        .value_of("code")
-        .map(ToOwned::to_owned)
-        .or_else(|| (!is_send).then(|| enter_code().expect("TODO handle this gracefully")))
-        .map(magic_wormhole::Code);
+    let code = match matches.value_of("code") {
+        Some(code) => Some(code.to_owned()),
+        None if !is_send => Some(enter_code(term, &rendezvous_server).await?),
+        None => None,
+    };
+    /* Codes may also be given as links */
+    let code = match code {
+        Some(code)
+            if matches!(
+                code.get(..transfer::URI_SCHEME.len()),
+                Some(scheme) if scheme.eq_ignore_ascii_case(transfer::URI_SCHEME)
+            ) =>
+        {
+            let uri: transfer::WormholeTransferUri = code.parse()?;
+            /* The one following a leader URI has to send, otherwise they receive */
+            match (uri.is_leader, is_send) {
+                (true, false) => eyre::bail!(
+                    "This code is for sending a file to its owner, please use `wormhole send` instead"
+                ),
+                (false, true) => eyre::bail!(
+                    "This code is for receiving a file from its owner, please use `wormhole receive` instead"
+                ),
+                _ => (),
+            }
+            if let Some(server) = uri.rendezvous_server {
+                rendezvous_server = server;
+            }
+            Some(uri.code)
+        },
+        Some(code) => Some(magic_wormhole::Code::parse(&code)?),
+        None => None,
+    };
+    let app_config = transfer::APP_CONFIG
+        .rendezvous_url(rendezvous_server.clone().into())
+        .on_event(emit_status);
     let (wormhole, code) = match code {
         Some(code) => {
             if is_send {
-                sender_print_code(term, &code)?;
+                sender_print_code(term, &code, &rendezvous_server)?;
             }
-            let (server_welcome, wormhole) = magic_wormhole::Wormhole::connect_with_code(
-                transfer::APP_CONFIG.rendezvous_url(rendezvous_server.into()),
-                code,
-            )
-            .await?;
+            let (server_welcome, wormhole) =
+                magic_wormhole::Wormhole::connect_with_code(app_config.clone(), code).await?;
             print_welcome(term, &server_welcome)?;
             (wormhole, server_welcome.code)
         },
@@ -322,21 +575,23 @@ async fn parse_and_connect(
                 .parse()
                 .expect("TODO error handling");
 
-            let (server_welcome, connector) = magic_wormhole::Wormhole::connect_without_code(
-                transfer::APP_CONFIG.rendezvous_url(rendezvous_server.into()),
-                numwords,
-            )
-            .await?;
+            let (server_welcome, connector) =
+                magic_wormhole::Wormhole::connect_without_code(app_config.clone(), numwords)
+                    .await?;
             print_welcome(term, &server_welcome)?;
             if is_send {
-                sender_print_code(term, &server_welcome.code)?;
+                sender_print_code(term, &server_welcome.code, &rendezvous_server)?;
             }
             let wormhole = connector.await?;
             (wormhole, server_welcome.code)
         },
     };
     writeln!(term, "Successfully connected to peer.")?;
-    eyre::Result::<_>::Ok((wormhole, code, relay_server))
+    util::emit(
+        "connected",
+        serde_json::json!({ "verifier": hex::encode(*wormhole.verifier) }),
+    );
+    eyre::Result::<_>::Ok((wormhole, code, relay_servers, app_config))
 }
 
 fn create_progress_bar(file_size: u64) -> ProgressBar {
@@ -352,13 +607,185 @@ fn create_progress_bar(file_size: u64) -> ProgressBar {
     pb
 }
 
-fn enter_code() -> eyre::Result<String> {
-    use dialoguer::Input;
+/**
+ * Prompt the user for a code, with tab completion
+ *
+ * The nameplate is completed from the ones currently in use on the server. They are fetched
+ * in the background and refreshed every [`NAMEPLATE_REFRESH_INTERVAL`] while the prompt is open,
+ * so that codes allocated in the meantime show up too. The words are completed from the wordlist,
+ * which works even if the server can't be reached.
+ */
+async fn enter_code(term: &mut Term, rendezvous_server: &str) -> eyre::Result<String> {
+    /* Without a terminal, there's nothing to complete */
+    if !term.is_term() {
+        use dialoguer::Input;
 
-    Input::new()
-        .with_prompt("Enter code")
-        .interact_text()
-        .map_err(From::from)
+        return Input::new()
+            .with_prompt("Enter code")
+            .interact_text()
+            .map_err(From::from);
+    }
+
+    let nameplates = Arc::new(std::sync::Mutex::new(None));
+    let (stop, stopped) = async_std::channel::bounded(1);
+    let refresher = async_std::task::spawn(refresh_nameplates(
+        rendezvous_server.to_owned(),
+        nameplates.clone(),
+        stopped,
+    ));
+    let result = read_code(term, &nameplates).await;
+    /* Closing the channel tells the refresher to disconnect */
+    std::mem::drop(stop);
+    refresher.await;
+    result
+}
+
+/* Read a code key by key, completing it on <Tab> */
+async fn read_code(
+    term: &mut Term,
+    nameplates: &std::sync::Mutex<Option<Vec<String>>>,
+) -> eyre::Result<String> {
+    use console::Key;
+
+    let wordlist = magic_wormhole::wordlist::default_wordlist(2);
+    let mut code = String::new();
+
+    loop {
+        term.clear_line()?;
+        write!(term, "Enter code: {}", code)?;
+        term.flush()?;
+
+        let key = {
+            let term = term.clone();
+            async_std::task::spawn_blocking(move || term.read_key()).await?
+        };
+        match key {
+            Key::Enter if !code.is_empty() => {
+                writeln!(term)?;
+                break Ok(code);
+            },
+            Key::Backspace => {
+                code.pop();
+            },
+            Key::Char(c) if !c.is_control() && !c.is_whitespace() => code.push(c),
+            Key::Tab => {
+                let completions: Vec<String> = match code.find('-') {
+                    None => match &*nameplates.lock().unwrap() {
+                        Some(nameplates) => nameplates
+                            .iter()
+                            .filter(|nameplate| nameplate.starts_with(&code))
+                            .map(|nameplate| format!("{}-", nameplate))
+                            .collect(),
+                        /* Without the server, take the nameplate as typed and go on with the words */
+                        None if !code.is_empty() && code.chars().all(|c| c.is_ascii_digit()) => {
+                            vec![format!("{}-", code)]
+                        },
+                        None => Vec::new(),
+                    },
+                    Some(dash) => wordlist
+                        .get_completions(&code[dash + 1..])
+                        .into_iter()
+                        .map(|words| format!("{}-{}", &code[..dash], words))
+                        .collect(),
+                };
+
+                let prefix = util::common_prefix(&completions);
+                if prefix.len() > code.len() {
+                    code = prefix.to_owned();
+                } else if completions.len() > 1 {
+                    writeln!(term)?;
+                    writeln!(term, "{}", completions.join("  "))?;
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/** How often the nameplates for code completion are refreshed while the user types */
+const NAMEPLATE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/*
+ * Keep the nameplates up to date, until `stopped` gets closed
+ *
+ * This stays connected to the server in between, and only reconnects if something went wrong.
+ */
+async fn refresh_nameplates(
+    rendezvous_server: String,
+    nameplates: Arc<std::sync::Mutex<Option<Vec<String>>>>,
+    stopped: async_std::channel::Receiver<()>,
+) {
+    use async_std::{future::timeout, prelude::FutureExt};
+
+    let mut server = None;
+    let mut warned = false;
+    loop {
+        let result = async {
+            Some(
+                timeout(
+                    NAMEPLATE_REFRESH_INTERVAL,
+                    list_nameplates(&mut server, &rendezvous_server),
+                )
+                .await,
+            )
+        }
+        .race(async {
+            let _ = stopped.recv().await;
+            None
+        })
+        .await;
+        match result {
+            Some(Ok(Ok(list))) => *nameplates.lock().unwrap() = Some(list),
+            /* Don't interrupt the prompt every few seconds while the server is unreachable */
+            Some(Ok(Err(error))) if !warned => {
+                log::warn!("Could not fetch the nameplates: {}", error);
+                warned = true;
+                server = None;
+            },
+            Some(Ok(Err(error))) => {
+                log::debug!("Could not fetch the nameplates: {}", error);
+                server = None;
+            },
+            Some(Err(_)) => {
+                log::debug!("Fetching the nameplates timed out");
+                server = None;
+            },
+            /* Stopped in the middle of a request, the connection is not in a clean state anymore */
+            None => return,
+        }
+        /* Receiving only finishes once the channel is closed */
+        if timeout(NAMEPLATE_REFRESH_INTERVAL, stopped.recv())
+            .await
+            .is_ok()
+        {
+            break;
+        }
+    }
+    if let Some(server) = server {
+        if let Err(error) = server.shutdown(magic_wormhole::Mood::Happy).await {
+            log::debug!("Failed to disconnect from the server: {}", error);
+        }
+    }
+}
+
+/** Ask the server which nameplates are currently in use, connecting to it first if needed */
+async fn list_nameplates(
+    server: &mut Option<magic_wormhole::rendezvous::RendezvousServer>,
+    rendezvous_server: &str,
+) -> eyre::Result<Vec<String>> {
+    if server.is_none() {
+        let (connected, _welcome) = magic_wormhole::rendezvous::RendezvousServer::connect(
+            &transfer::APPID,
+            rendezvous_server,
+        )
+        .await?;
+        *server = Some(connected);
+    }
+    let nameplates = server.as_mut().unwrap().list_nameplates().await?;
+    Ok(nameplates
+        .into_iter()
+        .map(|nameplate| nameplate.0)
+        .collect())
 }
 
 fn print_welcome(term: &mut Term, welcome: &magic_wormhole::WormholeWelcome) -> eyre::Result<()> {
@@ -368,7 +795,22 @@ fn print_welcome(term: &mut Term, welcome: &magic_wormhole::WormholeWelcome) ->
     Ok(())
 }
 
-fn sender_print_code(term: &mut Term, code: &magic_wormhole::Code) -> eyre::Result<()> {
+fn sender_print_code(
+    term: &mut Term,
+    code: &magic_wormhole::Code,
+    rendezvous_server: &str,
+) -> eyre::Result<()> {
+    let mut uri = transfer::WormholeTransferUri::new(code.clone());
+    if rendezvous_server != magic_wormhole::rendezvous::DEFAULT_RENDEZVOUS_SERVER {
+        uri = uri.rendezvous_server(rendezvous_server);
+    }
+    util::emit(
+        "code",
+        serde_json::json!({
+            "code": code.to_string(),
+            "uri": uri.to_string(),
+        }),
+    );
     writeln!(term, "This wormhole's code is: {}", &code)?;
     writeln!(term, "On the other computer, please run:\n")?;
     writeln!(term, "wormhole receive {}\n", &code)?;
@@ -377,33 +819,197 @@ fn sender_print_code(term: &mut Term, code: &magic_wormhole::Code) -> eyre::Resu
 
 async fn send(
     wormhole: &mut Wormhole,
-    relay_server: &RelayUrl,
+    relay_servers: &[RelayUrl],
     file_path: &std::ffi::OsStr,
     file_name: &std::ffi::OsStr,
-) -> eyre::Result<()> {
-    let pb = create_progress_bar(0);
-    let pb2 = pb.clone();
+    compression: transfer::FolderCompression,
+    skip_identical: bool,
+) -> eyre::Result<Option<transfer::TransferStats>> {
+    /* Folders are sent as tar archives created on the fly, so only files have a checksum */
+    if util::json_output() && std::path::Path::new(file_path).is_file() {
+        let mut file = async_std::fs::File::open(file_path).await?;
+        let mut hasher = HashWriter::new(futures::io::sink());
+        futures::io::copy(&mut file, &mut hasher).await?;
+        util::emit(
+            "checksum",
+            serde_json::json!({ "sha256": hasher.hex_digest() }),
+        );
+    }
+
+    /* Sparse files may be sent without their holes */
+    if std::path::Path::new(file_path).is_file() {
+        let mut file = async_std::fs::File::open(file_path).await?;
+        let file_size = file.metadata().await?.len();
+        if let Some(data) = util::data_segments(&file, file_size).await? {
+            log::debug!("Sending sparse file with data in {:?}", data);
+            return transfer::send_sparse_file(
+                wormhole,
+                relay_servers,
+                &mut file,
+                file_name,
+                file_size,
+                transfer::OfferMetadata::default(),
+                &data,
+                progress_handler(create_progress_bar(0)),
+            )
+            .await
+            .map_err(From::from);
+        }
+    }
+
+    if std::path::Path::new(file_path).is_dir() {
+        return transfer::send_folder_with_compression(
+            wormhole,
+            relay_servers,
+            file_path,
+            file_name,
+            compression,
+            progress_handler(create_progress_bar(0)),
+        )
+        .await
+        .map_err(From::from);
+    }
+
+    if skip_identical {
+        let mut file = async_std::fs::File::open(file_path).await?;
+        let file_size = file.metadata().await?.len();
+        return transfer::send_file_unless_identical(
+            wormhole,
+            relay_servers,
+            &mut file,
+            file_name,
+            file_size,
+            transfer::OfferMetadata::default(),
+            progress_handler(create_progress_bar(0)),
+        )
+        .await
+        .map_err(From::from);
+    }
+
     transfer::send_file_or_folder(
         wormhole,
-        relay_server,
+        relay_servers,
         file_path,
         file_name,
-        move |sent, total| {
-            if sent == 0 {
-                pb.reset_elapsed();
-                pb.set_length(total);
-                pb.enable_steady_tick(250);
-            }
-            pb.set_position(sent);
-        },
+        progress_handler(create_progress_bar(0)),
     )
-    .await?;
-    pb2.finish();
+    .await
+    .map_err(From::from)
+}
+
+/** Tell how the transfer went on the other side */
+fn print_peer_stats(term: &mut Term, stats: &transfer::TransferStats) -> eyre::Result<()> {
+    if stats.already_present {
+        writeln!(
+            term,
+            "The other side already has this file, nothing was sent"
+        )?;
+        util::emit("already_present", serde_json::json!({}));
+        return Ok(());
+    }
+    /* Too fast to measure */
+    let throughput = Some(stats.elapsed.as_secs_f64())
+        .filter(|seconds| *seconds > 0.0)
+        .map(|seconds| stats.bytes_received as f64 / seconds);
+    match throughput {
+        Some(throughput) => writeln!(
+            term,
+            "The other side received {} in {:.2?} ({:.1} MiB/s)",
+            indicatif::HumanBytes(stats.bytes_received),
+            stats.elapsed,
+            throughput / 1024.0 / 1024.0
+        )?,
+        None => writeln!(
+            term,
+            "The other side received {}",
+            indicatif::HumanBytes(stats.bytes_received),
+        )?,
+    }
+    util::emit(
+        "peer_stats",
+        serde_json::json!({
+            "bytes": stats.bytes_received,
+            "seconds": stats.elapsed.as_secs_f64(),
+            "bytes_per_second": throughput,
+        }),
+    );
     Ok(())
 }
 
+/** The progress bar for humans, or progress events for machines */
+fn progress_handler(pb: ProgressBar) -> Box<dyn FnMut(u64, u64)> {
+    if !util::json_output() {
+        return Box::new(transfer::indicatif_progress(pb));
+    }
+    /* Don't flood the output, one event per percent is enough */
+    let mut last_percent = None;
+    Box::new(move |done, total| {
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            util::emit(
+                "progress",
+                serde_json::json!({ "done": done, "total": total }),
+            );
+        }
+    })
+}
+
+/** Pass everything through to the inner writer, and calculate the SHA-256 of it on the way */
+struct HashWriter<W> {
+    inner: W,
+    hasher: sha2::Sha256,
+}
+
+impl<W> HashWriter<W> {
+    fn new(inner: W) -> Self {
+        use sha2::Digest;
+        Self {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    fn hex_digest(self) -> String {
+        use sha2::Digest;
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<W: futures::AsyncWrite + Unpin> futures::AsyncWrite for HashWriter<W> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use sha2::Digest;
+        let this = &mut *self;
+        let result = std::pin::Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(written)) = result {
+            this.hasher.update(&buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn send_many(
-    relay_server: RelayUrl,
+    app_config: magic_wormhole::AppConfig<transfer::AppVersion>,
+    relay_servers: Vec<RelayUrl>,
     code: &magic_wormhole::Code,
     file_path: &std::ffi::OsStr,
     file_name: &std::ffi::OsStr,
@@ -421,40 +1027,12 @@ async fn send_many(
 
     let file_path = Arc::new(file_path.to_owned());
     let file_name = Arc::new(file_name.to_owned());
-    let url = Arc::new(relay_server);
+    let url = Arc::new(relay_servers);
 
     let time = Instant::now();
 
     /* Special-case the first send with reusing the existing connection */
-    send_in_background(
-        Arc::clone(&url),
-        Arc::clone(&file_path),
-        Arc::clone(&file_name),
-        wormhole,
-        term.clone(),
-        // &mp,
-    )
-    .await?;
-
-    for tries in 0.. {
-        if time.elapsed() >= timeout {
-            writeln!(
-                term,
-                "{:?} have elapsed, we won't accept any new connections now.",
-                timeout
-            )?;
-            break;
-        }
-        if tries > max_tries {
-            writeln!(
-                term,
-                "Max number of tries reached, we won't accept any new connections now."
-            )?;
-            break;
-        }
-
-        let (_server_welcome, wormhole) =
-            magic_wormhole::Wormhole::connect_with_code(transfer::APP_CONFIG, code.clone()).await?;
+    let mut tasks = vec![
         send_in_background(
             Arc::clone(&url),
             Arc::clone(&file_path),
@@ -463,23 +1041,67 @@ async fn send_many(
             term.clone(),
             // &mp,
         )
-        .await?;
+        .await?,
+    ];
+
+    let result = async {
+        for tries in 0.. {
+            if time.elapsed() >= timeout {
+                writeln!(
+                    term,
+                    "{:?} have elapsed, we won't accept any new connections now.",
+                    timeout
+                )?;
+                break;
+            }
+            if tries > max_tries {
+                writeln!(
+                    term,
+                    "Max number of tries reached, we won't accept any new connections now."
+                )?;
+                break;
+            }
+
+            let (_server_welcome, wormhole) = interruptible(async {
+                Ok(
+                    magic_wormhole::Wormhole::connect_with_code(app_config.clone(), code.clone())
+                        .await?,
+                )
+            })
+            .await?;
+            tasks.push(
+                send_in_background(
+                    Arc::clone(&url),
+                    Arc::clone(&file_path),
+                    Arc::clone(&file_name),
+                    wormhole,
+                    term.clone(),
+                    // &mp,
+                )
+                .await?,
+            );
+        }
+        eyre::Result::<_>::Ok(())
     }
+    .await;
+
+    /* Let the running transfers finish, or clean up after them if we got interrupted */
+    futures::future::join_all(tasks).await;
 
     async fn send_in_background(
-        url: Arc<RelayUrl>,
+        url: Arc<Vec<RelayUrl>>,
         file_name: Arc<std::ffi::OsString>,
         file_path: Arc<std::ffi::OsString>,
         mut wormhole: Wormhole,
         mut term: Term,
         // mp: &MultiProgress,
-    ) -> eyre::Result<()> {
+    ) -> eyre::Result<async_std::task::JoinHandle<()>> {
         writeln!(&mut term, "Sending file to peer").unwrap();
         // let pb = create_progress_bar(file_size);
         // let pb = mp.add(pb);
-        async_std::task::spawn(async move {
+        Ok(async_std::task::spawn(async move {
             // let pb2 = pb.clone();
-            let result = async move {
+            let result = interruptible(async {
                 transfer::send_file_or_folder(
                     &mut wormhole,
                     &url,
@@ -494,9 +1116,18 @@ async fn send_many(
                     },
                 )
                 .await?;
-                eyre::Result::<_>::Ok(wormhole.close().await?)
+                eyre::Result::<_>::Ok(())
+            })
+            .await;
+            let result = match result {
+                Ok(()) => wormhole
+                    .close()
+                    .await
+                    .into_result()
+                    .map_err(eyre::Report::from),
+                Err(error) => Err(abort(wormhole, error).await),
             };
-            match result.await {
+            match result {
                 Ok(_) => {
                     // pb.finish();
                     writeln!(&mut term, "Successfully sent file to peer").unwrap();
@@ -506,20 +1137,67 @@ async fn send_many(
                     writeln!(&mut term, "Send failed, {}", e).unwrap();
                 },
             };
-        });
-        Ok(())
+        }))
     }
 
+    result
+}
+
+/** Send `size` bytes over a loopback transit connection, and report the throughput */
+async fn bench(term: &mut Term, size: u64) -> eyre::Result<()> {
+    let (mut leader, mut follower) = magic_wormhole::transit::loopback().await?;
+
+    /* Same record size as the file transfer */
+    let record = vec![0x42; 4096];
+    let start = Instant::now();
+    let sender = async {
+        let mut sent = 0;
+        while sent < size {
+            let len = (size - sent).min(record.len() as u64) as usize;
+            leader.send_record(&record[..len]).await?;
+            sent += len as u64;
+        }
+        eyre::Result::<_>::Ok(())
+    };
+    let receiver = async {
+        let mut received = 0;
+        while received < size {
+            received += follower.receive_record().await?.len() as u64;
+        }
+        eyre::Result::<_>::Ok(())
+    };
+    futures::try_join!(sender, receiver)?;
+    let elapsed = start.elapsed();
+
+    let throughput = size as f64 / elapsed.as_secs_f64();
+    writeln!(
+        term,
+        "Transferred {} MiB in {:.2?} ({:.1} MiB/s)",
+        size / 1024 / 1024,
+        elapsed,
+        throughput / 1024.0 / 1024.0
+    )?;
+    util::emit(
+        "benchmark",
+        serde_json::json!({
+            "bytes": size,
+            "seconds": elapsed.as_secs_f64(),
+            "bytes_per_second": throughput,
+        }),
+    );
     Ok(())
 }
 
 async fn receive(
+    term: &mut Term,
     wormhole: &mut Wormhole,
-    relay_server: &RelayUrl,
+    relay_servers: &[RelayUrl],
     target_dir: &std::ffi::OsStr,
     file_name: Option<&std::ffi::OsStr>,
+    noconfirm: bool,
+    fsync: bool,
 ) -> eyre::Result<()> {
-    let req = transfer::request_file(wormhole, relay_server).await?;
+    let req = transfer::request_file(wormhole, relay_servers).await?;
 
     /*
      * Control flow is a bit tricky here:
@@ -527,19 +1205,22 @@ async fn receive(
      * - Then, we check if the file already exists
      * - If it exists, ask whether to overwrite and act accordingly
      * - If it doesn't, directly accept, but DON'T overwrite any files
+     * With `noconfirm`, we don't ask and never overwrite.
      */
 
-    if !util::ask_user(
-        format!(
-            "Receive file '{}' (size: {} bytes)?",
-            req.filename.display(),
-            req.filesize
-        ),
-        true,
-    )
-    .await
+    if !noconfirm
+        && !util::ask_user(
+            format!(
+                "Receive file '{}' (size: {} bytes)?",
+                req.filename.display(),
+                req.filesize
+            ),
+            true,
+        )
+        .await
     {
-        return Ok(req.reject().await?);
+        req.reject().await?;
+        eyre::bail!(Rejected);
     }
 
     let file_name = file_name
@@ -547,37 +1228,146 @@ async fn receive(
         .ok_or_else(|| eyre::format_err!("The sender did not specify a valid file name, and neither did you. Try using --rename."))?;
     let file_path = std::path::Path::new(target_dir).join(file_name);
 
-    let pb = create_progress_bar(req.filesize);
+    /* Nothing to do if we have it already. This only works if the sender included the hash */
+    if file_path.is_file() && std::fs::metadata(&file_path)?.len() == req.filesize {
+        let mut file = async_std::fs::File::open(&file_path).await?;
+        if req.is_identical_to(&mut file).await? {
+            req.skip_identical().await?;
+            writeln!(term, "{} is already up to date", file_path.display())?;
+            util::emit(
+                "already_present",
+                serde_json::json!({ "path": file_path.display().to_string() }),
+            );
+            return Ok(());
+        }
+    }
 
-    let on_progress = move |received, _total| {
-        pb.set_position(received);
-    };
+    let on_progress = progress_handler(create_progress_bar(req.filesize));
 
     /* Then, accept if the file exists */
     if !file_path.exists() {
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .write(true)
             .create_new(true)
             .open(&file_path)
             .await?;
-        return Ok(req.accept(on_progress, &mut file).await?);
+        if fsync {
+            util::sync_parent_dir(&file_path).await?;
+        }
+        return accept(req, on_progress, file, &file_path, true, fsync).await;
     }
 
     /* If there is a collision, ask whether to overwrite */
+    if noconfirm {
+        req.reject().await?;
+        return Err(eyre::Report::new(Rejected).wrap_err(format!(
+            "Refusing to overwrite existing file {}",
+            file_path.display()
+        )));
+    }
     if !util::ask_user(
         format!("Override existing file {}?", file_path.display()),
         false,
     )
     .await
     {
-        return Ok(req.reject().await?);
+        req.reject().await?;
+        eyre::bail!(Rejected);
     }
 
-    let mut file = OpenOptions::new()
+    let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
         .open(&file_path)
         .await?;
-    Ok(req.accept(on_progress, &mut file).await?)
+    accept(req, on_progress, file, &file_path, false, fsync).await
+}
+
+async fn accept(
+    req: transfer::ReceiveRequest<'_>,
+    mut on_progress: impl FnMut(u64, u64) + 'static,
+    file: async_std::fs::File,
+    file_path: &std::path::Path,
+    created: bool,
+    fsync: bool,
+) -> eyre::Result<()> {
+    let received = Arc::new(AtomicU64::new(0));
+    let partial = PartialFile {
+        file: Some(file),
+        path: file_path,
+        created,
+        received: Arc::clone(&received),
+        done: false,
+    };
+    let on_progress = move |sent, total| {
+        received.store(sent, Ordering::Relaxed);
+        on_progress(sent, total)
+    };
+
+    /* Fail early if there is not enough space */
+    if let Err(err) = util::preallocate(partial.file(), req.filesize).await {
+        std::mem::drop(partial);
+        req.reject().await?;
+        return Err(eyre::Report::new(err).wrap_err("Failed to allocate space for the file"));
+    }
+    let mut writer = HashWriter::new(partial.file());
+    if fsync {
+        req.accept_and_sync(on_progress, &mut writer, partial.file())
+            .await?;
+    } else {
+        req.accept(on_progress, &mut writer).await?;
+    }
+    util::emit(
+        "checksum",
+        serde_json::json!({ "sha256": writer.hex_digest() }),
+    );
+    partial.keep();
+    Ok(())
+}
+
+/**
+ * A file that is being received into
+ *
+ * Unless [`keep`](Self::keep) gets called, dropping it cleans up after the failed or interrupted
+ * transfer: a file we created is removed, an existing one is truncated to the bytes actually received,
+ * so that no preallocated zeros are left behind.
+ */
+struct PartialFile<'a> {
+    file: Option<async_std::fs::File>,
+    path: &'a std::path::Path,
+    created: bool,
+    received: Arc<AtomicU64>,
+    done: bool,
+}
+
+impl PartialFile<'_> {
+    fn file(&self) -> &async_std::fs::File {
+        self.file.as_ref().unwrap()
+    }
+
+    fn keep(mut self) {
+        self.done = true;
+    }
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        /* Close the file first, some platforms can't remove open files */
+        std::mem::drop(self.file.take());
+        let result = if self.created {
+            std::fs::remove_file(self.path)
+        } else {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(self.path)
+                .and_then(|file| file.set_len(self.received.load(Ordering::Relaxed)))
+        };
+        if let Err(error) = result {
+            log::warn!("Failed to clean up {}: {}", self.path.display(), error);
+        }
+    }
 }
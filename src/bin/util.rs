@@ -1,4 +1,87 @@
 use async_std::{io, io::prelude::*};
+use once_cell::sync::OnceCell;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/** Switch to machine-readable output. Human-readable text should then go to stderr. */
+pub fn enable_json_output() {
+    JSON_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/**
+ * Emit a machine-readable event on stdout, one JSON object per line
+ *
+ * Does nothing unless `--json` was given.
+ */
+pub fn emit(event: &str, mut fields: serde_json::Value) {
+    if !json_output() {
+        return;
+    }
+    if let Some(object) = fields.as_object_mut() {
+        object.insert("event".into(), event.into());
+    }
+    println!("{}", fields);
+}
+
+static TRANSCRIPT_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/** Record a protocol transcript, to be written to `path` by [`write_transcript`] */
+pub fn enable_transcript(path: PathBuf) {
+    if TRANSCRIPT_PATH.set(path).is_ok() {
+        magic_wormhole::transcript::start();
+    }
+}
+
+/** Write the transcript as JSON, if one is being recorded */
+pub fn write_transcript() -> std::io::Result<()> {
+    match (TRANSCRIPT_PATH.get(), magic_wormhole::transcript::stop()) {
+        (Some(path), Some(transcript)) => std::fs::write(
+            path,
+            serde_json::to_vec_pretty(&transcript).map_err(std::io::Error::from)?,
+        ),
+        _ => Ok(()),
+    }
+}
+
+static INTERRUPT: OnceCell<async_std::channel::Receiver<()>> = OnceCell::new();
+
+/**
+ * Catch Ctrl-C and SIGTERM, so that we can clean up, see [`interrupted`]
+ *
+ * A second interrupt exits right away, with `exit_code`.
+ */
+pub fn catch_interrupts(exit_code: i32) -> Result<(), ctrlc::Error> {
+    let (sender, receiver) = async_std::channel::bounded::<()>(1);
+    if INTERRUPT.set(receiver).is_err() {
+        return Ok(());
+    }
+    ctrlc::set_handler(move || {
+        /* Closing the channel wakes up everybody who is waiting for it */
+        if sender.close() {
+            eprintln!("Interrupted, cleaning up. Interrupt again to quit immediately");
+        } else {
+            std::process::exit(exit_code);
+        }
+    })
+}
+
+/** Wait until we get interrupted. Never happens unless [`catch_interrupts`] was called. */
+pub async fn interrupted() {
+    match INTERRUPT.get() {
+        Some(receiver) => {
+            let _ = receiver.recv().await;
+        },
+        None => futures::future::pending().await,
+    }
+}
 
 pub async fn ask_user(message: String, default_answer: bool) -> bool {
     let message = format!(
@@ -8,7 +91,7 @@ pub async fn ask_user(message: String, default_answer: bool) -> bool {
         if default_answer { "n" } else { "N" }
     );
 
-    let mut stdout = io::stdout();
+    let mut stdout = io::stderr();
     let stdin = io::stdin();
 
     loop {
@@ -35,7 +118,127 @@ pub async fn ask_user(message: String, default_answer: bool) -> bool {
     }
 }
 
-#[allow(dead_code)]
-fn main() {
-    panic!("This ought to be a helper module, no idea why Rust thinks it's a crate");
+/** The longest prefix all `strings` have in common */
+pub fn common_prefix(strings: &[String]) -> &str {
+    let first = match strings.first() {
+        Some(first) => first,
+        None => return "",
+    };
+    let mut len = first.len();
+    for string in &strings[1..] {
+        len = first
+            .char_indices()
+            .zip(string.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len);
+    }
+    &first[..len]
+}
+
+/**
+ * Reserve `len` bytes of disk space for `file`
+ *
+ * Where supported, the space gets actually allocated so that running out of it fails right away instead of in the
+ * middle of a transfer. Elsewhere, this falls back to simply setting the file length.
+ */
+pub async fn preallocate(file: &async_std::fs::File, len: u64) -> std::io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use std::{convert::TryFrom, os::unix::io::AsRawFd};
+
+        if let Ok(len) = libc::off_t::try_from(len) {
+            let fd = file.as_raw_fd();
+            /* posix_fallocate does not set errno, but returns it */
+            match async_std::task::spawn_blocking(move || unsafe {
+                libc::posix_fallocate(fd, 0, len)
+            })
+            .await
+            {
+                0 => return Ok(()),
+                /* Not supported by the file system, fall through */
+                libc::EOPNOTSUPP | libc::EINVAL => {},
+                err => return Err(std::io::Error::from_raw_os_error(err)),
+            }
+        }
+    }
+    file.set_len(len).await
+}
+
+/**
+ * Find the regions of a sparse file that actually contain data, using `SEEK_DATA`/`SEEK_HOLE`
+ *
+ * Returns `None` if the file has no holes, or if the platform or file system can't tell.
+ */
+pub async fn data_segments(
+    file: &async_std::fs::File,
+    len: u64,
+) -> std::io::Result<Option<Vec<std::ops::Range<u64>>>> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use std::{convert::TryFrom, os::unix::io::AsRawFd};
+
+        let fd = file.as_raw_fd();
+        let len = match libc::off_t::try_from(len) {
+            Ok(len) if len > 0 => len,
+            _ => return Ok(None),
+        };
+        /* This moves the file cursor. Callers have to seek before reading anyways */
+        async_std::task::spawn_blocking(move || {
+            let mut segments = Vec::new();
+            let mut offset = 0;
+            while offset < len {
+                let data = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+                if data < 0 {
+                    let err = std::io::Error::last_os_error();
+                    match err.raw_os_error() {
+                        /* No more data until the end of the file */
+                        Some(libc::ENXIO) => break,
+                        /* Not supported */
+                        Some(libc::EINVAL) => return Ok(None),
+                        _ => return Err(err),
+                    }
+                }
+                let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+                if hole < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let hole = hole.min(len);
+                segments.push(data as u64..hole as u64);
+                offset = hole;
+            }
+            if segments.len() == 1 && segments[0] == (0..len as u64) {
+                Ok(None)
+            } else {
+                Ok(Some(segments))
+            }
+        })
+        .await
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    {
+        let _ = (file, len);
+        Ok(None)
+    }
+}
+
+/**
+ * Sync the directory containing `path` to disk, so that a newly created file there survives a crash
+ *
+ * Directories cannot be synced like this on all platforms. There, this does nothing.
+ */
+pub async fn sync_parent_dir(path: &std::path::Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        async_std::fs::File::open(dir).await?.sync_all().await?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
 }
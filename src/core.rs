@@ -1,16 +1,20 @@
 pub(super) mod key;
 pub mod rendezvous;
 mod server_messages;
-#[cfg(test)]
+mod split;
+#[cfg(all(test, feature = "transfer"))]
 mod test;
-mod wordlist;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wordlist;
 
+use futures::FutureExt;
 use serde_derive::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 use self::rendezvous::*;
 pub(self) use self::server_messages::EncryptedMessage;
-use log::*;
+pub use self::split::{WormholeReceiver, WormholeSender};
 
 use xsalsa20poly1305 as secretbox;
 
@@ -34,14 +38,98 @@ pub enum WormholeError {
     /// the server sent some bullshit message order
     #[error("Protocol error: {}", _0)]
     Protocol(Box<str>),
+    /// The other side is not who we think it is. Don't retry blindly, see [`SecurityError`]
+    #[error("Security error")]
+    Security(
+        #[from]
+        #[source]
+        SecurityError,
+    ),
+    #[error("Key exchange error")]
+    Key(
+        #[from]
+        #[source]
+        KeyError,
+    ),
+    /// Nobody else used our code in time. See [`AppConfig::peer_timeout`]
+    #[error("The other side never showed up. Did they enter the code correctly?")]
+    PeerNeverConnected,
+    /// The operation did not complete within the configured timeout. See [`AppConfig::timeout`]
+    #[error("Timed out waiting for the other side")]
+    Timeout,
+    /// The whole session took longer than allowed. See [`AppConfig::deadline`]
+    #[error("The session did not finish before its deadline")]
+    DeadlineExceeded,
+    #[error("Invalid code")]
+    InvalidCode(
+        #[from]
+        #[source]
+        ParseCodeError,
+    ),
+}
+
+/**
+ * The key exchange with the other side went wrong, before it could even be verified
+ *
+ * A failed verification is reported as [`SecurityError::PakeFailed`] instead.
+ */
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum KeyError {
+    /// The other side sent something else first. Maybe it is not a Wormhole client, or speaks a different protocol
+    #[error(
+        "Expected a '{}' message from the other side, but got phase '{}'",
+        expected,
+        got
+    )]
+    UnexpectedPhase { expected: Phase, got: Phase },
+    #[error("The other side sent a malformed PAKE message")]
+    MalformedPake(#[source] serde_json::Error),
+    #[error("The other side sent a malformed version message")]
+    MalformedVersion(#[source] serde_json::Error),
+}
+
+/**
+ * Something went wrong that doesn't happen with an honest peer and the right code
+ *
+ * Either the code was mistyped, or somebody is actively attacking the connection: guessing the code,
+ * sitting in the middle, or tampering with the messages. Applications should warn the user about it
+ * instead of silently retrying.
+ */
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum SecurityError {
     #[error(
         "Key confirmation failed. If you didn't mistype the code, \
         this is a sign of an attacker guessing passwords. Please try \
         again some time later."
     )]
     PakeFailed,
-    #[error("Cannot decrypt a received message")]
-    Crypto,
+    /// A message or record failed authentication
+    #[error("Cannot decrypt a received message. It may have been tampered with")]
+    Decryption,
+    /// A message or record could not be encrypted
+    #[error("Cannot encrypt a message. This is probably an implementation bug")]
+    Encryption,
+    /// Transit records must arrive in order, without gaps or replays
+    #[error("Wrong nonce received, got {:x?} but expected {:x?}. This is probably an implementation bug, but may also be caused by an attack.", _0, _1)]
+    Nonce(Box<[u8]>, Box<[u8]>),
+}
+
+/** What is wrong with a code. See [`Code::parse`] */
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseCodeError {
+    #[error("The code must not contain whitespace")]
+    Whitespace,
+    #[error("The code must start with a number (the nameplate), followed by a dash")]
+    Nameplate,
+    #[error("The code is missing the password part after the nameplate")]
+    MissingPassword,
+    #[error("The code contains an empty word. Check the dashes")]
+    EmptyWord,
+    #[error("'{}' is not a word from the wordlist. Did you mistype it?", _0)]
+    UnknownWord(String),
 }
 
 impl WormholeError {
@@ -51,7 +139,7 @@ impl WormholeError {
 
     /** Should we tell the server that we are "errory" or "scared"? */
     pub fn is_scared(&self) -> bool {
-        matches!(self, Self::PakeFailed)
+        matches!(self, Self::Security(_))
     }
 }
 
@@ -81,6 +169,17 @@ pub struct WormholeWelcome {
  * which values you have. Typically, the sender side connects without a code (which will create one), and the receiver side
  * has one (the user entered it, who got it from the sender).
  *
+ * # Key confirmation
+ *
+ * There is no such thing as a Wormhole with an unverified key. The connect methods only return once the
+ * key exchange is done *and* the other side proved to have the same key, by sending an encrypted version message
+ * that we could decrypt. Otherwise they fail with [`SecurityError::PakeFailed`]. Thus everything sent over a
+ * Wormhole (including the transit key derived with [`derive_transit_key`](Self::derive_transit_key)) is only
+ * ever encrypted with a confirmed key, and there is nothing to wait for before sending.
+ *
+ * The [`verifier`](Self::verifier) may additionally be compared out of band, to rule out a man in the middle
+ * who guessed the code.
+ *
  * # Clean shutdown
  *
  * TODO
@@ -114,8 +213,116 @@ pub struct Wormhole {
      * (e.g. by the file transfer API).
      */
     pub peer_version: serde_json::Value,
+    /**
+     * The abilities the other side announced next to its [`peer_version`](Self::peer_version)
+     *
+     * These are about the wormhole protocol itself (like `"resume-v1"`), independent of the application.
+     */
+    pub peer_abilities: Vec<String>,
+    /** Our protocol version information, as sent to the other side */
+    pub our_version: serde_json::Value,
+    timeout: Option<std::time::Duration>,
+    answer_timeout: Option<std::time::Duration>,
+    deadline: Option<std::time::Instant>,
+    timings: Timings,
+    /* When the key exchange finished */
+    established: std::time::Instant,
+    custom_phase_handler: CustomPhaseHandler,
+}
+
+/* Only a wrapper so that `Wormhole` can still derive `Debug` */
+#[derive(Default)]
+struct CustomPhaseHandler(Option<Box<dyn FnMut(&Phase, Vec<u8>) + Send>>);
+
+impl std::fmt::Debug for CustomPhaseHandler {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_tuple("CustomPhaseHandler")
+            .field(&self.0.is_some())
+            .finish()
+    }
+}
+
+/**
+ * A snapshot of the state of a [`Wormhole`], for diagnostics
+ *
+ * See [`Wormhole::debug_state`]. It contains no secrets, so it may be included in bug reports.
+ * A `Wormhole` only exists once the key exchange is done. For sessions stuck before that, look at
+ * [`RendezvousServer::debug_state`] instead.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct DebugState {
+    /// The state of the connection to the rendezvous server
+    pub rendezvous: rendezvous::DebugState,
+    /// The number of messages we sent with [`Wormhole::send`]
+    pub messages_sent: u64,
+    /// Whether a message from the peer arrived since the key exchange
+    pub first_message_received: bool,
+}
+
+/**
+ * How long the different steps of the connection took
+ *
+ * See [`Wormhole::timings`]. A step that did not happen (yet) has no duration.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Timings {
+    /// Getting a nameplate and opening the mailbox on the server
+    pub claim: Option<std::time::Duration>,
+    /// From sending our PAKE message until both sides agreed on a key, including the wait for the peer
+    pub pake: Option<std::time::Duration>,
+    /// From the key exchange until the first message from the peer arrived
+    pub first_message: Option<std::time::Duration>,
+}
+
+/**
+ * Progress of establishing a [`Wormhole`], for showing it to the user
+ *
+ * See [`AppConfig::on_event`]. The events come in this order, each one at most once per connection.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+#[non_exhaustive]
+pub enum WormholeEvent {
+    /// We hold the nameplate of the code
+    NameplateClaimed { nameplate: Nameplate },
+    /// We are in the mailbox and have sent our part of the key exchange. Now we wait for the other side
+    MailboxOpened,
+    /// The other side showed up, the key exchange is running
+    PeerConnected,
+    /// Both sides agreed on a key and confirmed it. The wormhole is ready
+    KeyEstablished,
 }
 
+/** Receives [`WormholeEvent`]s, see [`AppConfig::on_event`] */
+#[derive(Clone)]
+pub struct EventHandler(std::sync::Arc<dyn Fn(&WormholeEvent) + Send + Sync>);
+
+impl EventHandler {
+    fn emit(handler: &Option<Self>, event: WormholeEvent) {
+        log::debug!("{:?}", event);
+        if let Some(handler) = handler {
+            (handler.0)(&event);
+        }
+    }
+}
+
+impl std::fmt::Debug for EventHandler {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("EventHandler")
+    }
+}
+
+/* Handlers are only equal to their clones */
+impl PartialEq for EventHandler {
+    fn eq(&self, other: &Self) -> bool {
+        std::sync::Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for EventHandler {}
+
 impl Wormhole {
     /**
      * Generate a code and connect to the rendezvous server.
@@ -135,16 +342,71 @@ impl Wormhole {
             impl std::future::Future<Output = Result<Self, WormholeError>>,
         ),
         WormholeError,
+    > {
+        let (server, welcome, config) = Box::pin(Self::connect_server(config)).await?;
+        Box::pin(Self::connect_without_code_via(
+            server,
+            welcome,
+            config,
+            code_length,
+        ))
+        .await
+    }
+
+    /**
+     * [`connect_without_code`](Self::connect_without_code), but with an already connected [`RendezvousServer`]
+     *
+     * The `rendezvous_url` of the `config` is ignored, and `welcome` is passed through to the [`WormholeWelcome`].
+     * See [`RendezvousServer::connect_with_transport`] for an example.
+     */
+    pub async fn connect_without_code_via(
+        mut server: RendezvousServer,
+        welcome: Option<String>,
+        config: AppConfig<impl serde::Serialize>,
+        code_length: usize,
+    ) -> Result<
+        (
+            WormholeWelcome,
+            impl std::future::Future<Output = Result<Self, WormholeError>>,
+        ),
+        WormholeError,
     > {
         let AppConfig {
             id: appid,
-            rendezvous_url,
             app_version: versions,
+            queue_capacity,
+            keepalive_interval,
+            max_message_size,
+            timeout,
+            answer_timeout,
+            peer_timeout,
+            deadline,
+            on_event,
+            ..
         } = config;
+        let deadline = deadline_from(deadline);
+        server.set_queue_capacity(queue_capacity);
+        server.set_keepalive_interval(keepalive_interval);
+        server.set_max_message_size(max_message_size);
         let versions = serde_json::to_value(versions).unwrap();
-        let (mut server, welcome) = RendezvousServer::connect(&appid, &rendezvous_url).await?;
-        let (nameplate, mailbox) = server.allocate_claim_open().await?;
+        let claim_start = std::time::Instant::now();
+        let (nameplate, mailbox) = match with_deadline(
+            deadline,
+            with_timeout(timeout, async { Ok(server.allocate_claim_open().await?) }),
+        )
+        .await
+        {
+            Ok(claimed) => claimed,
+            Err(error) => bail!(give_up(server, error).await),
+        };
+        let claim = claim_start.elapsed();
         log::debug!("Connected to mailbox {}", mailbox);
+        EventHandler::emit(
+            &on_event,
+            WormholeEvent::NameplateClaimed {
+                nameplate: nameplate.clone(),
+            },
+        );
 
         let code = Code::new(
             &nameplate,
@@ -156,7 +418,22 @@ impl Wormhole {
                 welcome,
                 code: code.clone(),
             },
-            Self::connect_custom(server, appid, code.0, versions),
+            async move {
+                let mut wormhole = Box::pin(Self::connect_custom_inner(
+                    server,
+                    appid,
+                    code.0,
+                    versions,
+                    peer_timeout,
+                    on_event,
+                    deadline,
+                    timeout,
+                ))
+                .await?;
+                wormhole.set_answer_timeout(answer_timeout);
+                wormhole.timings.claim = Some(claim);
+                Ok(wormhole)
+            },
         ))
     }
 
@@ -166,26 +443,116 @@ impl Wormhole {
     pub async fn connect_with_code(
         config: AppConfig<impl serde::Serialize>,
         code: Code,
+    ) -> Result<(WormholeWelcome, Self), WormholeError> {
+        let (server, welcome, config) = Box::pin(Self::connect_server(config)).await?;
+        Box::pin(Self::connect_with_code_via(server, welcome, config, code)).await
+    }
+
+    /** Connect to the rendezvous server of `config`, and take the time it took from its deadline */
+    async fn connect_server<V: serde::Serialize>(
+        mut config: AppConfig<V>,
+    ) -> Result<(RendezvousServer, Option<String>, AppConfig<V>), WormholeError> {
+        let start = std::time::Instant::now();
+        let (server, welcome) = with_deadline(deadline_from(config.deadline), async {
+            Ok::<_, WormholeError>(
+                RendezvousServer::connect_with_options(
+                    &config.id,
+                    &config.rendezvous_url,
+                    &config.rendezvous_headers[..],
+                    &config.rendezvous_pins,
+                    config.max_message_size,
+                )
+                .await?,
+            )
+        })
+        .await?;
+        config.deadline = config
+            .deadline
+            .map(|deadline| deadline.checked_sub(start.elapsed()).unwrap_or_default());
+        Ok((server, welcome, config))
+    }
+
+    /**
+     * [`connect_with_code`](Self::connect_with_code), but with an already connected [`RendezvousServer`]
+     *
+     * The `rendezvous_url` of the `config` is ignored, and `welcome` is passed through to the [`WormholeWelcome`].
+     * See [`RendezvousServer::connect_with_transport`] for an example.
+     */
+    pub async fn connect_with_code_via(
+        mut server: RendezvousServer,
+        welcome: Option<String>,
+        config: AppConfig<impl serde::Serialize>,
+        code: Code,
     ) -> Result<(WormholeWelcome, Self), WormholeError> {
         let AppConfig {
             id: appid,
-            rendezvous_url,
             app_version: versions,
+            queue_capacity,
+            keepalive_interval,
+            max_message_size,
+            timeout,
+            answer_timeout,
+            peer_timeout,
+            check_nameplate,
+            deadline,
+            on_event,
+            ..
         } = config;
+        let deadline = deadline_from(deadline);
+        code.validate()?;
+        server.set_queue_capacity(queue_capacity);
+        server.set_keepalive_interval(keepalive_interval);
+        server.set_max_message_size(max_message_size);
         let versions = serde_json::to_value(versions).unwrap();
-        let (mut server, welcome) = RendezvousServer::connect(&appid, &rendezvous_url).await?;
 
         let nameplate = code.nameplate();
-        let mailbox = server.claim_open(nameplate).await?;
+        if check_nameplate {
+            let nameplates = match with_deadline(
+                deadline,
+                with_timeout(timeout, async { Ok(server.list_nameplates().await?) }),
+            )
+            .await
+            {
+                Ok(nameplates) => nameplates,
+                Err(error) => bail!(give_up(server, error).await),
+            };
+            if !nameplates.contains(&nameplate) {
+                log::info!("Nameplate {} does not exist on the server", nameplate);
+                let _ = server.shutdown(Mood::Errory).await;
+                bail!(RendezvousError::from(NameplateError::NotFound(nameplate)));
+            }
+        }
+        let claim_start = std::time::Instant::now();
+        let mailbox = match with_deadline(
+            deadline,
+            with_timeout(timeout, async {
+                Ok(server.claim_open(nameplate.clone()).await?)
+            }),
+        )
+        .await
+        {
+            Ok(mailbox) => mailbox,
+            Err(error) => bail!(give_up(server, error).await),
+        };
+        let claim = claim_start.elapsed();
         log::debug!("Connected to mailbox {}", mailbox);
+        EventHandler::emit(&on_event, WormholeEvent::NameplateClaimed { nameplate });
 
-        Ok((
-            WormholeWelcome {
-                welcome,
-                code: code.clone(),
-            },
-            Self::connect_custom(server, appid, code.0, versions).await?,
+        let mut wormhole = Box::pin(Self::connect_custom_inner(
+            server,
+            appid,
+            code.0.clone(),
+            versions,
+            peer_timeout,
+            on_event,
+            deadline,
+            timeout,
         ))
+        .await?;
+        wormhole.set_answer_timeout(answer_timeout);
+        wormhole.timings.claim = Some(claim);
+
+        Ok((WormholeWelcome { welcome, code }, wormhole))
     }
 
     /** TODO */
@@ -202,54 +569,183 @@ impl Wormhole {
     /// If the [`RendezvousServer`] is not properly initialized, i.e. if the
     /// mailbox is not open.
     pub async fn connect_custom(
+        server: RendezvousServer,
+        appid: AppID,
+        password: String,
+        app_versions: impl serde::Serialize,
+    ) -> Result<Self, WormholeError> {
+        Box::pin(Self::connect_custom_inner(
+            server,
+            appid,
+            password,
+            app_versions,
+            None,
+            None,
+            None,
+            None,
+        ))
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn connect_custom_inner(
         mut server: RendezvousServer,
         appid: AppID,
         password: String,
         app_versions: impl serde::Serialize,
+        peer_timeout: Option<std::time::Duration>,
+        on_event: Option<EventHandler>,
+        deadline: Option<std::time::Instant>,
+        timeout: Option<std::time::Duration>,
     ) -> Result<Self, WormholeError> {
+        let our_version = serde_json::to_value(app_versions).unwrap();
+        /* The server stays out here, so that we can still close it if we run out of time */
+        let result = with_deadline(
+            deadline,
+            with_timeout(
+                timeout,
+                Box::pin(Self::key_exchange(
+                    &mut server,
+                    &appid,
+                    &password,
+                    our_version.clone(),
+                    peer_timeout,
+                    &on_event,
+                )),
+            ),
+        )
+        .await;
+        let (key, versions, pake) = match result {
+            Ok(exchanged) => exchanged,
+            Err(error) => bail!(give_up(server, error).await),
+        };
+
+        let key::VersionsMessage {
+            abilities: peer_abilities,
+            app_versions: peer_version,
+        } = versions;
+
+        log::info!("Connected to peer (PAKE successful)");
+        EventHandler::emit(&on_event, WormholeEvent::KeyEstablished);
+
+        /* We are now fully initialized! Up and running! :tada: */
+        Ok(Self {
+            server,
+            appid,
+            phase: 0,
+            verifier: Box::new(key::derive_verifier(&key)),
+            key,
+            peer_version,
+            peer_abilities,
+            our_version,
+            timeout,
+            answer_timeout: None,
+            deadline,
+            timings: Timings {
+                pake: Some(pake),
+                ..Timings::default()
+            },
+            established: std::time::Instant::now(),
+            custom_phase_handler: CustomPhaseHandler::default(),
+        })
+    }
+
+    /** Do the PAKE and exchange the versions. Returns the key, their versions and how long the PAKE took */
+    async fn key_exchange(
+        server: &mut RendezvousServer,
+        appid: &AppID,
+        password: &str,
+        our_version: serde_json::Value,
+        peer_timeout: Option<std::time::Duration>,
+        on_event: &Option<EventHandler>,
+    ) -> Result<
+        (
+            key::Key<key::WormholeKey>,
+            key::VersionsMessage,
+            std::time::Duration,
+        ),
+        WormholeError,
+    > {
         /* Send PAKE */
-        let (pake_state, pake_msg_ser) = key::make_pake(&password, &appid);
+        let pake_start = std::time::Instant::now();
+        let (pake_state, pake_msg_ser) = key::make_pake(password, appid);
         server.send_peer_message(Phase::PAKE, pake_msg_ser).await?;
+        EventHandler::emit(on_event, WormholeEvent::MailboxOpened);
 
         /* Receive PAKE */
-        let peer_pake = key::extract_pake_msg(&server.next_peer_message_some().await?.body)?;
+        let peer_pake = match peer_timeout {
+            Some(peer_timeout) => {
+                match async_std::future::timeout(peer_timeout, server.next_peer_message_some())
+                    .await
+                {
+                    Ok(peer_pake) => peer_pake?,
+                    Err(_) => {
+                        log::info!("The other side did not show up, giving up");
+                        bail!(WormholeError::PeerNeverConnected);
+                    },
+                }
+            },
+            None => server.next_peer_message_some().await?,
+        };
+        ensure!(
+            peer_pake.phase.is_pake(),
+            KeyError::UnexpectedPhase {
+                expected: Phase::PAKE,
+                got: peer_pake.phase,
+            }
+        );
+        let peer_pake = key::extract_pake_msg(&peer_pake.body)?;
+        /* Only now we know it's a peer speaking our protocol, and not some garbage */
+        EventHandler::emit(on_event, WormholeEvent::PeerConnected);
         let key = pake_state
             .finish(&peer_pake)
-            .map_err(|_| WormholeError::PakeFailed)
-            .map(|key| *secretbox::Key::from_slice(&key))?;
+            .map_err(|_| {
+                crate::metrics::metrics().handshake_failed(crate::metrics::HandshakeKind::Pake);
+                SecurityError::PakeFailed
+            })
+            .map(|mut key| {
+                let wormhole_key =
+                    key::Key::<key::WormholeKey>::new(Box::new(*secretbox::Key::from_slice(&key)));
+                zeroize::Zeroize::zeroize(&mut key);
+                wormhole_key
+            })?;
+
+        let pake = pake_start.elapsed();
+
+        /* Both sides are in the mailbox now, nobody needs the nameplate anymore. Release it
+         * right away, so that the short codes go back to the pool quickly.
+         */
+        if server.needs_nameplate_release() {
+            server.release_nameplate().await?;
+        }
 
         /* Send versions message */
         let mut versions = key::VersionsMessage::new();
-        versions.set_app_versions(serde_json::to_value(app_versions).unwrap());
+        versions.set_app_versions(our_version);
         let (version_phase, version_msg) = key::build_version_msg(server.side(), &key, &versions);
         server.send_peer_message(version_phase, version_msg).await?;
         let peer_version = server.next_peer_message_some().await?;
+        ensure!(
+            peer_version.phase.is_version(),
+            KeyError::UnexpectedPhase {
+                expected: Phase::VERSION,
+                got: peer_version.phase,
+            }
+        );
 
         /* Handle received message */
         let versions: key::VersionsMessage = peer_version
             .decrypt(&key)
-            .ok_or(WormholeError::PakeFailed)
+            .ok_or_else(|| {
+                crate::metrics::metrics().handshake_failed(crate::metrics::HandshakeKind::Pake);
+                WormholeError::from(SecurityError::PakeFailed)
+            })
             .and_then(|plaintext| {
-                serde_json::from_slice(&plaintext).map_err(WormholeError::ProtocolJson)
+                serde_json::from_slice(&plaintext)
+                    .map_err(|error| KeyError::MalformedVersion(error).into())
             })?;
 
-        let peer_version = versions.app_versions;
-
-        if server.needs_nameplate_release() {
-            server.release_nameplate().await?;
-        }
-
-        log::info!("Connected to peer (PAKE successful)");
-
-        /* We are now fully initialized! Up and running! :tada: */
-        Ok(Self {
-            server,
-            appid,
-            phase: 0,
-            key: key::Key::new(key.into()),
-            verifier: Box::new(key::derive_verifier(&key)),
-            peer_version,
-        })
+        Ok((key, versions, pake))
     }
 
     /** Send an encrypted message to peer */
@@ -258,36 +754,227 @@ impl Wormhole {
         self.phase += 1;
         let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase_string);
         let (_nonce, encrypted) = key::encrypt_data(&data_key, &plaintext);
-        self.server
-            .send_peer_message(phase_string, encrypted)
-            .await?;
-        Ok(())
+        let server = &mut self.server;
+        with_deadline(self.deadline, async {
+            Ok(server.send_peer_message(phase_string, encrypted).await?)
+        })
+        .await
+    }
+
+    /**
+     * Send an encrypted message to peer, in an application-defined phase
+     *
+     * This is meant for prototyping protocol extensions. The other side only sees these messages
+     * if it has a [`custom phase handler`](Self::set_custom_phase_handler), otherwise they are
+     * ignored. A phase may only be used once per side, so add a counter if you need more.
+     *
+     * # Panics
+     *
+     * If `phase` is one of the standard ones, i.e. `pake`, `version` or a number.
+     */
+    pub async fn send_custom(
+        &mut self,
+        phase: Phase,
+        plaintext: Vec<u8>,
+    ) -> Result<(), WormholeError> {
+        assert!(
+            phase.is_custom(),
+            "Phase '{}' is reserved by the protocol",
+            phase
+        );
+        let data_key = key::derive_phase_key(self.server.side(), &self.key, &phase);
+        let (_nonce, encrypted) = key::encrypt_data(&data_key, &plaintext);
+        let server = &mut self.server;
+        with_deadline(self.deadline, async {
+            Ok(server.send_peer_message(phase, encrypted).await?)
+        })
+        .await
+    }
+
+    /**
+     * Handle messages in application-defined phases
+     *
+     * Messages with a phase that is not part of the protocol (see [`send_custom`](Self::send_custom))
+     * are passed to the `handler` after decryption, while waiting in [`receive`](Self::receive).
+     * Without a handler, they are ignored.
+     */
+    pub fn set_custom_phase_handler(
+        &mut self,
+        handler: impl FnMut(&Phase, Vec<u8>) + Send + 'static,
+    ) {
+        self.custom_phase_handler = CustomPhaseHandler(Some(Box::new(handler)));
     }
 
-    /** Receive an encrypted message from peer */
+    /**
+     * Receive an encrypted message from peer
+     *
+     * Fails with [`WormholeError::Timeout`] if a timeout is set and nothing arrives in time.
+     * Nothing is lost in that case, you may simply call it again. Once the [deadline](Self::deadline)
+     * passed, it fails with [`WormholeError::DeadlineExceeded`].
+     */
     pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
+        let timeout = self.timeout;
+        self.receive_with_timeout(timeout).await
+    }
+
+    /**
+     * Like [`receive`](Self::receive), but with a different timeout
+     *
+     * This is for messages that may take longer than usual, like answers that need a decision
+     * of the user on the other side. See [`answer_timeout`](Self::answer_timeout).
+     */
+    pub async fn receive_with_timeout(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Vec<u8>, WormholeError> {
+        let deadline = self.deadline;
+        with_deadline(deadline, with_timeout(timeout, self.receive_inner())).await
+    }
+
+    /**
+     * How many messages from the other side have arrived, but were not received yet
+     *
+     * This does not wait for anything. These messages are returned by [`receive`](Self::receive)
+     * or [`try_receive`](Self::try_receive) right away.
+     */
+    pub fn pending_messages(&mut self) -> Result<usize, WormholeError> {
+        Ok(self
+            .server
+            .poll_peer_messages()?
+            .iter()
+            .filter(|phase| phase.to_num().is_some())
+            .count())
+    }
+
+    /**
+     * Receive a message from the other side if one has arrived already, without waiting
+     *
+     * Use this to drain a backlog of messages: `while let Some(message) = wormhole.try_receive()? { … }`.
+     */
+    pub fn try_receive(&mut self) -> Result<Option<Vec<u8>>, WormholeError> {
+        if self.pending_messages()? == 0 {
+            return Ok(None);
+        }
+        /* The message is in the buffer, so this won't wait */
+        self.receive_inner()
+            .now_or_never()
+            .expect("Receiving a buffered message should not block")
+            .map(Some)
+    }
+
+    async fn receive_inner(&mut self) -> Result<Vec<u8>, WormholeError> {
         loop {
             let peer_message = match self.server.next_peer_message().await? {
                 Some(peer_message) => peer_message,
                 None => continue,
             };
             if peer_message.phase.to_num().is_none() {
-                // TODO: log and ignore, for future expansion
-                todo!("log and ignore, for future expansion");
+                if let (true, Some(handler)) = (
+                    peer_message.phase.is_custom(),
+                    &mut self.custom_phase_handler.0,
+                ) {
+                    let decrypted_message = peer_message
+                        .decrypt(&self.key)
+                        .ok_or(SecurityError::Decryption)?;
+                    handler(&peer_message.phase, decrypted_message);
+                    continue;
+                }
+                /* Ignore unknown phases, for future expansion */
+                log::warn!(
+                    "Received message with unknown phase '{}', ignoring",
+                    peer_message.phase
+                );
+                continue;
             }
 
             // TODO maybe reorder incoming messages by phase numeral?
             let decrypted_message = peer_message
                 .decrypt(&self.key)
-                .ok_or(WormholeError::Crypto)?;
+                .ok_or(SecurityError::Decryption)?;
+
+            if self.timings.first_message.is_none() {
+                self.timings.first_message = Some(self.established.elapsed());
+            }
 
             // Send to client
             return Ok(decrypted_message);
         }
     }
 
-    pub async fn close(self) -> Result<(), WormholeError> {
-        self.server.shutdown(Mood::Happy).await.map_err(Into::into)
+    /**
+     * Close the connection
+     *
+     * Check the returned status to find out whether this went well, or use
+     * [`into_result`](rendezvous::ClosedStatus::into_result) if you only care about errors.
+     */
+    pub async fn close(self) -> rendezvous::ClosedStatus {
+        self.server.close(Mood::Happy).await
+    }
+
+    /**
+     * Like [`close`](Self::close), but tell the server how things went
+     *
+     * Use [`Mood::Errory`] when giving up because of an error, or because the user cancelled.
+     */
+    pub async fn close_with_mood(self, mood: Mood) -> rendezvous::ClosedStatus {
+        self.server.close(mood).await
+    }
+
+    /** Change the timeout for [`receive`](Self::receive). See [`AppConfig::timeout`] */
+    pub fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.timeout = timeout;
+    }
+
+    /** The timeout for [`receive`](Self::receive). See [`AppConfig::timeout`] */
+    pub fn timeout(&self) -> Option<std::time::Duration> {
+        self.timeout
+    }
+
+    /**
+     * When the whole session has to be done, see [`AppConfig::deadline`]
+     *
+     * Protocols on top of the wormhole should give up at that point as well, like the file transfer does.
+     */
+    pub fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline
+    }
+
+    /** Change the deadline of the session. See [`AppConfig::deadline`] */
+    pub fn set_deadline(&mut self, deadline: Option<std::time::Instant>) {
+        self.deadline = deadline;
+    }
+
+    /** Change the timeout for answers of the other side. See [`AppConfig::answer_timeout`] */
+    pub fn set_answer_timeout(&mut self, answer_timeout: Option<std::time::Duration>) {
+        self.answer_timeout = answer_timeout;
+    }
+
+    /** How long to wait for answers of the other side. See [`AppConfig::answer_timeout`] */
+    pub fn answer_timeout(&self) -> Option<std::time::Duration> {
+        self.answer_timeout
+    }
+
+    /**
+     * Take a snapshot of the connection state, see [`DebugState`]
+     *
+     * Useful to find out where a session got stuck, and to include in bug reports.
+     */
+    pub fn debug_state(&self) -> DebugState {
+        DebugState {
+            rendezvous: self.server.debug_state(),
+            messages_sent: self.phase,
+            first_message_received: self.timings.first_message.is_some(),
+        }
+    }
+
+    /** The welcome message of the rendezvous server, see [`RendezvousServer::welcome`] */
+    pub fn server_welcome(&self) -> &rendezvous::WelcomeMessage {
+        self.server.welcome()
+    }
+
+    /** How long connecting took, see [`Timings`] */
+    pub fn timings(&self) -> Timings {
+        self.timings
     }
 
     /**
@@ -305,6 +992,81 @@ impl Wormhole {
     pub fn key(&self) -> &key::Key<key::WormholeKey> {
         &self.key
     }
+
+    /**
+     * Derive a sub-key for the given `purpose`
+     *
+     * Both sides will get the same key when using the same purpose. This is the equivalent of `derive_key`
+     * in the Python implementation, with a fixed length of 32 bytes.
+     */
+    pub fn derive_key<P: key::KeyPurpose>(&self, purpose: &str) -> key::Key<P> {
+        self.key.derive_subkey_from_purpose(purpose)
+    }
+
+    /**
+     * Derive the key to set up a [`transit`](crate::transit) connection with
+     *
+     * See [`Key::derive_transit_key`](key::Key::derive_transit_key).
+     */
+    #[cfg(feature = "transit")]
+    pub fn derive_transit_key(&self) -> key::Key<crate::transit::TransitKey> {
+        self.key.derive_transit_key(&self.appid)
+    }
+}
+
+/** When a session started now has to be done */
+fn deadline_from(deadline: Option<std::time::Duration>) -> Option<std::time::Instant> {
+    deadline.map(|deadline| std::time::Instant::now() + deadline)
+}
+
+/**
+ * Close the `server` after connecting failed with `error`, and return the `error`
+ *
+ * If the other side never showed up or we ran out of time, the server is still fine. Tell it, so that it
+ * releases our nameplate and mailbox. Otherwise, the connection is simply dropped.
+ */
+async fn give_up(server: RendezvousServer, error: WormholeError) -> WormholeError {
+    let mood = match error {
+        WormholeError::PeerNeverConnected => Mood::Lonely,
+        WormholeError::Timeout | WormholeError::DeadlineExceeded => Mood::Errory,
+        _ => return error,
+    };
+    /* We're failing anyways */
+    let _ = server.shutdown(mood).await;
+    error
+}
+
+/** Run `future`, but fail with [`WormholeError::DeadlineExceeded`] if it isn't done by `deadline` */
+pub(crate) async fn with_deadline<T, E: From<WormholeError>>(
+    deadline: Option<std::time::Instant>,
+    future: impl std::future::Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    match deadline {
+        Some(deadline) => {
+            /* Don't even start if it's too late already, a ready future would win against a zero timeout */
+            let remaining = match deadline.checked_duration_since(std::time::Instant::now()) {
+                Some(remaining) if remaining > std::time::Duration::from_secs(0) => remaining,
+                _ => return Err(WormholeError::DeadlineExceeded.into()),
+            };
+            async_std::future::timeout(remaining, future)
+                .await
+                .map_err(|_| WormholeError::DeadlineExceeded)?
+        },
+        None => future.await,
+    }
+}
+
+/** Run `future`, but fail with [`WormholeError::Timeout`] if it takes longer than `timeout` */
+async fn with_timeout<T>(
+    timeout: Option<std::time::Duration>,
+    future: impl std::future::Future<Output = Result<T, WormholeError>>,
+) -> Result<T, WormholeError> {
+    match timeout {
+        Some(timeout) => async_std::future::timeout(timeout, future)
+            .await
+            .map_err(|_| WormholeError::Timeout)?,
+        None => future.await,
+    }
 }
 
 // the serialized forms of these variants are part of the wire protocol, so
@@ -339,6 +1101,28 @@ pub struct AppConfig<V: serde::Serialize> {
     pub id: AppID,
     pub rendezvous_url: Cow<'static, str>,
     pub app_version: V,
+    /** See [`RendezvousServer::set_queue_capacity`] */
+    pub queue_capacity: usize,
+    /** See [`RendezvousServer::set_keepalive_interval`] */
+    pub keepalive_interval: Option<std::time::Duration>,
+    /** See [`RendezvousServer::set_max_message_size`] */
+    pub max_message_size: usize,
+    /** See [`AppConfig::timeout`] */
+    pub timeout: Option<std::time::Duration>,
+    /** See [`AppConfig::answer_timeout`] */
+    pub answer_timeout: Option<std::time::Duration>,
+    /** See [`AppConfig::peer_timeout`] */
+    pub peer_timeout: Option<std::time::Duration>,
+    /** See [`AppConfig::deadline`] */
+    pub deadline: Option<std::time::Duration>,
+    /** See [`AppConfig::on_event`] */
+    pub on_event: Option<EventHandler>,
+    /** See [`AppConfig::check_nameplate`] */
+    pub check_nameplate: bool,
+    /** See [`AppConfig::pin_rendezvous_certificate`] */
+    pub rendezvous_pins: Cow<'static, [CertificatePin]>,
+    /** See [`AppConfig::rendezvous_header`] */
+    pub rendezvous_headers: Cow<'static, [(Cow<'static, str>, Cow<'static, str>)]>,
 }
 
 impl<V: serde::Serialize> AppConfig<V> {
@@ -356,6 +1140,139 @@ impl<V: serde::Serialize> AppConfig<V> {
         self.app_version = app_version;
         self
     }
+
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    pub fn keepalive_interval(mut self, keepalive_interval: Option<std::time::Duration>) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    /**
+     * Reject larger messages from the rendezvous server
+     *
+     * See [`RendezvousServer::set_max_message_size`]. The default is [`DEFAULT_MAX_MESSAGE_SIZE`].
+     */
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /**
+     * Give up waiting after some time
+     *
+     * This applies separately to claiming a code, to connecting to the peer (i.e. until the verifier is known)
+     * and to each [`Wormhole::receive`] call. If they take longer, they fail with [`WormholeError::Timeout`].
+     * By default, there is no timeout.
+     */
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /**
+     * Give up waiting for the other side to answer
+     *
+     * Protocols use this instead of [`timeout`](Self::timeout) when the other side may need a decision of
+     * its user first, like accepting a file offer. This should thus be rather generous. If it takes longer,
+     * the file transfer fails with [`TransferError::Timeout`](crate::transfer::TransferError::Timeout).
+     * By default, we wait forever.
+     */
+    pub fn answer_timeout(mut self, answer_timeout: std::time::Duration) -> Self {
+        self.answer_timeout = Some(answer_timeout);
+        self
+    }
+
+    /**
+     * Give up if the other side doesn't show up in time
+     *
+     * If the peer doesn't start the key exchange within `peer_timeout` after we did, we close the
+     * connection with [`Mood::Lonely`] and fail with [`WormholeError::PeerNeverConnected`]. This
+     * usually means that the code was never entered on the other side.
+     * By default, we wait forever (or until [`timeout`](Self::timeout)).
+     */
+    pub fn peer_timeout(mut self, peer_timeout: std::time::Duration) -> Self {
+        self.peer_timeout = Some(peer_timeout);
+        self
+    }
+
+    /**
+     * Give up on the whole session after some time
+     *
+     * Unlike [`timeout`](Self::timeout), this is not reset between steps: connecting to the server, exchanging
+     * the code, the key exchange and everything the wormhole is used for afterwards must be done within `deadline`,
+     * counted from the call to [`Wormhole::connect_with_code`] or [`Wormhole::connect_without_code`]. Once it passed,
+     * all operations fail with [`WormholeError::DeadlineExceeded`], and so do file transfers. The wormhole should
+     * then be closed. By default, there is no deadline.
+     */
+    pub fn deadline(mut self, deadline: std::time::Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /**
+     * Get told about the progress of connecting
+     *
+     * `handler` is called with a [`WormholeEvent`] whenever a step of establishing the wormhole is done, so that
+     * applications can show something more meaningful than a spinner. It is called from within the protocol code,
+     * so it should return quickly.
+     */
+    pub fn on_event(mut self, handler: impl Fn(&WormholeEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(EventHandler(std::sync::Arc::new(handler)));
+        self
+    }
+
+    /**
+     * Check that the nameplate of a code exists before claiming it
+     *
+     * Claiming a nameplate that nobody allocated (because it expired, or because of a typo) creates it
+     * anew, and then we wait for a peer that will never come. With this set, [`Wormhole::connect_with_code`]
+     * asks the server for its list of nameplates first and fails with [`NameplateError::NotFound`] right away.
+     * This is off by default, because it costs a round trip and the server may not list all nameplates.
+     */
+    pub fn check_nameplate(mut self, check_nameplate: bool) -> Self {
+        self.check_nameplate = check_nameplate;
+        self
+    }
+
+    /**
+     * Only connect to the rendezvous server if its certificate matches
+     *
+     * May be called multiple times, any of the pins will do (e.g. to roll over to a new key).
+     * This requires a `wss://` [`rendezvous_url`](Self::rendezvous_url). See [`CertificatePin`].
+     */
+    pub fn pin_rendezvous_certificate(mut self, pin: CertificatePin) -> Self {
+        self.rendezvous_pins.to_mut().push(pin);
+        self
+    }
+
+    /**
+     * Add an HTTP header to the request that connects to the rendezvous server
+     *
+     * Private servers behind a reverse proxy may require some for access control. See also
+     * [`rendezvous_bearer_token`](Self::rendezvous_bearer_token).
+     *
+     * Don't use this to request WebSocket extensions like `permessage-deflate`: the WebSocket implementation
+     * does not support any, and fails on the first compressed message if the server agrees to it.
+     */
+    pub fn rendezvous_header(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.rendezvous_headers
+            .to_mut()
+            .push((name.into(), value.into()));
+        self
+    }
+
+    /** Authenticate to the rendezvous server with an `Authorization: Bearer <token>` header */
+    pub fn rendezvous_bearer_token(self, token: impl std::fmt::Display) -> Self {
+        self.rendezvous_header("Authorization", format!("Bearer {}", token))
+    }
 }
 
 /// Newtype wrapper for application IDs
@@ -373,6 +1290,11 @@ impl AppID {
     pub fn new(id: impl Into<Cow<'static, str>>) -> Self {
         AppID(id.into())
     }
+
+    /** Like [`new`](Self::new), but usable in constants. See [`transfer::APPID`](crate::transfer::APPID) for an example */
+    pub const fn from_static(id: &'static str) -> Self {
+        AppID(Cow::Borrowed(id))
+    }
 }
 
 impl From<String> for AppID {
@@ -381,6 +1303,18 @@ impl From<String> for AppID {
     }
 }
 
+impl From<&'static str> for AppID {
+    fn from(s: &'static str) -> Self {
+        Self::from_static(s)
+    }
+}
+
+impl AsRef<str> for AppID {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
 // MySide is used for the String that we send in all our outbound messages
 #[derive(
     PartialEq, Eq, Clone, Debug, Deserialize, Serialize, derive_more::Display, derive_more::Deref,
@@ -391,10 +1325,23 @@ pub struct MySide(EitherSide);
 
 impl MySide {
     pub fn generate() -> MySide {
-        use rand::{rngs::OsRng, RngCore};
+        Self::from_rng(&mut rand::rngs::OsRng)
+    }
+
+    /**
+     * Like [`generate`](Self::generate), but draw the side from `rng`
+     *
+     * With a seeded RNG, this makes sides reproducible, which is useful in tests. Don't do this in production:
+     * sides need to be unique, so that we can tell our own messages from those of the peer.
+     */
+    #[cfg(any(test, feature = "testing"))]
+    pub fn generate_with(rng: &mut (impl rand::RngCore + rand::CryptoRng)) -> MySide {
+        Self::from_rng(rng)
+    }
 
+    fn from_rng(rng: &mut (impl rand::RngCore + rand::CryptoRng)) -> MySide {
         let mut bytes: [u8; 5] = [0; 5];
-        OsRng.fill_bytes(&mut bytes);
+        rng.fill_bytes(&mut bytes);
 
         MySide(EitherSide(hex::encode(bytes)))
     }
@@ -456,6 +1403,10 @@ impl Phase {
     pub fn to_num(&self) -> Option<u64> {
         self.0.parse().ok()
     }
+    /** Not a phase used by the protocol itself, see [`Wormhole::send_custom`] */
+    pub fn is_custom(&self) -> bool {
+        !self.is_pake() && !self.is_version() && self.to_num().is_none()
+    }
 }
 
 #[derive(PartialEq, Eq, Clone, Debug, Deserialize, Serialize, derive_more::Display)]
@@ -485,7 +1436,8 @@ impl Into<String> for Nameplate {
  *
  * The part until the first dash is called the "nameplate" and is purely numeric.
  * The rest is the password and may be arbitrary, although dash-joining words from
- * a wordlist is a common convention.
+ * a wordlist is a common convention. A user-chosen passphrase works just as well,
+ * e.g. `Code::new(&nameplate, "correct_horse_battery_staple")`.
  */
 #[derive(PartialEq, Eq, Clone, Debug, derive_more::Display, derive_more::Deref)]
 #[display(fmt = "{}", _0)]
@@ -496,6 +1448,52 @@ impl Code {
         Code(format!("{}-{}", nameplate, password))
     }
 
+    /**
+     * Parse and [`validate`](Self::validate) a code
+     *
+     * Use this on codes entered by the user, so that typos are caught before anything is sent to the server.
+     */
+    pub fn parse(code: &str) -> Result<Self, ParseCodeError> {
+        let code = Code(code.to_owned());
+        code.validate()?;
+        Ok(code)
+    }
+
+    /**
+     * Check the structure of the code: a numeric nameplate, a dash and a non-empty password
+     *
+     * The password may be any passphrase without whitespace, it does not have to consist of words
+     * from the wordlist. Use [`validate_words`](Self::validate_words) if it should.
+     */
+    pub fn validate(&self) -> Result<(), ParseCodeError> {
+        ensure!(
+            !self.0.contains(char::is_whitespace),
+            ParseCodeError::Whitespace
+        );
+        let dash = self.0.find('-').ok_or(ParseCodeError::Nameplate)?;
+        let (nameplate, password) = (&self.0[..dash], &self.0[dash + 1..]);
+        ensure!(
+            !nameplate.is_empty() && nameplate.chars().all(|c| c.is_ascii_digit()),
+            ParseCodeError::Nameplate
+        );
+        ensure!(!password.is_empty(), ParseCodeError::MissingPassword);
+        Ok(())
+    }
+
+    /** Like [`validate`](Self::validate), but also check that the password is made of words from the `wordlist` */
+    pub fn validate_words(&self, wordlist: &wordlist::Wordlist) -> Result<(), ParseCodeError> {
+        self.validate()?;
+        let (_, password) = self.split();
+        for (index, word) in password.split('-').enumerate() {
+            ensure!(!word.is_empty(), ParseCodeError::EmptyWord);
+            ensure!(
+                wordlist.contains_word(index, word),
+                ParseCodeError::UnknownWord(word.to_owned())
+            );
+        }
+        Ok(())
+    }
+
     pub fn split(&self) -> (Nameplate, String) {
         let mut iter = self.0.splitn(2, '-');
         let nameplate = Nameplate::new(iter.next().unwrap());
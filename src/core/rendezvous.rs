@@ -6,8 +6,14 @@ use async_tungstenite::tungstenite as ws2;
 use futures::prelude::*;
 use std::collections::VecDeque;
 
+use crate::transcript::{Channel, Direction};
+
+mod pinning;
+pub use pinning::CertificatePin;
+
+pub use crate::core::server_messages::{HashcashPermission, PermissionRequired, WelcomeMessage};
 use crate::core::{
-    server_messages::{InboundMessage, OutboundMessage, PermissionRequired, SubmitPermission},
+    server_messages::{InboundMessage, OutboundMessage, SubmitPermission},
     AppID, EncryptedMessage, Mailbox, Mood, MySide, Nameplate, Phase,
 };
 
@@ -16,6 +22,21 @@ use crate::core::{
 /// Two applications that want to communicate with each other *must* use the same rendezvous server.
 pub const DEFAULT_RENDEZVOUS_SERVER: &str = "ws://relay.magic-wormhole.io:4000/v1";
 
+/// How many peer messages may pile up while waiting for a reply from the server, by default.
+///
+/// See [`RendezvousServer::set_queue_capacity`].
+pub const DEFAULT_QUEUE_CAPACITY: usize = 128;
+
+/// How long the connection to the server may be idle before we check whether it's still alive, by default.
+///
+/// See [`RendezvousServer::set_keepalive_interval`].
+pub const DEFAULT_KEEPALIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How large messages from the server may be, by default.
+///
+/// See [`RendezvousServer::set_max_message_size`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum RendezvousError {
@@ -32,12 +53,33 @@ pub enum RendezvousError {
     Protocol(Box<str>),
     /// The server sent us an error message
     #[error("Received error message from server: {}", _0)]
-    Server(Box<str>),
+    Server(ServerError),
     #[error(
         "Server wants one of {:?} for permissions, but we don't suppport any of these",
         _0
     )]
     Login(Vec<String>),
+    /// The server closed the connection, or did not answer our ping and is probably dead
+    #[error("Lost the connection to the server")]
+    ConnectionLost,
+    /// Pinning the server's certificate needs a `wss://` URL. See [`CertificatePin`]
+    #[error("Cannot pin the certificate of a server that does not use TLS")]
+    PinningRequiresTls,
+    /// The peer sent more messages than we were willing to buffer
+    #[error("Too many unprocessed messages from the peer (capacity is {})", _0)]
+    QueueFull(usize),
+    #[error("Nameplate error")]
+    Nameplate(
+        #[from]
+        #[source]
+        NameplateError,
+    ),
+    #[error("Mailbox error")]
+    Mailbox(
+        #[from]
+        #[source]
+        MailboxError,
+    ),
     #[error("Websocket IO error")]
     IO(
         #[from]
@@ -58,15 +100,98 @@ impl RendezvousError {
         ))
     }
 
-    pub(self) fn server(error: impl Into<Box<str>>) -> Self {
-        Self::Server(error.into())
+    /* The server tells us about a crowded nameplate with a plain error message */
+    fn for_claim(self, nameplate: &Nameplate) -> Self {
+        match self {
+            Self::Server(error) if error.message == "crowded" => {
+                NameplateError::Crowded(nameplate.clone()).into()
+            },
+            other => other,
+        }
     }
 }
 
+/** Something went wrong with claiming or releasing a nameplate */
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum NameplateError {
+    /// Two other clients already use this nameplate. Maybe somebody else was faster, or the code is wrong
+    #[error("The nameplate {} is already in use by two other clients", _0)]
+    Crowded(Nameplate),
+    /// There already is a nameplate or mailbox on this connection, only one is allowed
+    #[error("There already is a nameplate or mailbox on this connection")]
+    AlreadyClaimed,
+    /// There is no nameplate to release, or it already has been released
+    #[error("There is no nameplate to release")]
+    NotClaimed,
+    /// Nobody allocated this nameplate, or it expired already. See [`AppConfig::check_nameplate`](crate::AppConfig::check_nameplate)
+    #[error(
+        "The nameplate {} does not exist. Maybe the code is wrong, or it has expired",
+        _0
+    )]
+    NotFound(Nameplate),
+}
+
+/** Something went wrong with the mailbox */
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum MailboxError {
+    /// Peer messages can only be exchanged after opening a mailbox
+    #[error("No mailbox has been opened on this connection")]
+    NotOpen,
+    /// There already is a mailbox on this connection, only one is allowed
+    #[error("There already is a mailbox open on this connection")]
+    AlreadyOpen,
+}
+
+/**
+ * An error message from the rendezvous server
+ *
+ * The server tells us which of our commands failed, e.g. a `claim` failing because the nameplate is `crowded`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{}", message)]
+pub struct ServerError {
+    /// What went wrong
+    pub message: String,
+    /// The type of the command that caused the error (e.g. `"claim"`), if known
+    pub command: Option<String>,
+}
+
 type MessageQueue = VecDeque<EncryptedMessage>;
 
+/**
+ * Anything that can carry WebSocket messages to the rendezvous server and back
+ *
+ * Usually, this is a [`async_tungstenite::WebSocketStream`], but it may be swapped out,
+ * for example to tunnel through a proxy or for testing purposes. It is implemented
+ * automatically for all fitting types. See [`RendezvousServer::connect_with_transport`].
+ */
+pub trait Transport:
+    Stream<Item = Result<ws2::Message, ws2::Error>>
+    + Sink<ws2::Message, Error = ws2::Error>
+    + Send
+    + Unpin
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<ws2::Message, ws2::Error>>
+        + Sink<ws2::Message, Error = ws2::Error>
+        + Send
+        + Unpin
+{
+}
+
 struct WsConnection {
-    connection: async_tungstenite::WebSocketStream<async_tungstenite::async_std::ConnectStream>,
+    connection: Box<dyn Transport>,
+    /* The id of the last command we sent, to match the server's response */
+    last_id: Option<String>,
+    queue_capacity: usize,
+    keepalive_interval: Option<std::time::Duration>,
+    max_message_size: usize,
+    /* We sent a ping and haven't heard anything since */
+    awaiting_pong: bool,
 }
 
 impl WsConnection {
@@ -76,8 +201,17 @@ impl WsConnection {
         queue: Option<&mut MessageQueue>,
     ) -> Result<(), RendezvousError> {
         log::debug!("Sending {}", message);
+        let id = hex::encode(rand::random::<[u8; 2]>());
+        let mut message_value = serde_json::to_value(message).unwrap();
+        message_value
+            .as_object_mut()
+            .expect("Messages are always objects")
+            .insert("id".into(), id.clone().into());
+        let message_plain = message_value.to_string();
+        self.last_id = Some(id);
+        crate::transcript::record(Channel::Rendezvous, Direction::Sent, &message_plain);
         self.connection
-            .send(ws2::Message::Text(serde_json::to_string(message).unwrap()))
+            .send(ws2::Message::Text(message_plain))
             .await?;
         self.receive_ack(queue).await?;
         Ok(())
@@ -90,9 +224,19 @@ impl WsConnection {
         loop {
             let message = self.receive_message().await?;
             match message {
-                Some(InboundMessage::Ack) => break,
+                Some(InboundMessage::Ack { id }) => {
+                    /* Older servers don't echo the id */
+                    if id.is_none() || id == self.last_id {
+                        break;
+                    }
+                    log::warn!("Got an ack for a message we are not waiting for: {:?}", id);
+                },
                 Some(InboundMessage::Message(message)) => match &mut queue {
                     Some(queue) => {
+                        ensure!(
+                            queue.len() < self.queue_capacity,
+                            RendezvousError::QueueFull(self.queue_capacity)
+                        );
                         queue.push_back(message);
                     },
                     None => {
@@ -123,6 +267,9 @@ impl WsConnection {
                 Some(InboundMessage::Allocated { nameplate }) => {
                     break Ok(RendezvousReply::Allocated(nameplate))
                 },
+                Some(InboundMessage::Nameplates { nameplates }) => {
+                    break Ok(RendezvousReply::Nameplates(nameplates))
+                },
                 Some(InboundMessage::Released) => break Ok(RendezvousReply::Released),
                 Some(InboundMessage::Claimed { mailbox }) => {
                     break Ok(RendezvousReply::Claimed(mailbox))
@@ -130,6 +277,10 @@ impl WsConnection {
                 Some(InboundMessage::Closed) => break Ok(RendezvousReply::Closed),
                 Some(InboundMessage::Message(message)) => match &mut queue {
                     Some(queue) => {
+                        ensure!(
+                            queue.len() < self.queue_capacity,
+                            RendezvousError::QueueFull(self.queue_capacity)
+                        );
                         queue.push_back(message);
                     },
                     None => {
@@ -138,8 +289,8 @@ impl WsConnection {
                         ))
                     },
                 },
-                Some(InboundMessage::Error { error, orig: _ }) => {
-                    break Err(RendezvousError::Server(error.into()));
+                Some(InboundMessage::Error { error, orig }) => {
+                    break Err(self.server_error(error, &orig));
                 },
                 Some(other) => {
                     break Err(RendezvousError::protocol(format!(
@@ -152,6 +303,24 @@ impl WsConnection {
         }
     }
 
+    /** Find out which of our commands an error message belongs to */
+    fn server_error(&self, message: String, orig: &serde_json::Value) -> RendezvousError {
+        let orig_id = orig.get("id").and_then(serde_json::Value::as_str);
+        if orig_id.is_some() && orig_id != self.last_id.as_deref() {
+            log::warn!(
+                "Got an error for a message we are not waiting for: {:?}",
+                orig_id
+            );
+        }
+        RendezvousError::Server(ServerError {
+            message,
+            command: orig
+                .get("type")
+                .and_then(serde_json::Value::as_str)
+                .map(String::from),
+        })
+    }
+
     async fn receive_message_some(&mut self) -> Result<InboundMessage, RendezvousError> {
         loop {
             if let Some(message) = self.receive_message().await? {
@@ -161,13 +330,47 @@ impl WsConnection {
     }
 
     async fn receive_message(&mut self) -> Result<Option<InboundMessage>, RendezvousError> {
-        let message = self
-            .connection
-            .next()
-            .await
-            .expect("TODO this should always be Some")?;
+        let message = loop {
+            let next = match self.keepalive_interval {
+                Some(interval) => {
+                    match async_std::future::timeout(interval, self.connection.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            /* Idle for too long. Ping once, and give up if that goes unanswered too */
+                            ensure!(!self.awaiting_pong, RendezvousError::ConnectionLost);
+                            log::debug!("Connection to the server is idle, sending a ping");
+                            self.connection.send(ws2::Message::Ping(Vec::new())).await?;
+                            self.awaiting_pong = true;
+                            continue;
+                        },
+                    }
+                },
+                None => self.connection.next().await,
+            };
+            /* Anything we receive proves that the connection is alive */
+            self.awaiting_pong = false;
+            break next;
+        };
+        let message = match message {
+            Some(Err(ws2::Error::ConnectionClosed))
+            | Some(Err(ws2::Error::AlreadyClosed))
+            | None => {
+                log::debug!("Connection to the server went away");
+                bail!(RendezvousError::ConnectionLost);
+            },
+            Some(message) => message?,
+        };
         match message {
             ws2::Message::Text(message_plain) => {
+                ensure!(
+                    message_plain.len() <= self.max_message_size,
+                    RendezvousError::protocol(format!(
+                        "Message of {} bytes is larger than the limit of {} bytes",
+                        message_plain.len(),
+                        self.max_message_size
+                    ))
+                );
+                crate::transcript::record(Channel::Rendezvous, Direction::Received, &message_plain);
                 let message = serde_json::from_str(&message_plain)?;
                 log::debug!("Received {}", message);
                 match message {
@@ -175,26 +378,85 @@ impl WsConnection {
                         log::warn!("Got unknown message, ignoring: '{}'", message_plain);
                         Ok(None)
                     },
-                    InboundMessage::Error { error, orig: _ } => Err(RendezvousError::server(error)),
+                    InboundMessage::Error { error, orig } => Err(self.server_error(error, &orig)),
                     message => Ok(Some(message)),
                 }
             },
             ws2::Message::Binary(_) => Err(RendezvousError::protocol(
                 "WebSocket messages must be UTF-8 encoded text",
             )),
-            /* Ignore ping pong for now */
+            /* Pings get answered by the WebSocket implementation, and pongs only matter for the keepalive */
             ws2::Message::Ping(_) => Ok(None),
             ws2::Message::Pong(_) => Ok(None),
             ws2::Message::Close(_) => {
                 log::debug!("Received connection close");
-                Err(ws2::Error::ConnectionClosed.into())
+                Err(RendezvousError::ConnectionLost)
             },
         }
     }
 }
 
+/**
+ * How closing the connection to the server went
+ *
+ * See [`RendezvousServer::close`] and [`Wormhole::close`](crate::Wormhole::close).
+ */
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ClosedStatus {
+    /// The mood we told the server
+    pub mood: Mood,
+    /// The nameplate was released, or there was none to release
+    pub nameplate_released: bool,
+    /// The mailbox was closed, or there was none to close
+    pub mailbox_closed: bool,
+    /// What went wrong while closing, e.g. the server went away. Nothing was attempted after that
+    pub error: Option<RendezvousError>,
+}
+
+impl ClosedStatus {
+    /** Everything was released and closed properly */
+    pub fn is_clean(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /** Turn the status into an error, if there was one */
+    /* Large, but no larger than all the other results with a RendezvousError */
+    #[allow(clippy::result_large_err)]
+    pub fn into_result(self) -> Result<(), RendezvousError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * A snapshot of the state of a [`RendezvousServer`] connection, for diagnostics
+ *
+ * See [`RendezvousServer::debug_state`]. It contains no secrets, so it may be included in bug reports.
+ */
+#[derive(Clone, Debug, PartialEq, Eq, serde_derive::Serialize)]
+#[non_exhaustive]
+pub struct DebugState {
+    /// Our side
+    pub side: MySide,
+    /// The nameplate we hold. `None` if we never had one or already released it
+    pub nameplate: Option<Nameplate>,
+    /// The mailbox we have open, if any
+    pub mailbox: Option<Mailbox>,
+    /// The phases of the peer messages we received so far
+    pub received_phases: Vec<Phase>,
+    /// Peer messages that arrived, but have not been processed yet
+    pub queued_messages: usize,
+    /// We pinged the server and are still waiting for a sign of life
+    pub awaiting_pong: bool,
+}
+
 #[derive(Clone, Debug, derive_more::Display)]
 enum RendezvousReply {
+    #[display(fmt = "Nameplates({:?})", _0)]
+    Nameplates(Vec<Nameplate>),
     Allocated(Nameplate),
     Released,
     Claimed(Mailbox),
@@ -235,6 +497,7 @@ pub struct RendezvousServer {
     connection: WsConnection,
     state: Option<MailboxMachine>,
     side: MySide,
+    welcome: WelcomeMessage,
 }
 
 impl std::fmt::Debug for RendezvousServer {
@@ -246,6 +509,15 @@ impl std::fmt::Debug for RendezvousServer {
     }
 }
 
+/** Limit the WebSocket messages (and thus their frames) from the server to `max_message_size` */
+fn websocket_config(max_message_size: usize) -> ws2::protocol::WebSocketConfig {
+    ws2::protocol::WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..Default::default()
+    }
+}
+
 impl RendezvousServer {
     /**
      * Connect to the rendezvous server
@@ -257,9 +529,155 @@ impl RendezvousServer {
         appid: &AppID,
         relay_url: &str,
     ) -> Result<(Self, Option<String>), RendezvousError> {
-        let side = MySide::generate();
-        let (connection, _) = async_tungstenite::async_std::connect_async(relay_url).await?;
-        let mut connection = WsConnection { connection };
+        crate::metrics::metrics().rendezvous_connect_attempt();
+        let (connection, _) = async_tungstenite::async_std::connect_async_with_config(
+            relay_url,
+            Some(websocket_config(DEFAULT_MAX_MESSAGE_SIZE)),
+        )
+        .await?;
+        Self::connect_with_transport(appid, Box::new(connection)).await
+    }
+
+    /**
+     * Like [`connect`](Self::connect), but only accept a server whose certificate matches one of the `pins`
+     *
+     * Without any pins, this is the same as [`connect`](Self::connect).
+     */
+    pub async fn connect_pinned(
+        appid: &AppID,
+        relay_url: &str,
+        pins: &[CertificatePin],
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        let no_headers: &[(&str, &str)] = &[];
+        Self::connect_with_options(appid, relay_url, no_headers, pins, DEFAULT_MAX_MESSAGE_SIZE)
+            .await
+    }
+
+    /**
+     * Like [`connect`](Self::connect), but add some HTTP headers to the WebSocket upgrade request
+     *
+     * Use this to authenticate to a private server behind a reverse proxy, e.g. with an
+     * `Authorization: Bearer <token>` header.
+     */
+    pub async fn connect_with_headers(
+        appid: &AppID,
+        relay_url: &str,
+        headers: &[(impl AsRef<str>, impl AsRef<str>)],
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        Self::connect_with_options(appid, relay_url, headers, &[], DEFAULT_MAX_MESSAGE_SIZE).await
+    }
+
+    /**
+     * [`connect_with_headers`](Self::connect_with_headers) and [`connect_pinned`](Self::connect_pinned) at once
+     *
+     * Messages larger than `max_message_size` are already rejected by the WebSocket layer while
+     * receiving them, see [`set_max_message_size`](Self::set_max_message_size).
+     */
+    pub(crate) async fn connect_with_options(
+        appid: &AppID,
+        relay_url: &str,
+        headers: &[(impl AsRef<str>, impl AsRef<str>)],
+        pins: &[CertificatePin],
+        max_message_size: usize,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        use ws2::{
+            client::IntoClientRequest,
+            http::header::{HeaderName, HeaderValue, AUTHORIZATION},
+        };
+
+        ensure!(
+            pins.is_empty() || relay_url.starts_with("wss://"),
+            RendezvousError::PinningRequiresTls
+        );
+
+        let mut request = relay_url.into_client_request()?;
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_ref().as_bytes())
+                .map_err(|error| ws2::Error::HttpFormat(error.into()))?;
+            let mut value = HeaderValue::from_str(value.as_ref())
+                .map_err(|error| ws2::Error::HttpFormat(error.into()))?;
+            /* Keep the credentials out of logs */
+            value.set_sensitive(name == AUTHORIZATION);
+            request.headers_mut().append(name, value);
+        }
+
+        /* No `Sec-WebSocket-Extensions` are offered: tungstenite implements none of them, so
+         * permessage-deflate cannot be negotiated until it does.
+         */
+        crate::metrics::metrics().rendezvous_connect_attempt();
+        let connector = (!pins.is_empty()).then(|| pinning::tls_connector(pins));
+        let (connection, _) =
+            async_tungstenite::async_std::connect_async_with_tls_connector_and_config(
+                request,
+                connector,
+                Some(websocket_config(max_message_size)),
+            )
+            .await?;
+        let (mut server, motd) = Self::connect_with_transport(appid, Box::new(connection)).await?;
+        server.set_max_message_size(max_message_size);
+        Ok((server, motd))
+    }
+
+    /**
+     * Like [`connect`](Self::connect), but over an already established connection
+     *
+     * Use this if you need control over how the connection to the server is made, e.g.
+     * to go through a proxy, or to use your own TLS configuration. The transport must
+     * be freshly connected, so that the server's welcome message is the first one received.
+     * Together with [`Wormhole::connect_without_code_via`](crate::Wormhole::connect_without_code_via) and
+     * [`Wormhole::connect_with_code_via`](crate::Wormhole::connect_with_code_via), you can then proceed as usual.
+     *
+     * ```no_run
+     * # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+     * use magic_wormhole::{rendezvous::RendezvousServer, transfer, Wormhole};
+     *
+     * let stream = async_std::net::TcpStream::connect("relay.magic-wormhole.io:4000").await?;
+     * let (connection, _) =
+     *     async_tungstenite::client_async(transfer::APP_CONFIG.rendezvous_url.as_ref(), stream).await?;
+     * let (server, motd) =
+     *     RendezvousServer::connect_with_transport(&transfer::APPID, Box::new(connection)).await?;
+     * let (welcome, connector) =
+     *     Wormhole::connect_without_code_via(server, motd, transfer::APP_CONFIG, 2).await?;
+     * println!("The code is {}", welcome.code);
+     * let wormhole = connector.await?;
+     * # Ok(())
+     * # }
+     * ```
+     */
+    pub async fn connect_with_transport(
+        appid: &AppID,
+        connection: Box<dyn Transport>,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        Self::connect_with_transport_inner(appid, connection, MySide::generate()).await
+    }
+
+    /**
+     * Like [`connect_with_transport`](Self::connect_with_transport), but with the given `side` instead of a random one
+     *
+     * This is meant for reproducible tests, see [`MySide::generate_with`].
+     */
+    #[cfg(any(test, feature = "testing"))]
+    pub async fn connect_with_transport_as(
+        appid: &AppID,
+        connection: Box<dyn Transport>,
+        side: MySide,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        Self::connect_with_transport_inner(appid, connection, side).await
+    }
+
+    async fn connect_with_transport_inner(
+        appid: &AppID,
+        connection: Box<dyn Transport>,
+        side: MySide,
+    ) -> Result<(Self, Option<String>), RendezvousError> {
+        let mut connection = WsConnection {
+            connection,
+            last_id: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            keepalive_interval: Some(DEFAULT_KEEPALIVE_INTERVAL),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            awaiting_pong: false,
+        };
 
         let welcome = match connection.receive_message_some().await? {
             InboundMessage::Welcome { welcome } => welcome,
@@ -271,12 +689,12 @@ impl RendezvousServer {
             },
         };
 
-        match welcome.permission_required {
+        match &welcome.permission_required {
             Some(PermissionRequired {
                 hashcash: Some(hashcash),
                 ..
             }) => {
-                let token = crate::util::hashcash(hashcash.resource, hashcash.bits);
+                let token = crate::util::hashcash(hashcash.resource.clone(), hashcash.bits);
                 connection
                     .send_message(
                         &OutboundMessage::SubmitPermission(SubmitPermission::Hashcash {
@@ -303,21 +721,91 @@ impl RendezvousServer {
 
         log::info!("Connected to server");
 
+        let motd = welcome.motd.clone();
         Ok((
             Self {
                 connection,
                 state: None,
                 side,
+                welcome,
             },
-            welcome.motd,
+            motd,
         ))
     }
 
+    /**
+     * Limit the number of peer messages that may be buffered
+     *
+     * Peer messages are only read from the connection when asked for, so a slow application
+     * will simply make the server wait. However, messages arriving while we are waiting for a
+     * reply from the server need to be buffered. If there are more than `capacity` of them,
+     * the operation fails with [`RendezvousError::QueueFull`] instead of growing without bounds.
+     */
+    pub fn set_queue_capacity(&mut self, capacity: usize) {
+        self.connection.queue_capacity = capacity;
+    }
+
+    /**
+     * Check that the connection is still alive when it has been idle for some time
+     *
+     * Long-idle connections (e.g. while waiting for the other side to enter the code) tend to get dropped
+     * silently by NATs and load balancers. If nothing is received from the server for `interval`, we send
+     * a ping. If there still is no sign of life after another `interval`, waiting fails with
+     * [`RendezvousError::ConnectionLost`]. Pass `None` to disable this.
+     */
+    pub fn set_keepalive_interval(&mut self, interval: Option<std::time::Duration>) {
+        self.connection.keepalive_interval = interval;
+    }
+
+    /**
+     * Reject larger messages from the server
+     *
+     * This limits the size of the messages as they are received, i.e. of the hex encoded peer messages with
+     * their JSON envelope. Larger ones fail with [`RendezvousError::Protocol`], so that nobody can make us
+     * decode huge amounts of data.
+     *
+     * This check only happens once a message has been received completely. Connections made by
+     * [`Wormhole`](crate::Wormhole) also limit the WebSocket messages to [`AppConfig::max_message_size`](crate::AppConfig::max_message_size),
+     * so that they aren't buffered in the first place. [`connect`](Self::connect) and its siblings use
+     * [`DEFAULT_MAX_MESSAGE_SIZE`] for that.
+     */
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.connection.max_message_size = max_message_size;
+    }
+
     /** A random unique string for this session */
     pub fn side(&self) -> &MySide {
         &self.side
     }
 
+    /** The welcome message the server sent when we connected, including any fields we don't know about */
+    pub fn welcome(&self) -> &WelcomeMessage {
+        &self.welcome
+    }
+
+    /** Take a snapshot of the connection state, see [`DebugState`] */
+    pub fn debug_state(&self) -> DebugState {
+        let mut received_phases: Vec<Phase> = self
+            .state
+            .iter()
+            .flat_map(|state| state.processed.iter().cloned())
+            .collect();
+        /* The numeric ones will come first, in order */
+        received_phases
+            .sort_by_key(|phase| (phase.to_num().is_none(), phase.to_num(), phase.0.clone()));
+        DebugState {
+            side: self.side.clone(),
+            nameplate: self
+                .state
+                .as_ref()
+                .and_then(|state| state.nameplate.clone()),
+            mailbox: self.state.as_ref().map(|state| state.mailbox.clone()),
+            received_phases,
+            queued_messages: self.state.as_ref().map_or(0, |state| state.queue.len()),
+            awaiting_pong: self.connection.awaiting_pong,
+        }
+    }
+
     async fn send_message(&mut self, message: &OutboundMessage) -> Result<(), RendezvousError> {
         self.connection
             .send_message(message, self.state.as_mut().map(|state| &mut state.queue))
@@ -336,7 +824,9 @@ impl RendezvousServer {
         body: Vec<u8>,
     ) -> Result<(), RendezvousError> {
         self.send_message(&OutboundMessage::Add { body, phase })
-            .await
+            .await?;
+        crate::metrics::metrics().peer_message_sent();
+        Ok(())
     }
 
     pub async fn next_peer_message_some(&mut self) -> Result<EncryptedMessage, RendezvousError> {
@@ -348,12 +838,10 @@ impl RendezvousServer {
     }
 
     pub async fn next_peer_message(&mut self) -> Result<Option<EncryptedMessage>, RendezvousError> {
-        let machine = &mut self
-            .state
-            .as_mut()
-            .expect("Can only receive messages when having a claimed+open mailbox");
+        let machine = self.state.as_mut().ok_or(MailboxError::NotOpen)?;
         if let Some(message) = machine.queue.pop_front() {
             if machine.receive_message(&message, &self.side) {
+                crate::metrics::metrics().peer_message_received();
                 return Ok(Some(message));
             } else {
                 return Ok(None);
@@ -362,6 +850,7 @@ impl RendezvousServer {
         match self.connection.receive_message().await? {
             Some(InboundMessage::Message(message)) => {
                 if machine.receive_message(&message, &self.side) {
+                    crate::metrics::metrics().peer_message_received();
                     Ok(Some(message))
                 } else {
                     Ok(None)
@@ -375,12 +864,61 @@ impl RendezvousServer {
         }
     }
 
+    /**
+     * Fetch the peer messages that have arrived by now, without waiting for more
+     *
+     * They are buffered for [`next_peer_message`](Self::next_peer_message), at most up to the
+     * [queue capacity](Self::set_queue_capacity). Returns the phases of the buffered messages in order,
+     * without our own and without duplicates.
+     */
+    pub fn poll_peer_messages(&mut self) -> Result<Vec<Phase>, RendezvousError> {
+        let machine = self.state.as_mut().ok_or(MailboxError::NotOpen)?;
+        while machine.queue.len() < self.connection.queue_capacity {
+            /* Receiving is cancellation safe, as long as the keepalive does not kick in. It can't, without waiting */
+            match self.connection.receive_message().now_or_never() {
+                Some(Ok(Some(InboundMessage::Message(message)))) => {
+                    machine.queue.push_back(message)
+                },
+                Some(Ok(Some(other))) => {
+                    return Err(RendezvousError::protocol(format!(
+                        "Expected message from peer, got '{}' instead",
+                        other
+                    )))
+                },
+                Some(Ok(None)) => continue,
+                Some(Err(error)) => return Err(error),
+                None => break,
+            }
+        }
+
+        let mut phases = Vec::new();
+        for message in &machine.queue {
+            if *message.side != *self.side
+                && !machine.processed.contains(&message.phase)
+                && !phases.contains(&message.phase)
+            {
+                phases.push(message.phase.clone());
+            }
+        }
+        Ok(phases)
+    }
+
+    /**
+     * List the nameplates currently in use on the server
+     *
+     * This is mostly useful for completing codes while the user types them in.
+     */
+    pub async fn list_nameplates(&mut self) -> Result<Vec<Nameplate>, RendezvousError> {
+        self.send_message(&OutboundMessage::List).await?;
+        match self.receive_reply().await? {
+            RendezvousReply::Nameplates(nameplates) => Ok(nameplates),
+            other => Err(RendezvousError::invalid_message("nameplates", other)),
+        }
+    }
+
     /** Allocate a nameplate, claim the mailbox and open it */
     pub async fn allocate_claim_open(&mut self) -> Result<(Nameplate, Mailbox), RendezvousError> {
-        assert!(
-            self.state.is_none(),
-            "Can only call in initial state, and only once"
-        );
+        ensure!(self.state.is_none(), NameplateError::AlreadyClaimed);
 
         self.send_message(&OutboundMessage::Allocate).await?;
         let nameplate = match self.receive_reply().await? {
@@ -390,7 +928,11 @@ impl RendezvousServer {
 
         self.send_message(&OutboundMessage::claim(nameplate.clone()))
             .await?;
-        let mailbox = match self.receive_reply().await? {
+        let mailbox = match self
+            .receive_reply()
+            .await
+            .map_err(|error| error.for_claim(&nameplate))?
+        {
             RendezvousReply::Claimed(mailbox) => mailbox,
             other => return Err(RendezvousError::invalid_message("claimed", other)),
         };
@@ -409,14 +951,15 @@ impl RendezvousServer {
 
     /** Claim a nameplate+mailbox and open it */
     pub async fn claim_open(&mut self, nameplate: Nameplate) -> Result<Mailbox, RendezvousError> {
-        assert!(
-            self.state.is_none(),
-            "Can only call in initial state, and only once"
-        );
+        ensure!(self.state.is_none(), NameplateError::AlreadyClaimed);
 
         self.send_message(&OutboundMessage::claim(nameplate.clone()))
             .await?;
-        let mailbox = match self.receive_reply().await? {
+        let mailbox = match self
+            .receive_reply()
+            .await
+            .map_err(|error| error.for_claim(&nameplate))?
+        {
             RendezvousReply::Claimed(mailbox) => mailbox,
             other => return Err(RendezvousError::invalid_message("claimed", other)),
         };
@@ -445,7 +988,7 @@ impl RendezvousServer {
             .state
             .as_mut()
             .and_then(|state| state.nameplate.clone())
-            .expect("Can only release an allocated nameplate, and only once");
+            .ok_or(NameplateError::NotClaimed)?;
 
         use std::ops::Deref;
         self.send_message(&OutboundMessage::release(nameplate.deref().deref()))
@@ -464,10 +1007,7 @@ impl RendezvousServer {
      * This is the base functionality for seeds.
      */
     pub async fn open_directly(&mut self, mailbox: Mailbox) -> Result<(), RendezvousError> {
-        assert!(
-            self.state.is_none(),
-            "Can only call in initial state, and only once"
-        );
+        ensure!(self.state.is_none(), MailboxError::AlreadyOpen);
         self.send_message(&OutboundMessage::open(mailbox.clone()))
             .await?;
         self.state = Some(MailboxMachine {
@@ -479,34 +1019,61 @@ impl RendezvousServer {
         Ok(())
     }
 
-    pub async fn shutdown(mut self, mood: Mood) -> Result<(), RendezvousError> {
-        if let Some(MailboxMachine {
-            nameplate,
-            mailbox,
-            mut queue,
-            ..
-        }) = self.state
-        {
-            if let Some(nameplate) = nameplate {
-                self.connection
-                    .send_message(&OutboundMessage::release(nameplate), Some(&mut queue))
+    /** Like [`close`](Self::close), but only tells whether everything went fine */
+    pub async fn shutdown(self, mood: Mood) -> Result<(), RendezvousError> {
+        self.close(mood).await.into_result()
+    }
+
+    /**
+     * Release the nameplate, close the mailbox and disconnect
+     *
+     * This never fails as a whole. Instead, the [`ClosedStatus`] tells how far we got.
+     */
+    pub async fn close(mut self, mood: Mood) -> ClosedStatus {
+        let mut status = ClosedStatus {
+            mood,
+            nameplate_released: true,
+            mailbox_closed: true,
+            error: None,
+        };
+        let state = self.state.take();
+        let connection = &mut self.connection;
+        let result = async {
+            if let Some(MailboxMachine {
+                nameplate,
+                mailbox,
+                mut queue,
+                ..
+            }) = state
+            {
+                if let Some(nameplate) = nameplate {
+                    status.nameplate_released = false;
+                    connection
+                        .send_message(&OutboundMessage::release(nameplate), Some(&mut queue))
+                        .await?;
+                    match connection.receive_reply(Some(&mut queue)).await? {
+                        RendezvousReply::Released => (),
+                        other => return Err(RendezvousError::invalid_message("released", other)),
+                    };
+                    status.nameplate_released = true;
+                }
+
+                status.mailbox_closed = false;
+                connection
+                    .send_message(&OutboundMessage::close(mailbox, mood), Some(&mut queue))
                     .await?;
-                match self.connection.receive_reply(Some(&mut queue)).await? {
-                    RendezvousReply::Released => (),
-                    other => return Err(RendezvousError::invalid_message("released", other)),
+                match connection.receive_reply(Some(&mut queue)).await? {
+                    RendezvousReply::Closed => (),
+                    other => return Err(RendezvousError::invalid_message("closed", other)),
                 };
+                status.mailbox_closed = true;
             }
 
-            self.connection
-                .send_message(&OutboundMessage::close(mailbox, mood), Some(&mut queue))
-                .await?;
-            match self.connection.receive_reply(Some(&mut queue)).await? {
-                RendezvousReply::Closed => (),
-                other => return Err(RendezvousError::invalid_message("closed", other)),
-            };
+            connection.connection.close().await?;
+            Ok(())
         }
-
-        self.connection.connection.close(None).await?;
-        Ok(())
+        .await;
+        status.error = result.err();
+        status
     }
 }
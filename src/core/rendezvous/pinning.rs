@@ -0,0 +1,188 @@
+//! Pinning the TLS certificate of the rendezvous server
+//!
+//! See [`CertificatePin`].
+
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/**
+ * Only accept a rendezvous server with a known certificate
+ *
+ * Usually, the server's certificate is checked against the system's certificate authorities. This
+ * does not help against TLS-intercepting middleboxes, whose authority has been installed on the machine.
+ * For private deployments, you may instead pin the server's certificate or its public key. A connection
+ * is then only made if the server presents a certificate matching one of the pins, regardless of who
+ * signed it (so self-signed certificates work as well).
+ *
+ * See [`AppConfig::pin_rendezvous_certificate`](crate::AppConfig::pin_rendezvous_certificate).
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CertificatePin {
+    /** The SHA-256 hash of the whole (DER encoded) certificate. This needs updating whenever the certificate is renewed */
+    Certificate([u8; 32]),
+    /**
+     * The SHA-256 hash of the (DER encoded) SubjectPublicKeyInfo of the certificate
+     *
+     * This is what `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum` prints.
+     * It stays the same when the certificate is renewed with the same key.
+     */
+    PublicKey([u8; 32]),
+}
+
+impl CertificatePin {
+    /** Pin the given (DER encoded) certificate */
+    pub fn from_certificate(certificate: &[u8]) -> Self {
+        CertificatePin::Certificate(sha256(certificate))
+    }
+
+    /** Pin the public key of the given (DER encoded) certificate, or `None` if it cannot be parsed */
+    pub fn from_certificate_public_key(certificate: &[u8]) -> Option<Self> {
+        let public_key = subject_public_key_info(certificate)?;
+        Some(CertificatePin::PublicKey(sha256(public_key)))
+    }
+
+    fn matches(&self, certificate: &[u8]) -> bool {
+        match self {
+            CertificatePin::Certificate(hash) => sha256(certificate) == *hash,
+            CertificatePin::PublicKey(hash) => subject_public_key_info(certificate)
+                .map(|public_key| sha256(public_key) == *hash)
+                .unwrap_or(false),
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(data));
+    hash
+}
+
+/* A DER element split off the front of some input */
+struct DerElement<'a> {
+    tag: u8,
+    /* The whole element, including tag and length */
+    element: &'a [u8],
+    contents: &'a [u8],
+    /* The rest of the input after this element */
+    rest: &'a [u8],
+}
+
+fn der_element(input: &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&length, mut rest) = rest.split_first()?;
+    let length = if length < 0x80 {
+        length as usize
+    } else {
+        let octets = (length & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let (length, remainder) = rest.split_at(octets);
+        rest = remainder;
+        length
+            .iter()
+            .fold(0usize, |length, &octet| length << 8 | octet as usize)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    let header = input.len() - rest.len();
+    Some(DerElement {
+        tag,
+        element: &input[..header + length],
+        contents: &rest[..length],
+        rest: &rest[length..],
+    })
+}
+
+/* Just enough DER parsing to find the SubjectPublicKeyInfo of an X.509 certificate */
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let certificate = der_element(certificate)?.contents;
+    let tbs_certificate = der_element(certificate)?.contents;
+    /* The version is optional. Skip it, the serial number, signature, issuer, validity and subject */
+    let first = der_element(tbs_certificate)?;
+    let skip = if first.tag == 0xa0 { 5 } else { 4 };
+    let mut rest = first.rest;
+    for _ in 0..skip {
+        rest = der_element(rest)?.rest;
+    }
+    Some(der_element(rest)?.element)
+}
+
+struct PinningVerifier(Vec<CertificatePin>);
+
+impl rustls::ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        /* Only the end entity proves anything, since the server has to own its key. Everything
+         * else in the chain is public, so anybody could present it.
+         */
+        let certificate = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        if self.0.iter().any(|pin| pin.matches(&certificate.0)) {
+            Ok(rustls::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::TLSError::General(
+                "The server's certificate matches none of the pinned ones".into(),
+            ))
+        }
+    }
+}
+
+/** A TLS connector that only accepts certificates matching one of the `pins` */
+pub(super) fn tls_connector(pins: &[CertificatePin]) -> async_tls::TlsConnector {
+    let mut config = rustls::ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinningVerifier(pins.to_vec())));
+    Arc::new(config).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /* A self-signed certificate for "rendezvous.example" */
+    const CERTIFICATE: &str = "MIIBkTCCATegAwIBAgIUH94RSPF5QhrBKVDhJIYC7qCVhdUwCgYIKoZIzj0EAwIwHTEbMBkGA1UEAwwScmVuZGV6dm91cy5leGFtcGxlMCAXDTI2MTAxNzE3NTg0NFoYDzIxMjYwOTIzMTc1ODQ0WjAdMRswGQYDVQQDDBJyZW5kZXp2b3VzLmV4YW1wbGUwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAAT9SS1x0EI6rHr7gSakslMJ8d6+ffglhkWneYlD2xGO5UoIq+yd3c8NEYzToybEddg49D9v+mYzbFyhgCPnqHZ/o1MwUTAdBgNVHQ4EFgQUC9+47xED/ooBLMQZzsefDGtoyBQwHwYDVR0jBBgwFoAUC9+47xED/ooBLMQZzsefDGtoyBQwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEArGtJ/MbokELmrWoVDepNek1T0nxWTM3g0rlyxee9mi8CIC+y/J1Hr0BT2g+/lCPTAbONm51gOhbCfUFERSleOYQ+";
+
+    fn hash(hex: &str) -> [u8; 32] {
+        let mut hash = [0; 32];
+        hex::decode_to_slice(hex, &mut hash).unwrap();
+        hash
+    }
+
+    #[test]
+    fn test_certificate_pins() {
+        let certificate = base64::decode(CERTIFICATE).unwrap();
+
+        let pin = CertificatePin::from_certificate(&certificate);
+        assert_eq!(
+            pin,
+            CertificatePin::Certificate(hash(
+                "b20bae2be1e69cda7e421fd71aad8d0ed5a5a4495221571624f41a759e95b500"
+            ))
+        );
+        assert!(pin.matches(&certificate));
+
+        /* As computed by openssl */
+        let pin = CertificatePin::from_certificate_public_key(&certificate).unwrap();
+        assert_eq!(
+            pin,
+            CertificatePin::PublicKey(hash(
+                "fc5fb71b4ecf9624a7c3364c3f0b8384dd23383430ac8d66645309f463369bdf"
+            ))
+        );
+        assert!(pin.matches(&certificate));
+
+        assert!(!CertificatePin::PublicKey([0; 32]).matches(&certificate));
+        assert!(!CertificatePin::Certificate([0; 32]).matches(&certificate));
+        assert!(!pin.matches(&certificate[..100]));
+    }
+}
@@ -36,15 +36,25 @@ pub enum SubmitPermission {
     Hashcash { stamp: String },
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Default)]
+/**
+ * The welcome message the server greets us with
+ *
+ * See [`RendezvousServer::welcome`](crate::rendezvous::RendezvousServer::welcome).
+ */
+#[derive(Deserialize, Debug, PartialEq, Eq, Default, Clone)]
 pub struct WelcomeMessage {
     #[deprecated(note = "This is for the Python client")]
     pub current_cli_version: Option<String>,
+    /** A message of the day, which should be shown to the user */
     pub motd: Option<String>,
     #[deprecated(note = "Servers should send a proper error message instead")]
     pub error: Option<String>,
+    /** How the server wants us to prove that we are allowed to use it */
     #[serde(rename = "permission-required")]
     pub permission_required: Option<PermissionRequired>,
+    /** All other fields, for servers that announce more than the protocol knows about */
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
 }
 
 impl std::fmt::Display for WelcomeMessage {
@@ -61,7 +71,7 @@ impl std::fmt::Display for WelcomeMessage {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct PermissionRequired {
     #[serde(deserialize_with = "PermissionRequired::deserialize_none")]
     pub none: bool,
@@ -94,7 +104,7 @@ impl std::fmt::Display for PermissionRequired {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, derive_more::Display)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, derive_more::Display)]
 #[display(
     fmt = "HashcashPermission {{ bits: {}, resource: '{}' }}",
     bits,
@@ -236,7 +246,12 @@ pub enum InboundMessage {
     )]
     Message(EncryptedMessage),
     Closed,
-    Ack,
+    #[display(fmt = "Ack")]
+    Ack {
+        /// The id of the message being acknowledged
+        #[serde(default)]
+        id: Option<String>,
+    },
     #[display(fmt = "Pong({})", pong)]
     Pong {
         pong: u64,
@@ -244,7 +259,9 @@ pub enum InboundMessage {
     #[display(fmt = "Error {{ error: {:?}, .. }}", error)]
     Error {
         error: String,
-        orig: Box<InboundMessage>,
+        /// The message that caused the error. We don't need to understand it
+        #[serde(default)]
+        orig: serde_json::Value,
     },
     #[serde(other)]
     Unknown,
@@ -370,7 +387,8 @@ mod test {
                     current_cli_version: None,
                     motd: None,
                     error: None,
-                    permission_required: None
+                    permission_required: None,
+                    ..
                 }
             }
         ));
@@ -388,7 +406,8 @@ mod test {
                     current_cli_version: None,
                     motd: None,
                     error: None,
-                    permission_required: None
+                    permission_required: None,
+                    ..
                 }
             }
         ));
@@ -402,7 +421,7 @@ mod test {
     fn test_welcome5() {
         let s = r#"{"type": "welcome", "welcome": { "motd": "hello world" }, "server_tx": 1234.56 }"#;
         let m = serde_json::from_str(s).unwrap();
-        assert!(matches!(m, InboundMessage::Welcome { welcome: WelcomeMessage { current_cli_version: None, motd: Some(_), error: None, permission_required: None }  }));
+        assert!(matches!(m, InboundMessage::Welcome { welcome: WelcomeMessage { current_cli_version: None, motd: Some(_), error: None, permission_required: None, .. }  }));
     }
 
     /// Test permission_required field deserialization
@@ -429,11 +448,29 @@ mod test {
                     }),
                     current_cli_version: None,
                     error: None,
+                    other: Default::default(),
                 }
             }
         )
     }
 
+    /// Unknown fields are kept
+    #[test]
+    fn test_welcome7() {
+        let s = r#"{"type": "welcome", "welcome": { "motd": "hello world", "transit-relay": "tcp:relay.example:4001" } }"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        match m {
+            InboundMessage::Welcome { welcome } => {
+                assert_eq!(welcome.motd.as_deref(), Some("hello world"));
+                assert_eq!(
+                    welcome.other.get("transit-relay"),
+                    Some(&json!("tcp:relay.example:4001"))
+                );
+            },
+            other => panic!("Expected a welcome message, got {}", other),
+        }
+    }
+
     #[test]
     fn test_submit_permissions() {
         let m = OutboundMessage::SubmitPermission(SubmitPermission::Hashcash {
@@ -451,11 +488,34 @@ mod test {
         let s = r#"{"type": "ack", "id": null, "server_tx": 1234.56}"#;
         let m = serde_json::from_str(s).unwrap();
         match m {
-            InboundMessage::Ack {} => (),
+            InboundMessage::Ack { id: None } => (),
+            _ => panic!(),
+        }
+
+        let s = r#"{"type": "ack", "id": "1b3f", "server_tx": 1234.56}"#;
+        let m = serde_json::from_str(s).unwrap();
+        match m {
+            InboundMessage::Ack { id: Some(id) } => assert_eq!(id, "1b3f"),
             _ => panic!(),
         }
     }
 
+    #[test]
+    fn test_error() {
+        let s =
+            r#"{"type": "error", "error": "crowded", "orig": {"type": "claim", "nameplate": "1"}}"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert!(matches!(m, InboundMessage::Error { error, .. } if error == "crowded"));
+
+        /* Servers may send garbage or nothing at all in `orig` */
+        let s = r#"{"type": "error", "error": "crowded", "orig": "claim"}"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert!(matches!(m, InboundMessage::Error { .. }));
+        let s = r#"{"type": "error", "error": "crowded"}"#;
+        let m: InboundMessage = serde_json::from_str(s).unwrap();
+        assert!(matches!(m, InboundMessage::Error { .. }));
+    }
+
     #[test]
     fn test_message() {
         let s = r#"{"body": "7b2270616b655f7631223a22353361346566366234363434303364376534633439343832663964373236646538396462366631336632613832313537613335646562393562366237633536353533227d", "server_rx": 1523468188.293486, "id": null, "phase": "pake", "server_tx": 1523498654.753594, "type": "message", "side": "side1"}"#;
@@ -0,0 +1,119 @@
+//! Sending and receiving at the same time, see [`Wormhole::split`]
+
+use super::{with_deadline, with_timeout, Wormhole, WormholeError};
+use async_std::sync::Mutex;
+use futures::future::{self, Either};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/*
+ * Both halves share the wormhole. While the receiver waits for messages it holds the lock, so senders
+ * first announce themselves: the receiver then stops waiting and lets them go first. Waiting for a
+ * message can be interrupted without losing anything, everything that arrives in the meantime is buffered.
+ */
+struct Shared {
+    wormhole: Mutex<Wormhole>,
+    /* Senders waiting for the lock */
+    waiting_senders: AtomicUsize,
+    wakeup: (
+        async_std::channel::Sender<()>,
+        async_std::channel::Receiver<()>,
+    ),
+}
+
+/** The sending half of a [`Wormhole`], see [`Wormhole::split`] */
+pub struct WormholeSender {
+    shared: Arc<Shared>,
+}
+
+/** The receiving half of a [`Wormhole`], see [`Wormhole::split`] */
+pub struct WormholeReceiver {
+    shared: Arc<Shared>,
+}
+
+impl Wormhole {
+    /**
+     * Split the wormhole into a sending and a receiving half
+     *
+     * The halves may be moved to different tasks or threads, so that one of them waits for messages
+     * while the other one sends. The receiver honors the wormhole's [timeout](Self::timeout) and
+     * [deadline](Self::deadline).
+     * Use [`unsplit`](Self::unsplit) to get the wormhole back, for example to close it.
+     */
+    pub fn split(self) -> (WormholeSender, WormholeReceiver) {
+        let shared = Arc::new(Shared {
+            wormhole: Mutex::new(self),
+            waiting_senders: AtomicUsize::new(0),
+            wakeup: async_std::channel::unbounded(),
+        });
+        (
+            WormholeSender {
+                shared: shared.clone(),
+            },
+            WormholeReceiver { shared },
+        )
+    }
+
+    /**
+     * Put the halves of a [split](Self::split) wormhole back together
+     *
+     * # Panics
+     *
+     * If the halves are not from the same wormhole.
+     */
+    pub fn unsplit(sender: WormholeSender, receiver: WormholeReceiver) -> Self {
+        assert!(
+            Arc::ptr_eq(&sender.shared, &receiver.shared),
+            "The halves must belong to the same wormhole"
+        );
+        drop(receiver);
+        match Arc::try_unwrap(sender.shared) {
+            Ok(shared) => shared.wormhole.into_inner(),
+            Err(_) => unreachable!("There are only two halves"),
+        }
+    }
+}
+
+impl WormholeSender {
+    /** Send an encrypted message to peer, see [`Wormhole::send`] */
+    pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
+        /* First announce ourselves, then interrupt the receiver. The receiver checks in the opposite order */
+        self.shared.waiting_senders.fetch_add(1, Ordering::SeqCst);
+        let _ = self.shared.wakeup.0.try_send(());
+        let mut wormhole = self.shared.wormhole.lock().await;
+        self.shared.waiting_senders.fetch_sub(1, Ordering::SeqCst);
+        wormhole.send(plaintext).await
+    }
+}
+
+impl WormholeReceiver {
+    /** Receive an encrypted message from peer, see [`Wormhole::receive`] */
+    pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
+        let (timeout, deadline) = {
+            let wormhole = self.shared.wormhole.lock().await;
+            (wormhole.timeout(), wormhole.deadline())
+        };
+        let receive = with_timeout(timeout, async {
+            loop {
+                let mut wormhole = self.shared.wormhole.lock().await;
+                while self.shared.wakeup.1.try_recv().is_ok() {}
+                if self.shared.waiting_senders.load(Ordering::SeqCst) > 0 {
+                    drop(wormhole);
+                    async_std::task::yield_now().await;
+                    continue;
+                }
+
+                let receive = wormhole.receive_inner();
+                futures::pin_mut!(receive);
+                match future::select(receive, self.shared.wakeup.1.recv()).await {
+                    Either::Left((result, _)) => break result,
+                    /* Let the sender go first */
+                    Either::Right(_) => continue,
+                }
+            }
+        });
+        with_deadline(deadline, receive).await
+    }
+}
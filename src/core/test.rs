@@ -3,9 +3,7 @@ use std::time::Duration;
 
 use crate::{self as magic_wormhole, transfer, transit, AppID, Code, Wormhole};
 
-pub const TEST_APPID: AppID = AppID(std::borrow::Cow::Borrowed(
-    "lothar.com/wormhole/rusty-wormhole-test",
-));
+pub const TEST_APPID: AppID = AppID::from_static("lothar.com/wormhole/rusty-wormhole-test");
 
 const TIMEOUT: Duration = Duration::from_secs(60);
 
@@ -20,6 +18,24 @@ fn init_logger() {
         .try_init();
 }
 
+/** Two wormholes connected to each other over a new in-memory server, see [`memory_pair_with`] */
+async fn memory_pair() -> eyre::Result<(Wormhole, Wormhole)> {
+    memory_pair_with(transfer::APP_CONFIG.id(TEST_APPID)).await
+}
+
+/** Two wormholes connected to each other over a new in-memory server, the first one allocated the code */
+async fn memory_pair_with<V: serde::Serialize + Clone>(
+    config: crate::AppConfig<V>,
+) -> eyre::Result<(Wormhole, Wormhole)> {
+    let server = magic_wormhole::testing::MailboxServer::new();
+    Ok(server.connect_pair(config, 2).await?)
+}
+
+/** The relay hints for transfers in tests: none, both sides connect directly on this machine */
+fn test_relay_urls() -> Vec<transit::RelayUrl> {
+    Vec::new()
+}
+
 /** Send a file using the Rust implementation. This does not guarantee compatibility with Python! ;) */
 #[async_std::test]
 pub async fn test_file_rust2rust() -> eyre::Result<()> {
@@ -41,7 +57,7 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
             eyre::Result::<_>::Ok(
                 transfer::send_file(
                     &mut wormhole,
-                    &transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+                    &[transit::DEFAULT_RELAY_SERVER.parse().unwrap()],
                     &mut async_std::fs::File::open("examples/example-file.bin").await?,
                     "example-file.bin",
                     std::fs::metadata("examples/example-file.bin")
@@ -65,7 +81,7 @@ pub async fn test_file_rust2rust() -> eyre::Result<()> {
 
             let req = transfer::request_file(
                 &mut wormhole,
-                &transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+                &[transit::DEFAULT_RELAY_SERVER.parse().unwrap()],
             )
             .await?;
 
@@ -108,7 +124,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
             log::info!("Sending file #{}", 0);
             let mut wormhole = connector.await?;
             senders.push(async_std::task::spawn(async move {
-                let url = crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap();
+                let url = [crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap()];
                 crate::transfer::send_file(
                     &mut wormhole,
                     &url,
@@ -131,7 +147,7 @@ pub async fn test_send_many() -> eyre::Result<()> {
             )
             .await?;
             senders.push(async_std::task::spawn(async move {
-                let url = crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap();
+                let url = [crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap()];
                 crate::transfer::send_file(
                     &mut wormhole,
                     &url,
@@ -155,10 +171,9 @@ pub async fn test_send_many() -> eyre::Result<()> {
         log::info!("Receiving file #{}", i);
         let (_welcome, mut wormhole) =
             Wormhole::connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code.clone()).await?;
-        log::info!("Got key: {}", &wormhole.key);
         let req = crate::transfer::request_file(
             &mut wormhole,
-            &crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap(),
+            &[crate::transit::DEFAULT_RELAY_SERVER.parse().unwrap()],
         )
         .await?;
 
@@ -237,9 +252,11 @@ pub async fn test_crowded() -> eyre::Result<()> {
 
     match futures::try_join!(connector1, connector2, connector3).unwrap_err() {
         magic_wormhole::WormholeError::ServerError(
-            magic_wormhole::rendezvous::RendezvousError::Server(error),
+            magic_wormhole::rendezvous::RendezvousError::Nameplate(
+                magic_wormhole::rendezvous::NameplateError::Crowded(nameplate),
+            ),
         ) => {
-            assert_eq!(&*error, "crowded")
+            assert_eq!(nameplate, welcome.code.nameplate())
         },
         other => panic!("Got wrong error message: {}, wanted 'crowded'", other),
     }
@@ -247,37 +264,1615 @@ pub async fn test_crowded() -> eyre::Result<()> {
     Ok(())
 }
 
-#[test]
-fn test_phase() {
-    let p = Phase::PAKE;
-    assert!(p.is_pake());
-    assert!(!p.is_version());
+/** Do a simple message round trip over the in-memory rendezvous server */
+#[async_std::test]
+pub async fn test_memory_roundtrip() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+    assert_eq!(wormhole1.verifier, wormhole2.verifier);
+
+    wormhole1.send(b"hello".to_vec()).await?;
+    wormhole2.send(b"world".to_vec()).await?;
+    assert_eq!(wormhole2.receive().await?, b"hello");
+    assert_eq!(wormhole1.receive().await?, b"world");
+
+    let status = wormhole1.close().await;
+    assert!(status.is_clean());
+    assert_eq!(status.mood, Mood::Happy);
+    assert!(status.nameplate_released && status.mailbox_closed);
+    wormhole2.close().await.into_result()?;
+    Ok(())
 }
 
-#[test]
-fn test_mood() {
-    // The serialized forms of these variants are part of the wire protocol,
-    // so they must be spelled exactly as shown (they must match the strings
-    // used in the Python version in src/wormhole/_boss.py , in calls to
-    // self._T.close())
-    assert_eq!(
-        String::from(r#""happy""#),
-        serde_json::to_string(&Mood::Happy).unwrap()
+/** Seeded servers hand out the same sides every time */
+#[async_std::test]
+pub async fn test_memory_seeded_sides() -> eyre::Result<()> {
+    init_logger();
+
+    let server1 = magic_wormhole::testing::MailboxServer::with_seed(42);
+    let server2 = magic_wormhole::testing::MailboxServer::with_seed(42);
+    let (first1, _) = server1.connect(&TEST_APPID).await?;
+    let (second1, _) = server1.connect(&TEST_APPID).await?;
+    let (first2, _) = server2.connect(&TEST_APPID).await?;
+    let (second2, _) = server2.connect(&TEST_APPID).await?;
+    assert_eq!(first1.side(), first2.side());
+    assert_eq!(second1.side(), second2.side());
+    assert_ne!(first1.side(), second1.side());
+
+    let (mut wormhole1, mut wormhole2) = server1
+        .connect_pair(transfer::APP_CONFIG.id(TEST_APPID), 2)
+        .await?;
+    wormhole1.send(b"hello".to_vec()).await?;
+    assert_eq!(wormhole2.receive().await?, b"hello");
+    Ok(())
+}
+
+/** Check for messages without waiting for them */
+#[async_std::test]
+pub async fn test_memory_pending_messages() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+    assert_eq!(wormhole2.pending_messages()?, 0);
+    assert_eq!(wormhole2.try_receive()?, None);
+
+    for message in &["one", "two", "three"] {
+        wormhole1.send(message.as_bytes().to_vec()).await?;
+    }
+    assert_eq!(wormhole2.pending_messages()?, 3);
+    assert_eq!(wormhole2.try_receive()?.as_deref(), Some(&b"one"[..]));
+    assert_eq!(wormhole2.pending_messages()?, 2);
+    assert_eq!(wormhole2.receive().await?, b"two");
+    assert_eq!(wormhole2.try_receive()?.as_deref(), Some(&b"three"[..]));
+    assert_eq!(wormhole2.try_receive()?, None);
+
+    /* Our own messages don't count */
+    assert_eq!(wormhole1.pending_messages()?, 0);
+    Ok(())
+}
+
+/** Send while waiting for messages */
+#[async_std::test]
+pub async fn test_memory_split() -> eyre::Result<()> {
+    init_logger();
+
+    let (wormhole1, mut wormhole2) = memory_pair().await?;
+    let (mut sender, mut receiver) = wormhole1.split();
+
+    /* The receiver is already waiting when the sender starts */
+    let receiving = async_std::task::spawn(async move {
+        let message = receiver.receive().await;
+        (receiver, message)
+    });
+    async_std::task::sleep(Duration::from_millis(50)).await;
+    sender.send(b"ping".to_vec()).await?;
+    assert_eq!(wormhole2.receive().await?, b"ping");
+    wormhole2.send(b"pong".to_vec()).await?;
+    let (receiver, message) = receiving.await;
+    assert_eq!(message?, b"pong");
+
+    let wormhole1 = Wormhole::unsplit(sender, receiver);
+    wormhole1.close().await.into_result()?;
+    wormhole2.close().await.into_result()?;
+    Ok(())
+}
+
+/** Send some data without touching the file system, using the in-memory rendezvous server */
+#[async_std::test]
+pub async fn test_memory_send_data() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+    let data = b"Some data that never touches the disk".to_vec();
+
+    let (sent, received) = futures::join!(
+        transfer::send_data(&mut sender, &relay_urls, &data, "data.bin", |_, _| {}),
+        async {
+            let request = transfer::request_file(&mut receiver, &relay_urls).await?;
+            assert_eq!(request.filename, std::path::Path::new("data.bin"));
+            assert_eq!(request.filesize, data.len() as u64);
+            request.accept_to_vec(|_, _| {}).await
+        },
     );
-    assert_eq!(
-        String::from(r#""lonely""#),
-        serde_json::to_string(&Mood::Lonely).unwrap()
+    let stats = sent?.expect("We should know how it went for the receiver");
+    assert_eq!(stats.bytes_received, data.len() as u64);
+    assert_eq!(received?, data);
+
+    Ok(())
+}
+
+/** The sender gives up if the receiver doesn't answer the offer in time, and tells it so */
+#[async_std::test]
+pub async fn test_memory_answer_timeout() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair_with(
+        transfer::APP_CONFIG
+            .id(TEST_APPID)
+            .answer_timeout(Duration::from_millis(500)),
+    )
+    .await?;
+    assert_eq!(sender.answer_timeout(), Some(Duration::from_millis(500)));
+    let relay_urls = test_relay_urls();
+
+    let (sent, received) = futures::join!(
+        transfer::send_data(
+            &mut sender,
+            &relay_urls,
+            b"unanswered",
+            "data.bin",
+            |_, _| {}
+        ),
+        async {
+            /* Look at the offer, but never answer it */
+            std::mem::drop(transfer::request_file(&mut receiver, &relay_urls).await?);
+            let message: serde_json::Value = serde_json::from_slice(&receiver.receive().await?)?;
+            eyre::Result::<_>::Ok(message)
+        },
     );
+    assert!(matches!(sent, Err(transfer::TransferError::Timeout)));
     assert_eq!(
-        String::from(r#""errory""#),
-        serde_json::to_string(&Mood::Errory).unwrap()
+        received?,
+        serde_json::json!({"error": "Timed out waiting for the other side"})
     );
-    assert_eq!(
-        String::from(r#""scary""#),
-        serde_json::to_string(&Mood::Scared).unwrap()
+
+    Ok(())
+}
+
+/** Stripe a transfer over several transit connections */
+#[async_std::test]
+pub async fn test_memory_send_multi_stream() -> eyre::Result<()> {
+    use std::borrow::Cow;
+
+    init_logger();
+
+    let app_version = transfer::AppVersion {
+        extensions: Cow::Borrowed(&[Cow::Borrowed(transfer::EXTENSION_MULTI_STREAM)]),
+    };
+    let (mut sender, mut receiver) =
+        memory_pair_with(transfer::APP_CONFIG.id(TEST_APPID).app_version(app_version)).await?;
+    let relay_urls = test_relay_urls();
+    /* Smaller files go over one connection */
+    let data: Vec<u8> = (0..transfer::MULTI_STREAM_MIN_SIZE as u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+
+    let (sent, received) = futures::join!(
+        transfer::send_data(&mut sender, &relay_urls, &data, "data.bin", |_, _| {}),
+        async {
+            transfer::request_file(&mut receiver, &relay_urls)
+                .await?
+                .accept_to_vec(|_, _| {})
+                .await
+        },
     );
-    assert_eq!(
-        String::from(r#""unwelcome""#),
-        serde_json::to_string(&Mood::Unwelcome).unwrap()
+    sent?;
+    assert_eq!(received?, data);
+
+    Ok(())
+}
+
+/** Additional connectors offer the same addresses and relays, but on new ports */
+#[async_std::test]
+pub async fn test_transit_another() -> eyre::Result<()> {
+    use std::collections::HashSet;
+
+    init_logger();
+
+    let relay_urls = vec!["tcp:relay.example:4001".parse().unwrap()];
+    let connector = transit::init(
+        vec![transit::Ability::DirectTcpV1, transit::Ability::RelayV1],
+        &relay_urls,
+    )
+    .await?;
+    let another = connector.another().await?;
+
+    assert_eq!(another.our_abilities(), connector.our_abilities());
+    assert_eq!(another.our_hints().relay, connector.our_hints().relay);
+    let hostnames = |connector: &transit::TransitConnector| {
+        connector
+            .our_hints()
+            .direct_tcp
+            .iter()
+            .map(|hint| hint.hostname.clone())
+            .collect::<HashSet<_>>()
+    };
+    assert_eq!(hostnames(&another), hostnames(&connector));
+    let ports = |connector: &transit::TransitConnector| {
+        connector
+            .our_hints()
+            .direct_tcp
+            .iter()
+            .map(|hint| hint.port)
+            .collect::<HashSet<_>>()
+    };
+    assert!(ports(&another).is_disjoint(&ports(&connector)));
+
+    Ok(())
+}
+
+/** Records sent over a striped transit come out in order */
+#[async_std::test]
+pub async fn test_striped_transit_loopback() -> eyre::Result<()> {
+    init_logger();
+
+    let (leader, follower) = transit::loopback().await?;
+    let (mut leader, mut follower) = (
+        transit::StripedTransit::from(leader),
+        transit::StripedTransit::from(follower),
+    );
+    for _ in 0..2 {
+        let (more_leader, more_follower) = transit::loopback().await?;
+        leader.push(more_leader);
+        follower.push(more_follower);
+    }
+    assert_eq!(leader.streams(), 3);
+
+    for i in 0..10u8 {
+        leader.send_record(&[i]).await?;
+    }
+    leader.flush().await?;
+    for i in 0..10u8 {
+        assert_eq!(&*follower.receive_record().await?, &[i]);
+    }
+    follower.send_record(b"ack").await?;
+    follower.flush().await?;
+    assert_eq!(&*leader.receive_record().await?, b"ack");
+
+    Ok(())
+}
+
+/** A receiver that already has the file skips the transfer */
+#[async_std::test]
+pub async fn test_memory_skip_identical() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+    let data = b"Some data the receiver already has".to_vec();
+    let mut file = futures::io::Cursor::new(data.clone());
+
+    let (sent, received) = futures::join!(
+        transfer::send_file_unless_identical(
+            &mut sender,
+            &relay_urls,
+            &mut file,
+            "data.bin",
+            data.len() as u64,
+            transfer::OfferMetadata::default(),
+            |_, _| {},
+        ),
+        async {
+            let request = transfer::request_file(&mut receiver, &relay_urls).await?;
+            assert!(
+                !request
+                    .is_identical_to(&mut futures::io::Cursor::new(b"Other data"))
+                    .await?
+            );
+            assert!(
+                request
+                    .is_identical_to(&mut futures::io::Cursor::new(data.clone()))
+                    .await?
+            );
+            request.skip_identical().await
+        },
+    );
+    received?;
+    let stats = sent?.expect("Skipped transfers always have stats");
+    assert!(stats.already_present);
+    assert_eq!(stats.bytes_received, 0);
+
+    Ok(())
+}
+
+/** Readers that return less than asked for (like pipes) must not end the transfer early */
+#[async_std::test]
+pub async fn test_memory_send_short_reads() -> eyre::Result<()> {
+    use futures::io::AsyncRead;
+    use std::{pin::Pin, task::Poll};
+
+    /* Never returns more than 1000 bytes at once */
+    struct Trickle<R>(R);
+
+    impl<R: AsyncRead + Unpin> AsyncRead for Trickle<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let len = buf.len().min(1000);
+            Pin::new(&mut self.0).poll_read(cx, &mut buf[..len])
+        }
+    }
+
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    let mut file = Trickle(futures::io::Cursor::new(data.clone()));
+
+    let (sent, received) = futures::join!(
+        transfer::send_file(
+            &mut sender,
+            &relay_urls,
+            &mut file,
+            "data.bin",
+            data.len() as u64,
+            |_, _| {},
+        ),
+        async {
+            transfer::request_file(&mut receiver, &relay_urls)
+                .await?
+                .accept_to_vec(|_, _| {})
+                .await
+        },
+    );
+    sent?;
+    assert_eq!(received?, data);
+
+    Ok(())
+}
+
+/** Send a sparse file, skipping the holes */
+#[async_std::test]
+pub async fn test_memory_send_sparse() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+    let mut data = vec![0; 20000];
+    data[10000..10004].copy_from_slice(b"data");
+    let regions: Vec<std::ops::Range<u64>> = std::iter::once(10000..10004).collect();
+    let mut file = futures::io::Cursor::new(data.clone());
+
+    let (sent, received) = futures::join!(
+        transfer::send_sparse_file(
+            &mut sender,
+            &relay_urls,
+            &mut file,
+            "sparse.img",
+            data.len() as u64,
+            transfer::OfferMetadata::default(),
+            &regions,
+            |_, _| {},
+        ),
+        async {
+            let request = transfer::request_file(&mut receiver, &relay_urls).await?;
+            assert_eq!(request.filesize, data.len() as u64);
+            request.accept_to_vec(|_, _| {}).await
+        },
+    );
+    sent?;
+    assert_eq!(received?, data);
+
+    Ok(())
+}
+
+/** Connect transit to itself, without any server */
+#[async_std::test]
+pub async fn test_transit_loopback() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut leader, mut follower) = transit::loopback().await?;
+    leader.send_record(b"hello").await?;
+    follower.send_record(b"world").await?;
+    assert_eq!(&*follower.receive_record().await?, b"hello");
+    assert_eq!(&*leader.receive_record().await?, b"world");
+
+    Ok(())
+}
+
+/** Records encrypted with ChaCha20-Poly1305 only work if both sides use it */
+#[async_std::test]
+pub async fn test_transit_loopback_chacha20poly1305() -> eyre::Result<()> {
+    use transit::RecordCipher;
+
+    init_logger();
+
+    let (mut leader, mut follower) = transit::loopback().await?;
+    leader.set_record_cipher(RecordCipher::ChaCha20Poly1305);
+    follower.set_record_cipher(RecordCipher::ChaCha20Poly1305);
+    leader.send_record(b"hello").await?;
+    follower.send_record(b"world").await?;
+    assert_eq!(&*follower.receive_record().await?, b"hello");
+    assert_eq!(&*leader.receive_record().await?, b"world");
+
+    let (mut leader, mut follower) = transit::loopback().await?;
+    leader.set_record_cipher(RecordCipher::ChaCha20Poly1305);
+    leader.send_record(b"hello").await?;
+    assert!(matches!(
+        follower.receive_record().await,
+        Err(transit::TransitError::Security(_))
+    ));
+
+    Ok(())
+}
+
+/** Oversized records are rejected before reading them */
+#[async_std::test]
+pub async fn test_transit_loopback_max_record_size() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut leader, mut follower) = transit::loopback().await?;
+    assert_eq!(follower.max_record_size(), transit::DEFAULT_MAX_RECORD_SIZE);
+    follower.set_max_record_size(1024);
+    leader.send_record(&[0; 512]).await?;
+    assert_eq!(follower.receive_record().await?.len(), 512);
+    leader.send_record(&[0; 2048]).await?;
+    assert!(matches!(
+        follower.receive_record().await,
+        Err(transit::TransitError::Protocol(_))
+    ));
+
+    Ok(())
+}
+
+/** Stream bytes over a bare transit connection, without the file transfer */
+#[async_std::test]
+pub async fn test_memory_transit_io() -> eyre::Result<()> {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::Arc;
+
+    init_logger();
+
+    async fn connect(wormhole: &mut Wormhole, is_leader: bool) -> eyre::Result<transit::Transit> {
+        let connector =
+            transit::init(transit::Ability::all_abilities(), &test_relay_urls()).await?;
+        let ours = transit::TransitType {
+            abilities_v1: connector.our_abilities().to_vec(),
+            hints_v1: (**connector.our_hints()).clone().into(),
+        };
+        wormhole.send(serde_json::to_vec(&ours)?).await?;
+        let theirs: transit::TransitType = serde_json::from_slice(&wormhole.receive().await?)?;
+
+        let abilities = Arc::new(theirs.abilities_v1);
+        let hints = Arc::new(theirs.hints_v1.into());
+        Ok(if is_leader {
+            connector
+                .leader_connect(wormhole.derive_transit_key(), abilities, hints)
+                .await?
+        } else {
+            connector
+                .follower_connect(wormhole.derive_transit_key(), abilities, hints)
+                .await?
+        })
+    }
+
+    let (mut leader, mut follower) = memory_pair().await?;
+    /* Larger than a single record */
+    let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+
+    let (sent, received) = futures::join!(
+        async {
+            let (_reader, mut writer) = connect(&mut leader, true).await?.into_io();
+            writer.write_all(&data).await?;
+            writer.write_all(b"").await?;
+            writer.close().await?;
+            eyre::Result::<_>::Ok(())
+        },
+        async {
+            let (mut reader, _writer) = connect(&mut follower, false).await?.into_io();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await?;
+            eyre::Result::<_>::Ok(received)
+        },
+    );
+    sent?;
+    assert_eq!(received?, data);
+
+    Ok(())
+}
+
+/** Both sides punch holes, and get a direct connection */
+#[async_std::test]
+pub async fn test_memory_transit_hole_punching() -> eyre::Result<()> {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::Arc;
+
+    init_logger();
+
+    async fn connect(wormhole: &mut Wormhole, is_leader: bool) -> eyre::Result<transit::Transit> {
+        /* Without relays, only a direct connection can succeed */
+        let mut connector = transit::init(vec![transit::Ability::DirectTcpV1], &[]).await?;
+        connector.set_hole_punching(Some(Duration::from_secs(5)));
+        let ours = transit::TransitType {
+            abilities_v1: connector.our_abilities().to_vec(),
+            hints_v1: connector.our_hints_for(None),
+        };
+        wormhole.send(serde_json::to_vec(&ours)?).await?;
+        let theirs: transit::TransitType = serde_json::from_slice(&wormhole.receive().await?)?;
+
+        let abilities = Arc::new(theirs.abilities_v1);
+        let hints = Arc::new(theirs.hints_v1.into());
+        Ok(if is_leader {
+            connector
+                .leader_connect(wormhole.derive_transit_key(), abilities, hints)
+                .await?
+        } else {
+            connector
+                .follower_connect(wormhole.derive_transit_key(), abilities, hints)
+                .await?
+        })
+    }
+
+    let (mut leader, mut follower) = memory_pair().await?;
+    let (sent, received) = futures::join!(
+        async {
+            let (_reader, mut writer) = connect(&mut leader, true).await?.into_io();
+            writer.write_all(b"punched").await?;
+            writer.close().await?;
+            eyre::Result::<_>::Ok(())
+        },
+        async {
+            let (mut reader, _writer) = connect(&mut follower, false).await?.into_io();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await?;
+            eyre::Result::<_>::Ok(received)
+        },
+    );
+    sent?;
+    assert_eq!(received?, b"punched");
+
+    Ok(())
+}
+
+/** A port that is reserved, but doesn't accept connections (yet) */
+fn closed_port() -> eyre::Result<(socket2::Socket, std::net::SocketAddr)> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
+    socket.bind(
+        &"[::ffff:127.0.0.1]:0"
+            .parse::<std::net::SocketAddr>()?
+            .into(),
+    )?;
+    let addr = socket.local_addr()?.as_socket().unwrap();
+    Ok((socket, addr))
+}
+
+/** Hole punching keeps trying until the other side is there */
+#[async_std::test]
+pub async fn test_hole_punching_retry() -> eyre::Result<()> {
+    init_logger();
+
+    let (socket, dest_addr) = closed_port()?;
+    let local_addr: std::net::SocketAddr = "[::ffff:127.0.0.1]:0".parse()?;
+    let (local_addr, dest_addr) = (local_addr.into(), dest_addr.into());
+    let (punched, ()) = futures::join!(
+        transit::punch_hole(&local_addr, &dest_addr, Duration::from_secs(5)),
+        async {
+            /* Let the first attempts fail */
+            async_std::task::sleep(2 * transit::HOLE_PUNCHING_INTERVAL).await;
+            socket.listen(1).unwrap();
+        },
+    );
+    assert_eq!(Some(punched?.peer_addr()?), dest_addr.as_socket());
+
+    Ok(())
+}
+
+/** Hole punching gives up once the window has passed */
+#[async_std::test]
+pub async fn test_hole_punching_window() -> eyre::Result<()> {
+    init_logger();
+
+    let (_socket, dest_addr) = closed_port()?;
+    let local_addr: std::net::SocketAddr = "[::ffff:127.0.0.1]:0".parse()?;
+    let window = Duration::from_secs(1);
+    let start = std::time::Instant::now();
+    let punched = transit::punch_hole(&local_addr.into(), &dest_addr.into(), window).await;
+    assert!(punched.is_err());
+    assert!(start.elapsed() >= window);
+    assert!(start.elapsed() < window + 2 * transit::HOLE_PUNCHING_INTERVAL);
+
+    Ok(())
+}
+
+/** The sender learns why its offer got rejected */
+#[async_std::test]
+pub async fn test_memory_offer_rejected() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+
+    let (sent, received) = futures::join!(
+        transfer::send_data(&mut sender, &relay_urls, b"unwanted", "spam.txt", |_, _| {}),
+        async {
+            transfer::request_file(&mut receiver, &relay_urls)
+                .await?
+                .reject()
+                .await
+        },
     );
+    received?;
+    match sent {
+        Err(transfer::TransferError::OfferRejected { reason }) => {
+            assert_eq!(reason, "transfer rejected")
+        },
+        other => panic!("Expected a rejection, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+/** Send the same file to two recipients, each with their own code */
+#[async_std::test]
+pub async fn test_memory_send_to_many() -> eyre::Result<()> {
+    init_logger();
+
+    let path = std::env::temp_dir().join(format!(
+        "magic-wormhole-test-send-to-many-{}",
+        std::process::id()
+    ));
+    std::fs::write(&path, b"for everyone")?;
+    let result = async {
+        let server = magic_wormhole::testing::MailboxServer::new();
+        let relay_urls = test_relay_urls();
+        let many = transfer::SendToMany::new(
+            transfer::APP_CONFIG.id(TEST_APPID),
+            2,
+            relay_urls.clone(),
+            &path,
+            "data.txt",
+        );
+
+        let mut codes = Vec::new();
+        for _ in 0..2 {
+            let (rendezvous, welcome) = server.connect(&TEST_APPID).await?;
+            let recipient = many.next_recipient_via(rendezvous, welcome).await?;
+            let code = recipient.welcome.code.clone();
+            let (sent, received) = futures::join!(recipient.send(|_, _| {}), async {
+                let (_welcome, mut wormhole) = server
+                    .connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code.clone())
+                    .await?;
+                let data = transfer::request_file(&mut wormhole, &relay_urls)
+                    .await?
+                    .accept_to_vec(|_, _| {})
+                    .await?;
+                eyre::Result::<_>::Ok(data)
+            });
+            sent?;
+            assert_eq!(received?, b"for everyone");
+            assert!(!codes.contains(&code));
+            codes.push(code);
+        }
+        eyre::Result::<_>::Ok(())
+    }
+    .await;
+    std::fs::remove_file(&path)?;
+    result
+}
+
+/** A recipient of a `SendToMany` rejecting the file */
+#[async_std::test]
+pub async fn test_memory_send_to_many_rejected() -> eyre::Result<()> {
+    init_logger();
+
+    let path = std::env::temp_dir().join(format!(
+        "magic-wormhole-test-send-to-many-rejected-{}",
+        std::process::id()
+    ));
+    std::fs::write(&path, b"unwanted")?;
+    let result = async {
+        let server = magic_wormhole::testing::MailboxServer::new();
+        let relay_urls = test_relay_urls();
+        let many = transfer::SendToMany::new(
+            transfer::APP_CONFIG.id(TEST_APPID),
+            2,
+            relay_urls.clone(),
+            &path,
+            "spam.txt",
+        );
+
+        let (rendezvous, welcome) = server.connect(&TEST_APPID).await?;
+        let recipient = many.next_recipient_via(rendezvous, welcome).await?;
+        let code = recipient.welcome.code.clone();
+        let (sent, received) = futures::join!(recipient.send(|_, _| {}), async {
+            let (_welcome, mut wormhole) = server
+                .connect_with_code(transfer::APP_CONFIG.id(TEST_APPID), code)
+                .await?;
+            transfer::request_file(&mut wormhole, &relay_urls)
+                .await?
+                .reject()
+                .await?;
+            eyre::Result::<_>::Ok(())
+        });
+        received?;
+        match sent {
+            Err(transfer::TransferError::OfferRejected { reason }) => {
+                assert_eq!(reason, "transfer rejected")
+            },
+            other => panic!("Expected a rejection, got {:?}", other),
+        }
+        eyre::Result::<_>::Ok(())
+    }
+    .await;
+    std::fs::remove_file(&path)?;
+    result
+}
+
+/** Send two files and then a message over the same wormhole */
+#[async_std::test]
+pub async fn test_memory_sequential_transfers() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+    let relay_urls = test_relay_urls();
+
+    let (sent, received) = futures::join!(
+        async {
+            transfer::send_data(&mut sender, &relay_urls, b"first", "first.txt", |_, _| {}).await?;
+            transfer::send_data(&mut sender, &relay_urls, b"second", "second.txt", |_, _| {})
+                .await?;
+            transfer::send_message(&mut sender, "done", TIMEOUT).await?;
+            eyre::Result::<_>::Ok(())
+        },
+        async {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                match transfer::request_offer(&mut receiver, &relay_urls).await? {
+                    transfer::Offer::File(request) => {
+                        let filename = request.filename.clone();
+                        let data = request.accept_to_vec(|_, _| {}).await?;
+                        received.push((filename.display().to_string(), data));
+                    },
+                    transfer::Offer::Message(message) => {
+                        received.push(("message".to_string(), message.into_bytes()));
+                    },
+                }
+            }
+            eyre::Result::<_>::Ok(received)
+        },
+    );
+    sent?;
+    assert_eq!(
+        received?,
+        vec![
+            ("first.txt".to_string(), b"first".to_vec()),
+            ("second.txt".to_string(), b"second".to_vec()),
+            ("message".to_string(), b"done".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+/** A text message is only delivered once the other side acknowledged it */
+#[async_std::test]
+pub async fn test_memory_send_message() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut sender, mut receiver) = memory_pair().await?;
+
+    let (sent, received) = futures::join!(
+        transfer::send_message(&mut sender, "Hello", TIMEOUT),
+        async {
+            let message = receiver.receive().await?;
+            receiver
+                .send(br#"{"answer": {"message_ack": "ok"}}"#.to_vec())
+                .await?;
+            eyre::Result::<_>::Ok(message)
+        },
+    );
+    sent?;
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&received?)?,
+        serde_json::json!({"offer": {"message": "Hello"}})
+    );
+
+    /* Nobody answers this time */
+    assert!(matches!(
+        transfer::send_message(&mut sender, "Hello?", Duration::from_millis(100)).await,
+        Err(transfer::TransferError::Timeout)
+    ));
+
+    Ok(())
+}
+
+/** The transcript records the conversation with the server, but no secrets */
+#[async_std::test]
+pub async fn test_memory_transcript() -> eyre::Result<()> {
+    use magic_wormhole::transcript::{self, Channel, Direction};
+    init_logger();
+
+    transcript::start();
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+    wormhole1.send(b"secret".to_vec()).await?;
+    assert_eq!(wormhole2.receive().await?, b"secret");
+    let transcript = transcript::stop().expect("We started recording");
+
+    assert!(transcript
+        .entries
+        .iter()
+        .any(|entry| entry.channel == Channel::Rendezvous
+            && entry.direction == Direction::Sent
+            && entry.message.contains(r#""type":"bind""#)));
+    let secrets = regex::Regex::new("[0-9a-fA-F]{64}").unwrap();
+    assert!(transcript
+        .entries
+        .iter()
+        .all(|entry| !secrets.is_match(&entry.message)));
+    assert!(transcript::stop().is_none());
+
+    Ok(())
+}
+
+/** Idle connections stay up as long as the server answers our pings */
+#[async_std::test]
+pub async fn test_memory_keepalive() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair_with(
+        transfer::APP_CONFIG
+            .id(TEST_APPID)
+            .keepalive_interval(Some(Duration::from_millis(20))),
+    )
+    .await?;
+
+    let (sent, received) = futures::join!(
+        async {
+            async_std::task::sleep(Duration::from_millis(200)).await;
+            wormhole1.send(b"still there?".to_vec()).await
+        },
+        wormhole2.receive(),
+    );
+    sent?;
+    assert_eq!(received?, b"still there?");
+
+    Ok(())
+}
+
+/** A server that stops talking to us or hangs up is detected as dead */
+#[async_std::test]
+pub async fn test_keepalive_connection_lost() -> eyre::Result<()> {
+    use async_tungstenite::tungstenite as ws2;
+    use futures::{Sink, Stream, StreamExt};
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /* Welcomes us, acknowledges the bind, and then falls silent */
+    struct SilentServer(Pin<Box<dyn Stream<Item = Result<ws2::Message, ws2::Error>> + Send>>);
+
+    impl Stream for SilentServer {
+        type Item = Result<ws2::Message, ws2::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.0.poll_next_unpin(cx)
+        }
+    }
+
+    impl Sink<ws2::Message> for SilentServer {
+        type Error = ws2::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ws2::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: ws2::Message) -> Result<(), ws2::Error> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ws2::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ws2::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    init_logger();
+
+    let messages = vec![
+        Ok(ws2::Message::Text(
+            r#"{"type": "welcome", "welcome": {}}"#.into(),
+        )),
+        Ok(ws2::Message::Text(r#"{"type": "ack"}"#.into())),
+    ];
+    let transport = SilentServer(
+        futures::stream::iter(messages)
+            .chain(futures::stream::pending())
+            .boxed(),
+    );
+    let (mut server, _) = magic_wormhole::rendezvous::RendezvousServer::connect_with_transport(
+        &TEST_APPID,
+        Box::new(transport),
+    )
+    .await?;
+    server.set_keepalive_interval(Some(Duration::from_millis(20)));
+
+    assert!(matches!(
+        server.list_nameplates().await,
+        Err(magic_wormhole::rendezvous::RendezvousError::ConnectionLost)
+    ));
+
+    /* This one hangs up right after the welcome */
+    let messages = vec![Ok(ws2::Message::Text(
+        r#"{"type": "welcome", "welcome": {}}"#.into(),
+    ))];
+    let (mut server, _) = magic_wormhole::rendezvous::RendezvousServer::connect_with_transport(
+        &TEST_APPID,
+        Box::new(SilentServer(futures::stream::iter(messages).boxed())),
+    )
+    .await?;
+
+    assert!(matches!(
+        server.list_nameplates().await,
+        Err(magic_wormhole::rendezvous::RendezvousError::ConnectionLost)
+    ));
+
+    Ok(())
+}
+
+/** Messages in custom phases go to the handler, and don't get in the way otherwise */
+#[async_std::test]
+pub async fn test_memory_custom_phase() -> eyre::Result<()> {
+    use magic_wormhole::Phase;
+    use std::sync::{Arc, Mutex};
+
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    wormhole1.set_custom_phase_handler(move |phase, message| {
+        received2.lock().unwrap().push((phase.clone(), message));
+    });
+
+    wormhole2
+        .send_custom(Phase("x-hello".into()), b"hello".to_vec())
+        .await?;
+    wormhole2.send(b"world".to_vec()).await?;
+    assert_eq!(wormhole1.receive().await?, b"world");
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(Phase("x-hello".into()), b"hello".to_vec())]
+    );
+
+    Ok(())
+}
+
+/** The debug state tells how far the connection got */
+#[async_std::test]
+pub async fn test_memory_debug_state() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+    wormhole1.send(b"hello".to_vec()).await?;
+    wormhole2.receive().await?;
+
+    let state = wormhole2.debug_state();
+    assert!(state.first_message_received);
+    assert_eq!(state.messages_sent, 0);
+    /* The nameplate gets released right after the key exchange */
+    assert_eq!(state.rendezvous.nameplate, None);
+    assert!(state.rendezvous.mailbox.is_some());
+    assert_eq!(
+        state.rendezvous.received_phases,
+        vec![
+            magic_wormhole::Phase::numeric(0),
+            magic_wormhole::Phase::PAKE,
+            magic_wormhole::Phase::VERSION
+        ]
+    );
+    /* It is meant to be serialized */
+    serde_json::to_string(&state)?;
+
+    Ok(())
+}
+
+/** The steps of the connection are timed */
+#[async_std::test]
+pub async fn test_memory_timings() -> eyre::Result<()> {
+    init_logger();
+
+    let (mut wormhole1, mut wormhole2) = memory_pair().await?;
+    let timings = wormhole2.timings();
+    assert!(timings.claim.is_some());
+    assert!(timings.pake.is_some());
+    assert_eq!(timings.first_message, None);
+
+    wormhole1.send(b"hello".to_vec()).await?;
+    wormhole2.receive().await?;
+    assert!(wormhole2.timings().first_message.is_some());
+
+    Ok(())
+}
+
+/** Only extensions that both sides announce get used */
+#[async_std::test]
+pub async fn test_memory_negotiate_extensions() -> eyre::Result<()> {
+    use std::borrow::Cow;
+    use transfer::AppVersion;
+
+    init_logger();
+
+    let config = |extensions: &'static [Cow<'static, str>]| {
+        transfer::APP_CONFIG.id(TEST_APPID).app_version(AppVersion {
+            extensions: Cow::Borrowed(extensions),
+        })
+    };
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (welcome, connector) = server
+        .connect_without_code(
+            config(&[Cow::Borrowed("compression"), Cow::Borrowed("resume")]),
+            2,
+        )
+        .await?;
+    let ((_welcome, wormhole2), wormhole1) = futures::try_join!(
+        server.connect_with_code(config(&[Cow::Borrowed("resume")]), welcome.code),
+        connector,
+    )?;
+    assert_eq!(AppVersion::negotiate(&wormhole1), vec!["resume"]);
+    assert_eq!(AppVersion::negotiate(&wormhole2), vec!["resume"]);
+
+    /* The built-in extensions are announced by default */
+    let (wormhole1, _wormhole2) = server
+        .connect_pair(transfer::APP_CONFIG.id(TEST_APPID), 2)
+        .await?;
+    let extensions = &transfer::APP_CONFIG.app_version.extensions;
+    assert!(extensions.contains(&Cow::Borrowed(transfer::EXTENSION_SPARSE)));
+    assert_eq!(
+        wormhole1.our_version,
+        serde_json::json!({ "extensions": extensions })
+    );
+    assert_eq!(AppVersion::negotiate(&wormhole1), extensions.to_vec());
+
+    /* Announcing nothing */
+    let (wormhole1, _wormhole2) = server.connect_pair(config(&[]), 2).await?;
+    assert_eq!(wormhole1.our_version, serde_json::json!({}));
+    assert!(AppVersion::negotiate(&wormhole1).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_code_parse() {
+    use magic_wormhole::ParseCodeError;
+
+    assert_eq!(
+        Code::parse("4-purple-sausages").unwrap(),
+        Code("4-purple-sausages".into())
+    );
+    assert_eq!(
+        Code::parse("4 purple").unwrap_err(),
+        ParseCodeError::Whitespace
+    );
+    assert_eq!(
+        Code::parse("purple").unwrap_err(),
+        ParseCodeError::Nameplate
+    );
+    assert_eq!(
+        Code::parse("x4-purple").unwrap_err(),
+        ParseCodeError::Nameplate
+    );
+    assert_eq!(
+        Code::parse("-purple").unwrap_err(),
+        ParseCodeError::Nameplate
+    );
+    assert_eq!(
+        Code::parse("4-").unwrap_err(),
+        ParseCodeError::MissingPassword
+    );
+    /* Arbitrary passphrases are fine, unless we want words */
+    let code = Code::parse("4-Tr0ub4dor&3--x").unwrap();
+    assert_eq!(
+        code.validate_words(&magic_wormhole::wordlist::default_wordlist(2)),
+        Err(ParseCodeError::UnknownWord("Tr0ub4dor&3".into()))
+    );
+    assert_eq!(
+        Code::parse("4-adroitness--aardvark")
+            .unwrap()
+            .validate_words(&magic_wormhole::wordlist::default_wordlist(2)),
+        Err(ParseCodeError::EmptyWord)
+    );
+
+    let wordlist = magic_wormhole::wordlist::default_wordlist(2);
+    let code = Code::parse("4-adroitness-aardvark").unwrap();
+    assert_eq!(code.validate_words(&wordlist), Ok(()));
+    let code = Code::parse("4-adroitness-aardwolf").unwrap();
+    assert_eq!(
+        code.validate_words(&wordlist),
+        Err(ParseCodeError::UnknownWord("aardwolf".into()))
+    );
+}
+
+/** Both sides must agree on derived sub-keys */
+#[async_std::test]
+pub async fn test_memory_derive_key() -> eyre::Result<()> {
+    init_logger();
+
+    let (wormhole1, wormhole2) = memory_pair().await?;
+
+    let key1: magic_wormhole::Key<magic_wormhole::GenericKey> = wormhole1.derive_key("purpose");
+    let key2: magic_wormhole::Key<magic_wormhole::GenericKey> = wormhole2.derive_key("purpose");
+    assert_eq!(key1.to_hex(), key2.to_hex());
+    let other: magic_wormhole::Key<magic_wormhole::GenericKey> = wormhole1.derive_key("other");
+    assert_ne!(key1.to_hex(), other.to_hex());
+    assert_eq!(
+        wormhole1.derive_transit_key().to_hex(),
+        wormhole2.derive_transit_key().to_hex()
+    );
+
+    Ok(())
+}
+
+/** Nobody shows up, or nobody says anything */
+#[async_std::test]
+pub async fn test_memory_timeout() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .timeout(Duration::from_millis(100));
+
+    let (_welcome, connector) = server.connect_without_code(config.clone(), 2).await?;
+    assert!(matches!(
+        connector.await,
+        Err(magic_wormhole::WormholeError::Timeout)
+    ));
+
+    let (mut wormhole1, mut wormhole2) = server.connect_pair(config, 2).await?;
+    assert!(matches!(
+        wormhole1.receive().await,
+        Err(magic_wormhole::WormholeError::Timeout)
+    ));
+    /* Still usable after a timeout */
+    wormhole2.send(b"hello".to_vec()).await?;
+    assert_eq!(wormhole1.receive().await?, b"hello");
+
+    Ok(())
+}
+
+/** The deadline covers the whole session, not single steps */
+#[async_std::test]
+pub async fn test_memory_deadline() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .deadline(Duration::from_millis(300));
+
+    let (_welcome, connector) = server.connect_without_code(config.clone(), 2).await?;
+    assert!(matches!(
+        connector.await,
+        Err(magic_wormhole::WormholeError::DeadlineExceeded)
+    ));
+
+    let (mut wormhole1, mut wormhole2) = server.connect_pair(config, 2).await?;
+    assert!(wormhole1.deadline().is_some());
+    wormhole2.send(b"hello".to_vec()).await?;
+    assert_eq!(wormhole1.receive().await?, b"hello");
+
+    assert!(matches!(
+        wormhole1.receive().await,
+        Err(magic_wormhole::WormholeError::DeadlineExceeded)
+    ));
+    /* The other side started its clock a bit later */
+    assert!(matches!(
+        wormhole2.receive().await,
+        Err(magic_wormhole::WormholeError::DeadlineExceeded)
+    ));
+    assert!(matches!(
+        wormhole2.send(b"too late".to_vec()).await,
+        Err(magic_wormhole::WormholeError::DeadlineExceeded)
+    ));
+
+    wormhole1.close_with_mood(Mood::Errory).await;
+    wormhole2.close_with_mood(Mood::Errory).await;
+    Ok(())
+}
+
+/** Running out of time while connecting releases the nameplate and closes the mailbox */
+#[async_std::test]
+pub async fn test_memory_deadline_cleanup() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .deadline(Duration::from_millis(300));
+    let (_welcome, connector) = server.connect_without_code(config, 2).await?;
+    assert!(!server.is_empty());
+    assert!(matches!(
+        connector.await,
+        Err(magic_wormhole::WormholeError::DeadlineExceeded)
+    ));
+    assert!(server.is_empty());
+
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .timeout(Duration::from_millis(300));
+    let (_welcome, connector) = server.connect_without_code(config, 2).await?;
+    assert!(matches!(
+        connector.await,
+        Err(magic_wormhole::WormholeError::Timeout)
+    ));
+    assert!(server.is_empty());
+
+    Ok(())
+}
+
+/** Both sides report their progress */
+#[async_std::test]
+pub async fn test_memory_events() -> eyre::Result<()> {
+    use magic_wormhole::WormholeEvent;
+    use std::sync::{Arc, Mutex};
+
+    init_logger();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .on_event(move |event| events2.lock().unwrap().push(event.clone()));
+    let (_wormhole1, _wormhole2) = memory_pair_with(config).await?;
+
+    let events = events.lock().unwrap();
+    let count = |expected: &WormholeEvent| events.iter().filter(|event| *event == expected).count();
+    assert!(matches!(events[0], WormholeEvent::NameplateClaimed { .. }));
+    /* Both sides claimed the same nameplate */
+    assert_eq!(count(&events[0]), 2);
+    assert_eq!(count(&WormholeEvent::MailboxOpened), 2);
+    assert_eq!(count(&WormholeEvent::PeerConnected), 2);
+    assert_eq!(count(&WormholeEvent::KeyEstablished), 2);
+    assert_eq!(events.last(), Some(&WormholeEvent::KeyEstablished));
+
+    Ok(())
+}
+
+/** Garbage in the mailbox does not count as the peer showing up */
+#[async_std::test]
+pub async fn test_memory_events_garbage_pake() -> eyre::Result<()> {
+    use magic_wormhole::WormholeEvent;
+    use std::sync::{Arc, Mutex};
+
+    init_logger();
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .on_event(move |event| events2.lock().unwrap().push(event.clone()));
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (welcome, connector) = server.connect_without_code(config, 2).await?;
+    let (mut other, _) = server.connect(&TEST_APPID).await?;
+    other.claim_open(welcome.code.nameplate()).await?;
+    other
+        .send_peer_message(Phase::PAKE, b"garbage".to_vec())
+        .await?;
+
+    assert!(connector.await.is_err());
+    assert!(!events
+        .lock()
+        .unwrap()
+        .contains(&WormholeEvent::PeerConnected));
+
+    Ok(())
+}
+
+/** Oversized messages from the server are rejected */
+#[async_std::test]
+pub async fn test_memory_max_message_size() -> eyre::Result<()> {
+    init_logger();
+
+    let config = transfer::APP_CONFIG.id(TEST_APPID).max_message_size(2048);
+    let (mut wormhole1, mut wormhole2) = memory_pair_with(config).await?;
+
+    wormhole1.send(vec![0; 256]).await?;
+    assert_eq!(wormhole2.receive().await?.len(), 256);
+    /* Hex encoded, this is way over the limit */
+    wormhole1.send(vec![0; 2048]).await?;
+    assert!(matches!(
+        wormhole2.receive().await,
+        Err(magic_wormhole::WormholeError::ServerError(
+            magic_wormhole::rendezvous::RendezvousError::Protocol(_)
+        ))
+    ));
+
+    Ok(())
+}
+
+/** Allocate a code, but nobody uses it */
+#[async_std::test]
+pub async fn test_memory_peer_timeout() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let config = transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .peer_timeout(Duration::from_millis(100));
+
+    let (_welcome, connector) = server.connect_without_code(config, 2).await?;
+    assert!(matches!(
+        connector.await,
+        Err(magic_wormhole::WormholeError::PeerNeverConnected)
+    ));
+
+    /* The nameplate must have been released */
+    let (mut other, _) = server.connect(&TEST_APPID).await?;
+    assert!(other.list_nameplates().await?.is_empty());
+
+    Ok(())
+}
+
+/** Like `test_wrong_code`, but without network */
+#[async_std::test]
+pub async fn test_memory_wrong_code() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (welcome, connector) = server
+        .connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2)
+        .await?;
+    let (result1, result2) = futures::join!(
+        connector,
+        server.connect_with_code(
+            transfer::APP_CONFIG.id(TEST_APPID),
+            Code::new(&welcome.code.nameplate(), "foo-bar"),
+        )
+    );
+
+    assert!(matches!(
+        result1,
+        Err(magic_wormhole::WormholeError::Security(
+            magic_wormhole::SecurityError::PakeFailed
+        ))
+    ));
+    assert!(matches!(
+        result2,
+        Err(magic_wormhole::WormholeError::Security(
+            magic_wormhole::SecurityError::PakeFailed
+        ))
+    ));
+    Ok(())
+}
+
+/** Listing nameplates must show the ones in use */
+#[async_std::test]
+pub async fn test_memory_list_nameplates() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (mut first, _) = server.connect(&TEST_APPID).await?;
+    let (mut second, _) = server.connect(&TEST_APPID).await?;
+    assert!(second.list_nameplates().await?.is_empty());
+
+    let (nameplate, _mailbox) = first.allocate_claim_open().await?;
+    assert_eq!(second.list_nameplates().await?, vec![nameplate]);
+
+    Ok(())
+}
+
+/** The nameplate goes back to the pool as soon as both sides are connected */
+#[async_std::test]
+pub async fn test_memory_early_nameplate_release() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (_wormhole1, _wormhole2) = server
+        .connect_pair(transfer::APP_CONFIG.id(TEST_APPID), 2)
+        .await?;
+    let (mut observer, _) = server.connect(&TEST_APPID).await?;
+    assert!(observer.list_nameplates().await?.is_empty());
+
+    Ok(())
+}
+
+/** Like `test_crowded`, but without network */
+#[async_std::test]
+pub async fn test_memory_crowded() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (welcome, _connector) = server
+        .connect_without_code(transfer::APP_CONFIG.id(TEST_APPID), 2)
+        .await?;
+    let (mut second, _) = server.connect(&TEST_APPID).await?;
+    second.claim_open(welcome.code.nameplate()).await?;
+    let (mut third, _) = server.connect(&TEST_APPID).await?;
+
+    match third
+        .claim_open(welcome.code.nameplate())
+        .await
+        .unwrap_err()
+    {
+        magic_wormhole::rendezvous::RendezvousError::Nameplate(
+            magic_wormhole::rendezvous::NameplateError::Crowded(nameplate),
+        ) => assert_eq!(nameplate, welcome.code.nameplate()),
+        other => panic!("Got wrong error message: {}, wanted 'crowded'", other),
+    }
+
+    Ok(())
+}
+
+/** Messages piling up while waiting for the server must not exceed the queue capacity */
+#[async_std::test]
+pub async fn test_memory_queue_full() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let (mut first, _) = server.connect(&TEST_APPID).await?;
+    let (nameplate, _mailbox) = first.allocate_claim_open().await?;
+    let (mut second, _) = server.connect(&TEST_APPID).await?;
+    second.claim_open(nameplate).await?;
+    second.set_queue_capacity(1);
+
+    first
+        .send_peer_message(Phase::PAKE, b"one".to_vec())
+        .await?;
+    first
+        .send_peer_message(Phase::VERSION, b"two".to_vec())
+        .await?;
+
+    match second
+        .send_peer_message(Phase::PAKE, b"three".to_vec())
+        .await
+        .unwrap_err()
+    {
+        magic_wormhole::rendezvous::RendezvousError::QueueFull(1) => (),
+        other => panic!("Got wrong error: {}, wanted QueueFull", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_phase() {
+    let p = Phase::PAKE;
+    assert!(p.is_pake());
+    assert!(!p.is_version());
+}
+
+#[test]
+fn test_mood() {
+    // The serialized forms of these variants are part of the wire protocol,
+    // so they must be spelled exactly as shown (they must match the strings
+    // used in the Python version in src/wormhole/_boss.py , in calls to
+    // self._T.close())
+    assert_eq!(
+        String::from(r#""happy""#),
+        serde_json::to_string(&Mood::Happy).unwrap()
+    );
+    assert_eq!(
+        String::from(r#""lonely""#),
+        serde_json::to_string(&Mood::Lonely).unwrap()
+    );
+    assert_eq!(
+        String::from(r#""errory""#),
+        serde_json::to_string(&Mood::Errory).unwrap()
+    );
+    assert_eq!(
+        String::from(r#""scary""#),
+        serde_json::to_string(&Mood::Scared).unwrap()
+    );
+    assert_eq!(
+        String::from(r#""unwelcome""#),
+        serde_json::to_string(&Mood::Unwelcome).unwrap()
+    );
+}
+
+/** With `check_nameplate`, a code nobody allocated fails right away instead of waiting forever */
+#[async_std::test]
+pub async fn test_memory_check_nameplate() -> eyre::Result<()> {
+    init_logger();
+
+    let server = magic_wormhole::testing::MailboxServer::new();
+    let config = transfer::APP_CONFIG.id(TEST_APPID).check_nameplate(true);
+
+    match server
+        .connect_with_code(
+            config.clone(),
+            Code::new(&magic_wormhole::Nameplate::new("42"), "purple-sausages"),
+        )
+        .await
+    {
+        Err(magic_wormhole::WormholeError::ServerError(
+            magic_wormhole::rendezvous::RendezvousError::Nameplate(
+                magic_wormhole::rendezvous::NameplateError::NotFound(nameplate),
+            ),
+        )) => assert_eq!(nameplate, magic_wormhole::Nameplate::new("42")),
+        Err(other) => panic!("Got wrong error: {}, wanted 'not found'", other),
+        Ok(_) => panic!("Connected to a nameplate that doesn't exist"),
+    }
+
+    let (welcome, connector) = server.connect_without_code(config.clone(), 2).await?;
+    let (first, (_welcome, second)) =
+        futures::try_join!(connector, server.connect_with_code(config, welcome.code))?;
+    assert_eq!(first.verifier, second.verifier);
+    first.close().await.into_result()?;
+    second.close().await.into_result()?;
+    Ok(())
+}
+
+#[test]
+fn test_relay_url_token() {
+    let relay: transit::RelayUrl = "tcp:relay.example:4001".parse().unwrap();
+    assert_eq!(relay.host, "relay.example");
+    assert_eq!(relay.port, 4001);
+    assert_eq!(relay.token, None);
+
+    let relay: transit::RelayUrl = "tcp:relay.example:4001?token=s3cr3t".parse().unwrap();
+    assert_eq!(relay.port, 4001);
+    assert_eq!(relay.token.as_deref(), Some("s3cr3t"));
+
+    assert!("tcp:relay.example:4001?token="
+        .parse::<transit::RelayUrl>()
+        .is_err());
+    assert!("tcp:relay.example:4001?tokn=s3cr3t"
+        .parse::<transit::RelayUrl>()
+        .is_err());
+}
+
+#[test]
+fn test_relay_url_websocket() {
+    let relay: transit::RelayUrl = "wss://relay.example/transit?token=s3cr3t".parse().unwrap();
+    assert_eq!(relay.host, "relay.example");
+    assert_eq!(relay.port, 443);
+    assert_eq!(relay.token.as_deref(), Some("s3cr3t"));
+    assert_eq!(
+        relay.websocket.as_deref(),
+        Some("wss://relay.example/transit")
+    );
+
+    let relay: transit::RelayUrl = "ws://relay.example:4002".parse().unwrap();
+    assert_eq!(relay.port, 4002);
+    assert_eq!(relay.websocket.as_deref(), Some("ws://relay.example:4002"));
+
+    let relay: transit::RelayUrl = "tcp:relay.example:4001".parse().unwrap();
+    assert_eq!(relay.websocket, None);
+
+    assert!("wss://".parse::<transit::RelayUrl>().is_err());
+    assert!("wss://relay.example:https"
+        .parse::<transit::RelayUrl>()
+        .is_err());
+
+    /* The URLs end up in their own hints. Older clients fail on those, so they are only sent to newer ones */
+    let hints: Vec<transit::Hint> = serde_json::from_str(
+        r#"[{"type": "relay-websocket-v1", "url": "wss://relay.example/transit"}, {"type": "direct-carrier-pigeon-v1"}]"#,
+    )
+    .unwrap();
+    let hints = transit::Hints::from(hints);
+    assert!(hints
+        .relay_websocket
+        .contains("wss://relay.example/transit"));
+    assert!(hints.relay.is_empty());
+}
+
+/** WebSocket relay hints are only sent to clients announcing that they know them */
+#[async_std::test]
+pub async fn test_relay_websocket_hints() -> eyre::Result<()> {
+    init_logger();
+
+    let relay_urls = vec!["wss://relay.example/transit".parse().unwrap()];
+    let connector = transit::init(vec![transit::Ability::RelayWebsocketV1], &relay_urls).await?;
+    let websocket_hints = |hints: Vec<transit::Hint>| {
+        hints
+            .iter()
+            .filter(|hint| matches!(hint, transit::Hint::RelayWebsocketV1 { .. }))
+            .count()
+    };
+
+    assert_eq!(websocket_hints(connector.our_hints_for(None)), 0);
+    assert_eq!(
+        websocket_hints(connector.our_hints_for(Some(&[transit::Ability::RelayV1]))),
+        0
+    );
+    assert_eq!(
+        websocket_hints(connector.our_hints_for(Some(&[
+            transit::Ability::RelayV1,
+            transit::Ability::RelayWebsocketV1
+        ]))),
+        1
+    );
+
+    Ok(())
 }
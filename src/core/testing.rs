@@ -0,0 +1,378 @@
+//! Test your Wormhole applications without a network
+//!
+//! This provides an in-memory rendezvous server that speaks just enough of the mailbox protocol to connect
+//! clients to each other. All messages are processed synchronously as they are sent, no background tasks are involved,
+//! which keeps the message ordering deterministic.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), magic_wormhole::WormholeError> {
+//! use magic_wormhole::{testing::MailboxServer, transfer};
+//!
+//! let server = MailboxServer::new();
+//! let (mut sender, mut receiver) = server.connect_pair(transfer::APP_CONFIG, 2).await?;
+//! sender.send(b"hello".to_vec()).await?;
+//! assert_eq!(receiver.receive().await?, b"hello");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Note that this only covers the rendezvous part. Transit connections will still use real sockets.
+
+use async_tungstenite::tungstenite as ws2;
+use futures::{channel::mpsc, prelude::*};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use super::{rendezvous::*, AppConfig, Code, MySide, Wormhole, WormholeError, WormholeWelcome};
+
+#[derive(Default)]
+struct NameplateState {
+    mailbox: String,
+    sides: HashSet<String>,
+}
+
+#[derive(Default)]
+struct MailboxState {
+    sides: HashSet<String>,
+    messages: Vec<Value>,
+    /* Connection ids that opened this mailbox */
+    listeners: HashSet<usize>,
+}
+
+#[derive(Default)]
+struct ServerState {
+    next_connection: usize,
+    next_mailbox: usize,
+    connections: HashMap<usize, mpsc::UnboundedSender<ws2::Message>>,
+    nameplates: HashMap<String, NameplateState>,
+    mailboxes: HashMap<String, MailboxState>,
+    /* Only set if the sides of the clients should be reproducible */
+    sides: Option<rand::rngs::StdRng>,
+}
+
+impl ServerState {
+    fn send_to(&self, connection: usize, message: Value) {
+        if let Some(sender) = self.connections.get(&connection) {
+            /* The client might have hung up already, which is fine */
+            let _ = sender.unbounded_send(ws2::Message::Text(message.to_string()));
+        }
+    }
+
+    fn claim(&mut self, nameplate: &str, side: &str) -> Result<String, &'static str> {
+        let next_mailbox = &mut self.next_mailbox;
+        let entry = self
+            .nameplates
+            .entry(nameplate.to_owned())
+            .or_insert_with(|| {
+                *next_mailbox += 1;
+                NameplateState {
+                    mailbox: format!("mailbox{}", next_mailbox),
+                    sides: Default::default(),
+                }
+            });
+        entry.sides.insert(side.to_owned());
+        if entry.sides.len() > 2 {
+            return Err("crowded");
+        }
+        Ok(entry.mailbox.clone())
+    }
+
+    fn handle(&mut self, connection: usize, side: &mut Option<String>, message: &Value) {
+        let orig = message.clone();
+        let error = |state: &Self, error: &str| {
+            state.send_to(
+                connection,
+                json!({"type": "error", "error": error, "orig": orig}),
+            );
+        };
+
+        self.send_to(connection, json!({"type": "ack", "id": message.get("id")}));
+
+        let msg_type = message.get("type").and_then(Value::as_str).unwrap_or("");
+        let field = |name: &str| message.get(name).and_then(Value::as_str).unwrap_or("");
+
+        if msg_type == "bind" {
+            *side = Some(field("side").to_owned());
+            return;
+        }
+        if msg_type == "ping" {
+            self.send_to(
+                connection,
+                json!({"type": "pong", "pong": message.get("ping")}),
+            );
+            return;
+        }
+        let side = match side {
+            Some(side) => side.clone(),
+            None => return error(self, "must bind first"),
+        };
+
+        match msg_type {
+            "list" => {
+                let nameplates: Vec<Value> = self
+                    .nameplates
+                    .keys()
+                    .map(|id| json!({ "id": id }))
+                    .collect();
+                self.send_to(
+                    connection,
+                    json!({"type": "nameplates", "nameplates": nameplates}),
+                );
+            },
+            "allocate" => {
+                let nameplate = (1..)
+                    .map(|id: u64| id.to_string())
+                    .find(|id| !self.nameplates.contains_key(id))
+                    .unwrap();
+                match self.claim(&nameplate, &side) {
+                    Ok(_) => self.send_to(
+                        connection,
+                        json!({"type": "allocated", "nameplate": nameplate}),
+                    ),
+                    Err(err) => error(self, err),
+                }
+            },
+            "claim" => match self.claim(field("nameplate"), &side) {
+                Ok(mailbox) => {
+                    self.send_to(connection, json!({"type": "claimed", "mailbox": mailbox}))
+                },
+                Err(err) => error(self, err),
+            },
+            "release" => {
+                let nameplate = field("nameplate");
+                if let Some(state) = self.nameplates.get_mut(nameplate) {
+                    state.sides.remove(&side);
+                    if state.sides.is_empty() {
+                        self.nameplates.remove(nameplate);
+                    }
+                }
+                self.send_to(connection, json!({"type": "released"}));
+            },
+            "open" => {
+                let mailbox = self
+                    .mailboxes
+                    .entry(field("mailbox").to_owned())
+                    .or_default();
+                mailbox.sides.insert(side);
+                if mailbox.sides.len() > 2 {
+                    return error(self, "crowded");
+                }
+                mailbox.listeners.insert(connection);
+                let messages = mailbox.messages.clone();
+                for message in messages {
+                    self.send_to(connection, message);
+                }
+            },
+            "add" => {
+                let mailbox = match self
+                    .mailboxes
+                    .values_mut()
+                    .find(|mailbox| mailbox.listeners.contains(&connection))
+                {
+                    Some(mailbox) => mailbox,
+                    None => return error(self, "must open mailbox first"),
+                };
+                let message = json!({
+                    "type": "message",
+                    "side": side,
+                    "phase": message.get("phase"),
+                    "body": message.get("body"),
+                    "id": message.get("id"),
+                });
+                mailbox.messages.push(message.clone());
+                let listeners = mailbox.listeners.clone();
+                for listener in listeners {
+                    self.send_to(listener, message.clone());
+                }
+            },
+            "close" => {
+                let name = field("mailbox");
+                if let Some(mailbox) = self.mailboxes.get_mut(name) {
+                    mailbox.listeners.remove(&connection);
+                    mailbox.sides.remove(&side);
+                    if mailbox.listeners.is_empty() {
+                        self.mailboxes.remove(name);
+                    }
+                }
+                self.send_to(connection, json!({"type": "closed"}));
+            },
+            other => error(self, &format!("unknown type '{}'", other)),
+        }
+    }
+}
+
+/**
+ * An in-memory rendezvous server
+ *
+ * Cloning it gives you another handle to the same server.
+ */
+#[derive(Clone, Default)]
+pub struct MailboxServer {
+    state: Arc<Mutex<ServerState>>,
+}
+
+impl std::fmt::Debug for MailboxServer {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("MailboxServer").finish()
+    }
+}
+
+impl MailboxServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /**
+     * Like [`new`](Self::new), but the sides of the connecting clients are derived from `seed`
+     *
+     * Together with a fixed code, this makes the messages on the server reproducible from run to run.
+     * See also [`MySide::generate_with`](crate::MySide::generate_with).
+     */
+    pub fn with_seed(seed: u64) -> Self {
+        use rand::SeedableRng;
+
+        let server = Self::new();
+        server.state.lock().unwrap().sides = Some(rand::rngs::StdRng::seed_from_u64(seed));
+        server
+    }
+
+    /** Whether all nameplates have been released and all mailboxes closed, i.e. the clients cleaned up after themselves */
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.nameplates.is_empty() && state.mailboxes.is_empty()
+    }
+
+    fn open_connection(&self) -> MemoryConnection {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_connection;
+        state.next_connection += 1;
+        state.connections.insert(id, sender);
+        state.send_to(id, json!({"type": "welcome", "welcome": {}}));
+        MemoryConnection {
+            server: self.state.clone(),
+            id,
+            side: None,
+            incoming: receiver,
+        }
+    }
+
+    /** Like [`RendezvousServer::connect`], but in memory. */
+    pub async fn connect(
+        &self,
+        appid: &crate::AppID,
+    ) -> Result<(RendezvousServer, Option<String>), RendezvousError> {
+        let side = match &mut self.state.lock().unwrap().sides {
+            Some(rng) => MySide::generate_with(rng),
+            None => MySide::generate(),
+        };
+        RendezvousServer::connect_with_transport_as(appid, Box::new(self.open_connection()), side)
+            .await
+    }
+
+    /** Like [`Wormhole::connect_without_code`], but in memory. The `rendezvous_url` is ignored. */
+    pub async fn connect_without_code(
+        &self,
+        config: AppConfig<impl serde::Serialize>,
+        code_length: usize,
+    ) -> Result<
+        (
+            WormholeWelcome,
+            impl std::future::Future<Output = Result<Wormhole, WormholeError>>,
+        ),
+        WormholeError,
+    > {
+        let (server, welcome) = self.connect(&config.id).await?;
+        Wormhole::connect_without_code_via(server, welcome, config, code_length).await
+    }
+
+    /** Like [`Wormhole::connect_with_code`], but in memory. The `rendezvous_url` is ignored. */
+    pub async fn connect_with_code(
+        &self,
+        config: AppConfig<impl serde::Serialize>,
+        code: Code,
+    ) -> Result<(WormholeWelcome, Wormhole), WormholeError> {
+        let (server, welcome) = self.connect(&config.id).await?;
+        Wormhole::connect_with_code_via(server, welcome, config, code).await
+    }
+
+    /**
+     * Connect two wormholes to each other
+     *
+     * The first one allocates a code, the second one uses it. Both are returned in that order.
+     */
+    pub async fn connect_pair<V: serde::Serialize + Clone>(
+        &self,
+        config: AppConfig<V>,
+        code_length: usize,
+    ) -> Result<(Wormhole, Wormhole), WormholeError> {
+        let (welcome, connector) = self
+            .connect_without_code(config.clone(), code_length)
+            .await?;
+        let (first, (_welcome, second)) =
+            futures::try_join!(connector, self.connect_with_code(config, welcome.code))?;
+        Ok((first, second))
+    }
+}
+
+/** One client connection to a [`MailboxServer`] */
+struct MemoryConnection {
+    server: Arc<Mutex<ServerState>>,
+    id: usize,
+    side: Option<String>,
+    incoming: mpsc::UnboundedReceiver<ws2::Message>,
+}
+
+impl Stream for MemoryConnection {
+    type Item = Result<ws2::Message, ws2::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming
+            .poll_next_unpin(cx)
+            .map(|message| message.map(Ok))
+    }
+}
+
+impl Sink<ws2::Message> for MemoryConnection {
+    type Error = ws2::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: ws2::Message) -> Result<(), Self::Error> {
+        let this = &mut *self;
+        let mut state = this.server.lock().unwrap();
+        let message: Value = match item {
+            /* Garbage will be answered with an error message */
+            ws2::Message::Text(text) => serde_json::from_str(&text).unwrap_or(Value::Null),
+            ws2::Message::Ping(payload) => {
+                if let Some(sender) = state.connections.get(&this.id) {
+                    let _ = sender.unbounded_send(ws2::Message::Pong(payload));
+                }
+                return Ok(());
+            },
+            _ => return Ok(()),
+        };
+        state.handle(this.id, &mut this.side, &message);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut state = self.server.lock().unwrap();
+        state.connections.remove(&self.id);
+        for mailbox in state.mailboxes.values_mut() {
+            mailbox.listeners.remove(&self.id);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,7 +1,12 @@
+//! The words used to make up the codes
+//!
+//! This is the PGP wordlist, like the Python implementation uses.
+
 use rand::{rngs::OsRng, seq::SliceRandom};
 use serde_json::{self, Value};
 use std::fmt;
 
+/** A list of words to make codes from, alternating between even and odd words like in PGP */
 #[derive(PartialEq)]
 pub struct Wordlist {
     pub num_words: usize,
@@ -20,7 +25,11 @@ impl Wordlist {
         Wordlist { num_words, words }
     }
 
-    #[allow(dead_code)] // TODO make this API public one day
+    /**
+     * All the ways to complete a partially typed code (without the nameplate)
+     *
+     * Every completion but the last word will end with a dash, so that the user can continue typing.
+     */
     pub fn get_completions(&self, prefix: &str) -> Vec<String> {
         let count_dashes = prefix.matches('-').count();
         let mut completions = Vec::new();
@@ -55,6 +64,14 @@ impl Wordlist {
         completions
     }
 
+    /** Whether `word` may appear at position `index` (counting from zero) of a code's password */
+    pub fn contains_word(&self, index: usize, word: &str) -> bool {
+        self.words[index % self.words.len()]
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(word))
+    }
+
+    /** Make up a random code (without the nameplate) */
     pub fn choose_words(&self) -> String {
         let mut rng = OsRng;
         let components: Vec<String>;
@@ -97,6 +114,7 @@ fn load_pgpwords() -> Vec<Vec<String>> {
     vec![even_words, odd_words]
 }
 
+/** The wordlist for codes with `num_words` words */
 pub fn default_wordlist(num_words: usize) -> Wordlist {
     Wordlist {
         num_words,
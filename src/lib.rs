@@ -23,10 +23,18 @@
 #[macro_use]
 mod util;
 mod core;
+pub mod metrics;
+pub mod transcript;
+#[cfg(feature = "transfer")]
 pub mod transfer;
+#[cfg(feature = "transit")]
 pub mod transit;
 
+#[cfg(any(test, feature = "testing"))]
+pub use crate::core::testing;
 pub use crate::core::{
     key::{GenericKey, Key, KeyPurpose, WormholeKey},
-    rendezvous, AppConfig, AppID, Code, Wormhole, WormholeError, WormholeWelcome,
+    rendezvous, wordlist, AppConfig, AppID, Code, DebugState, EventHandler, KeyError, Mood, MySide,
+    Nameplate, ParseCodeError, Phase, SecurityError, Timings, Wormhole, WormholeError,
+    WormholeEvent, WormholeReceiver, WormholeSender, WormholeWelcome,
 };
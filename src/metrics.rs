@@ -0,0 +1,89 @@
+//! Hooks for observing what the Wormhole is doing
+//!
+//! If you embed this crate into a service, you probably want some numbers on how well things are going.
+//! Implement [`Metrics`] and install it once at startup using [`set_metrics`]. All methods have empty default
+//! implementations, so you only need to override what you are interested in.
+//!
+//! This works like the [`log`] crate: there is exactly one global instance, and it can only be set once.
+//! Without one, nothing gets reported at all.
+
+use once_cell::sync::OnceCell;
+
+/// Which side of a handshake failed
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum HandshakeKind {
+    /// The PAKE key exchange with the peer (usually a wrong code)
+    Pake,
+    /// A transit connection attempt (direct or relayed)
+    Transit,
+}
+
+/// The way an established transit connection goes
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum TransitPath {
+    /// We are directly connected to the peer
+    Direct,
+    /// The connection goes through a relay server
+    Relay,
+}
+
+/**
+ * Receiver of runtime counters
+ *
+ * The methods are called synchronously from within the protocol code,
+ * so implementations should be cheap (increment an atomic, send into a channel, …).
+ */
+pub trait Metrics: Send + Sync {
+    /// We are trying to (re)connect to the rendezvous server
+    fn rendezvous_connect_attempt(&self) {}
+
+    /// A message was sent to the peer via the rendezvous server
+    fn peer_message_sent(&self) {}
+
+    /// A message from the peer was received via the rendezvous server
+    fn peer_message_received(&self) {}
+
+    /// Some payload bytes (unencrypted size) have been sent over a transit connection
+    fn transit_bytes_sent(&self, _bytes: u64) {}
+
+    /// Some payload bytes (unencrypted size) have been received over a transit connection
+    fn transit_bytes_received(&self, _bytes: u64) {}
+
+    /// A handshake failed
+    fn handshake_failed(&self, _kind: HandshakeKind) {}
+
+    /// A transit connection has been established, and this is how it goes
+    fn transit_established(&self, _path: TransitPath) {}
+}
+
+struct NopMetrics;
+
+impl Metrics for NopMetrics {}
+
+static METRICS: OnceCell<Box<dyn Metrics>> = OnceCell::new();
+
+/// Returned by [`set_metrics`] if a [`Metrics`] instance has already been installed.
+#[derive(Debug, thiserror::Error)]
+#[error("A metrics instance has already been set")]
+pub struct SetMetricsError(());
+
+/**
+ * Install the global [`Metrics`] instance
+ *
+ * This can only be done once, subsequent calls will fail.
+ */
+pub fn set_metrics(metrics: impl Metrics + 'static) -> Result<(), SetMetricsError> {
+    METRICS
+        .set(Box::new(metrics))
+        .map_err(|_| SetMetricsError(()))
+}
+
+/** The currently installed [`Metrics`], or a no-op one. */
+pub(crate) fn metrics() -> &'static dyn Metrics {
+    match METRICS.get() {
+        Some(metrics) => &**metrics,
+        None => &NopMetrics,
+    }
+}
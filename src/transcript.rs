@@ -0,0 +1,94 @@
+//! Record what was said on the wire, for bug reports
+//!
+//! Call [`start`] before connecting, and [`stop`] once you are done (or something went wrong). In between, every
+//! message exchanged with the rendezvous server and every transit handshake line gets recorded. Secrets (everything
+//! that looks like a key-derived token) are redacted before they are stored, so the result can be shared safely.
+//! This includes the encrypted message bodies, which would be of no use in a bug report anyways.
+//!
+//! Like [`metrics`](crate::metrics), there is only one global recorder. Concurrent sessions will end up in the same
+//! transcript.
+
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::{sync::Mutex, time::Instant};
+
+/// Where a line was exchanged
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Channel {
+    /// With the rendezvous server, over the WebSocket connection
+    Rendezvous,
+    /// With the peer or the relay server, during a transit handshake
+    Transit,
+}
+
+/// Whether we sent or received a line
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single recorded line
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    /// Seconds since the recording started
+    pub time: f64,
+    pub channel: Channel,
+    pub direction: Direction,
+    /// The message, with secrets redacted
+    pub message: String,
+}
+
+/** A recorded protocol transcript. Serialize it to get the structured form. */
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Transcript {
+    pub entries: Vec<Entry>,
+}
+
+struct Recording {
+    started: Instant,
+    transcript: Transcript,
+}
+
+static RECORDING: Lazy<Mutex<Option<Recording>>> = Lazy::new(|| Mutex::new(None));
+
+/** Start recording a new transcript. A running recording is discarded. */
+pub fn start() {
+    *RECORDING.lock().unwrap() = Some(Recording {
+        started: Instant::now(),
+        transcript: Transcript::default(),
+    });
+}
+
+/** Stop recording, and return what has been recorded if [`start`] was called before */
+pub fn stop() -> Option<Transcript> {
+    RECORDING
+        .lock()
+        .unwrap()
+        .take()
+        .map(|recording| recording.transcript)
+}
+
+/** Record a line, if we're recording at all */
+pub(crate) fn record(channel: Channel, direction: Direction, message: &str) {
+    if let Some(recording) = RECORDING.lock().unwrap().as_mut() {
+        recording.transcript.entries.push(Entry {
+            time: recording.started.elapsed().as_secs_f64(),
+            channel,
+            direction,
+            message: redact(message),
+        });
+    }
+}
+
+/** Replace anything that looks like a hex encoded key (32 bytes or more) */
+fn redact(message: &str) -> String {
+    static SECRETS: Lazy<regex::Regex> =
+        Lazy::new(|| regex::Regex::new("[0-9a-fA-F]{64,}").unwrap());
+    SECRETS
+        .replace_all(message.trim_end(), "[redacted]")
+        .into_owned()
+}
@@ -8,16 +8,16 @@
 //! At its core, "peer messages" are exchanged over an established wormhole connection with the other side.
 //! They are used to set up a [transit] portal and to exchange a file offer/accept. Then, the file is transmitted over the transit relay.
 
-use futures::{AsyncRead, AsyncWrite};
+use futures::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite};
 use serde_derive::{Deserialize, Serialize};
 #[cfg(test)]
 use serde_json::json;
-use std::sync::Arc;
+use std::{borrow::Cow, convert::TryFrom, ops::Range, sync::Arc};
 
 use super::{
-    core::WormholeError,
+    core::{with_deadline, WormholeError},
     transit,
-    transit::{RelayUrl, Transit},
+    transit::{RecordCipher, RelayUrl, StripedTransit, Transit},
     AppID, Wormhole,
 };
 use async_std::io::{prelude::WriteExt, ReadExt};
@@ -26,22 +26,93 @@ use sha2::{digest::FixedOutput, Digest, Sha256};
 use std::path::PathBuf;
 use transit::{TransitConnectError, TransitConnector, TransitError};
 
+mod many;
 mod messages;
+mod uri;
+pub use many::{Recipient, RecipientResult, SendToMany};
+pub use messages::OfferMetadata;
 use messages::*;
-
-const APPID_RAW: &str = "lothar.com/wormhole/text-or-file-xfer";
+pub use uri::{ParseUriError, WormholeTransferUri, URI_SCHEME};
 
 /// The App ID associated with this protocol.
-pub const APPID: AppID = AppID(std::borrow::Cow::Borrowed(APPID_RAW));
+///
+/// This is the one of the official file transfer, as used by all magic-wormhole clients.
+pub const APPID: AppID = AppID::from_static("lothar.com/wormhole/text-or-file-xfer");
+
+/**
+ * Protocol extension: skip the holes of sparse files
+ *
+ * If both sides support it, [`send_sparse_file`] only transfers the regions of a file that actually
+ * contain data. The receiver fills in the zeros.
+ */
+pub const EXTENSION_SPARSE: &str = "sparse-v1";
+
+/**
+ * Protocol extension: encrypt the transit records with ChaCha20-Poly1305
+ *
+ * If both sides support it, the records are encrypted with [`RecordCipher::ChaCha20Poly1305`](transit::RecordCipher)
+ * instead of the default XSalsa20-Poly1305.
+ */
+pub const EXTENSION_CHACHA20POLY1305: &str = "chacha20poly1305-v1";
+
+/**
+ * Protocol extension: skip files the receiver already has
+ *
+ * If both sides support it, [`send_file_unless_identical`] includes the file's hash in the offer. A receiver
+ * that already has an identical file may then answer with [`ReceiveRequest::skip_identical`] instead of
+ * accepting it, and nothing gets transferred.
+ */
+pub const EXTENSION_SKIP_IDENTICAL: &str = "skip-identical-v1";
+
+/**
+ * Protocol extension: stripe the file over several transit connections
+ *
+ * A single TCP connection often cannot fill links with a lot of bandwidth and a high latency. If both
+ * sides support this, files of at least [`MULTI_STREAM_MIN_SIZE`] are sent over [`TRANSIT_STREAMS`]
+ * connections at once, see [`StripedTransit`]. The additional connections are set up concurrently after
+ * the first one. If some of them fail, the transfer goes on over the ones that worked. This puts more load
+ * on the relay servers, remove it from the [`AppVersion`] to always use a single connection.
+ */
+pub const EXTENSION_MULTI_STREAM: &str = "multi-stream-v1";
+
+/** The number of transit connections for [`EXTENSION_MULTI_STREAM`] */
+pub const TRANSIT_STREAMS: u32 = 4;
+
+/** Smaller files are sent over a single connection, even with [`EXTENSION_MULTI_STREAM`] */
+pub const MULTI_STREAM_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+/* Don't let the sender make us open arbitrarily many connections */
+const MAX_TRANSIT_STREAMS: u32 = 16;
+
+/* The file ack of a receiver that already has the offered file */
+const FILE_ACK_IDENTICAL: &str = "identical";
 
 /// An [`crate::AppConfig`] with sane defaults for this protocol.
 ///
 /// You **must not** change `id` and `rendezvous_url` to be interoperable.
 /// The `app_version` can be adjusted if you want to disable some features.
 pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersion> {
-    id: AppID(std::borrow::Cow::Borrowed(APPID_RAW)),
+    id: APPID,
     rendezvous_url: std::borrow::Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
-    app_version: AppVersion {},
+    app_version: AppVersion {
+        extensions: Cow::Borrowed(&[
+            Cow::Borrowed(EXTENSION_SPARSE),
+            Cow::Borrowed(EXTENSION_CHACHA20POLY1305),
+            Cow::Borrowed(EXTENSION_SKIP_IDENTICAL),
+            Cow::Borrowed(EXTENSION_MULTI_STREAM),
+        ]),
+    },
+    queue_capacity: crate::rendezvous::DEFAULT_QUEUE_CAPACITY,
+    keepalive_interval: Some(crate::rendezvous::DEFAULT_KEEPALIVE_INTERVAL),
+    max_message_size: crate::rendezvous::DEFAULT_MAX_MESSAGE_SIZE,
+    timeout: None,
+    answer_timeout: None,
+    peer_timeout: None,
+    deadline: None,
+    on_event: None,
+    check_nameplate: false,
+    rendezvous_pins: Cow::Borrowed(&[]),
+    rendezvous_headers: Cow::Borrowed(&[]),
 };
 
 // TODO be more extensible on the JSON enum types (i.e. recognize unknown variants)
@@ -61,8 +132,15 @@ pub enum TransferError {
     // TODO be more specific
     #[error("Unsupported offer type")]
     UnsupportedOffer,
+    /// The other side declined our offer, or failed before accepting it
+    #[error("The other side rejected the offer: {}", reason)]
+    OfferRejected { reason: String },
     #[error("Something went wrong on the other side: {}", _0)]
     PeerError(String),
+    /// The other side did not answer in time. See [`AppConfig::timeout`](crate::AppConfig::timeout) and
+    /// [`AppConfig::answer_timeout`](crate::AppConfig::answer_timeout)
+    #[error("Timed out waiting for the other side")]
+    Timeout,
 
     /// Some deserialization went wrong, we probably got some garbage
     #[error("Corrupt message received")]
@@ -129,16 +207,74 @@ impl TransferError {
 /**
  * The application specific version information for this protocol.
  *
- * At the moment, this always is an empty object, but this will likely change in the future.
+ * Both sides announce the protocol extensions they support here. An extension is only used if both
+ * sides announce it, see [`AppVersion::negotiate`]. Otherwise, the classic transfer protocol is used.
+ * Unknown fields and extensions are ignored, so other clients are free to announce whatever they like.
  */
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
-pub struct AppVersion {}
+pub struct AppVersion {
+    /** The names of the supported protocol extensions */
+    #[serde(default, skip_serializing_if = "is_empty")]
+    pub extensions: Cow<'static, [Cow<'static, str>]>,
+}
+
+fn is_empty(extensions: &[Cow<'static, str>]) -> bool {
+    extensions.is_empty()
+}
+
+impl AppVersion {
+    /** Whether `extension` is announced */
+    pub fn supports(&self, extension: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|supported| supported == extension)
+    }
+
+    /**
+     * The extensions that both sides of the `wormhole` announced
+     *
+     * Version information that cannot be parsed counts as supporting no extensions at all.
+     */
+    pub fn negotiate(wormhole: &Wormhole) -> Vec<Cow<'static, str>> {
+        let parse = |version: &serde_json::Value| {
+            serde_json::from_value::<AppVersion>(version.clone()).unwrap_or_else(|err| {
+                debug!("Failed to parse app version {}, {}", version, err);
+                AppVersion::default()
+            })
+        };
+        let ours = parse(&wormhole.our_version);
+        let theirs = parse(&wormhole.peer_version);
+        ours.extensions
+            .iter()
+            .filter(|extension| theirs.supports(extension))
+            .cloned()
+            .collect()
+    }
+
+    /** The transit record cipher both sides of the `wormhole` support */
+    pub fn negotiate_record_cipher(wormhole: &Wormhole) -> RecordCipher {
+        if AppVersion::negotiate(wormhole)
+            .iter()
+            .any(|extension| extension == EXTENSION_CHACHA20POLY1305)
+        {
+            RecordCipher::ChaCha20Poly1305
+        } else {
+            RecordCipher::XSalsa20Poly1305
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct TransitAck {
     pub ack: String,
     pub sha256: String,
+    /** Not part of the protocol, other clients neither send it nor look at it. See [`TransferStats`] */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes_received: Option<u64>,
+    /** Not part of the protocol, see [`TransferStats`] */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
 }
 
 impl TransitAck {
@@ -146,9 +282,26 @@ impl TransitAck {
         TransitAck {
             ack: msg.into(),
             sha256: sha256.into(),
+            bytes_received: None,
+            elapsed_ms: None,
         }
     }
 
+    /** Tell the other side how the transfer went for us */
+    pub fn with_stats(mut self, stats: TransferStats) -> Self {
+        self.bytes_received = Some(stats.bytes_received);
+        self.elapsed_ms = Some(u64::try_from(stats.elapsed.as_millis()).unwrap_or(u64::MAX));
+        self
+    }
+
+    /** The statistics the other side sent along, if it sent any */
+    pub fn stats(&self) -> Option<TransferStats> {
+        Some(TransferStats::new(
+            self.bytes_received?,
+            std::time::Duration::from_millis(self.elapsed_ms?),
+        ))
+    }
+
     #[cfg(test)]
     pub fn serialize(&self) -> String {
         json!(self).to_string()
@@ -159,13 +312,49 @@ impl TransitAck {
     }
 }
 
+/**
+ * How a file transfer went for the receiving side
+ *
+ * The receiver sends this along with its acknowledgement, so that both sides can report the same numbers.
+ * The send functions return it, but only if the other side is recent enough to send it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TransferStats {
+    /** The number of payload bytes received, which includes skipped holes of sparse files */
+    pub bytes_received: u64,
+    /** How long it took from the first to the last received byte */
+    pub elapsed: std::time::Duration,
+    /** The other side already had an identical file, so nothing was sent. See [`EXTENSION_SKIP_IDENTICAL`] */
+    pub already_present: bool,
+}
+
+impl TransferStats {
+    pub fn new(bytes_received: u64, elapsed: std::time::Duration) -> Self {
+        TransferStats {
+            bytes_received,
+            elapsed,
+            already_present: false,
+        }
+    }
+
+    /** Nothing was transferred, since the other side already had the file */
+    pub fn already_present() -> Self {
+        TransferStats {
+            bytes_received: 0,
+            elapsed: std::time::Duration::from_secs(0),
+            already_present: true,
+        }
+    }
+}
+
 pub async fn send_file_or_folder<N, M, H>(
     wormhole: &mut Wormhole,
-    relay_url: &RelayUrl,
+    relay_urls: &[RelayUrl],
     file_path: N,
     file_name: M,
     progress_handler: H,
-) -> Result<(), TransferError>
+) -> Result<Option<TransferStats>, TransferError>
 where
     N: AsRef<async_std::path::Path>,
     M: AsRef<async_std::path::Path>,
@@ -178,20 +367,67 @@ where
     let mut file = File::open(file_path).await?;
     let metadata = file.metadata().await?;
     if metadata.is_dir() {
-        send_folder(wormhole, relay_url, file_path, file_name, progress_handler).await?;
+        send_folder(wormhole, relay_urls, file_path, file_name, progress_handler).await
     } else {
         let file_size = metadata.len();
         send_file(
             wormhole,
-            relay_url,
+            relay_urls,
             &mut file,
             file_name,
             file_size,
             progress_handler,
         )
-        .await?;
+        .await
     }
-    Ok(())
+}
+
+/**
+ * Report the progress of a transfer on an [`indicatif::ProgressBar`]
+ *
+ * Pass the result as `progress_handler` to any of the transfer functions. The bar's length is set once the
+ * transfer starts, and it will be finished once all bytes have been transferred.
+ */
+#[cfg(feature = "indicatif")]
+pub fn indicatif_progress(pb: indicatif::ProgressBar) -> impl FnMut(u64, u64) + 'static {
+    move |done, total| {
+        if done == 0 {
+            pb.reset_elapsed();
+            pb.set_length(total);
+            pb.enable_steady_tick(250);
+        }
+        pb.set_position(done);
+        if done == total {
+            pb.finish();
+        }
+    }
+}
+
+/// Send some data to the other side
+///
+/// It will be offered as a file named `file_name`, but nothing is read from the file system.
+/// The receiver may use [`ReceiveRequest::accept_to_vec`] to get it without touching the disk either.
+pub async fn send_data<N, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    data: &[u8],
+    file_name: N,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    let mut reader = data;
+    send_file(
+        wormhole,
+        relay_urls,
+        &mut reader,
+        file_name,
+        data.len() as u64,
+        progress_handler,
+    )
+    .await
 }
 
 /// Send a file to the other side
@@ -200,18 +436,280 @@ where
 /// as advertized in file_size.
 pub async fn send_file<F, N, H>(
     wormhole: &mut Wormhole,
-    relay_url: &RelayUrl,
+    relay_urls: &[RelayUrl],
     file: &mut F,
     file_name: N,
     file_size: u64,
     progress_handler: H,
-) -> Result<(), TransferError>
+) -> Result<Option<TransferStats>, TransferError>
+where
+    F: AsyncRead + Unpin,
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    send_file_with_metadata(
+        wormhole,
+        relay_urls,
+        file,
+        file_name,
+        file_size,
+        OfferMetadata::default(),
+        progress_handler,
+    )
+    .await
+}
+
+/// Like [`send_file`], but also tell the other side some [`OfferMetadata`] about the file
+pub async fn send_file_with_metadata<F, N, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    file: &mut F,
+    file_name: N,
+    file_size: u64,
+    metadata: OfferMetadata,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
 where
     F: AsyncRead + Unpin,
     N: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    let connector = transit::init(transit::Ability::all_abilities(), relay_url).await?;
+    with_deadline(
+        wormhole.deadline(),
+        Box::pin(send_offered_file(
+            wormhole,
+            relay_urls,
+            file,
+            file_size,
+            PeerMessage::new_offer_file(file_name, file_size, metadata),
+            progress_handler,
+        )),
+    )
+    .await
+}
+
+/**
+ * Like [`send_file_with_metadata`], but skip the transfer if the other side already has an identical file
+ *
+ * If the other side supports [`EXTENSION_SKIP_IDENTICAL`], the file is hashed before offering it. This
+ * reads it twice, so it pays off for repeated transfers of mostly unchanged files. The returned
+ * [`TransferStats`] tell whether the file was [`already_present`](TransferStats::already_present).
+ */
+pub async fn send_file_unless_identical<F, N, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    file: &mut F,
+    file_name: N,
+    file_size: u64,
+    metadata: OfferMetadata,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    F: AsyncRead + AsyncSeek + Unpin,
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    let offer = if AppVersion::negotiate(wormhole)
+        .iter()
+        .any(|extension| extension == EXTENSION_SKIP_IDENTICAL)
+    {
+        let sha256 = hash_file(file).await?;
+        file.seek(std::io::SeekFrom::Start(0)).await?;
+        PeerMessage::new_offer_file_with_sha256(file_name, file_size, metadata, sha256)
+    } else {
+        PeerMessage::new_offer_file(file_name, file_size, metadata)
+    };
+
+    with_deadline(
+        wormhole.deadline(),
+        Box::pin(send_offered_file(
+            wormhole,
+            relay_urls,
+            file,
+            file_size,
+            offer,
+            progress_handler,
+        )),
+    )
+    .await
+}
+
+/* The hex encoded SHA-256 of everything `file` yields */
+async fn hash_file(file: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize_fixed()))
+}
+
+/* Offer a file, then send it, unless the other side already has it */
+async fn send_offered_file<F, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    file: &mut F,
+    file_size: u64,
+    offer: PeerMessage,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    F: AsyncRead + Unpin,
+    H: FnMut(u64, u64) + 'static,
+{
+    let mut transit = match offer_and_connect(wormhole, relay_urls, offer).await? {
+        Some(transit) => transit,
+        None => return Ok(Some(TransferStats::already_present())),
+    };
+
+    debug!("Beginning file transfer");
+
+    // 11. send the file as encrypted records.
+    let checksum = match send_records(&mut transit, file, file_size, progress_handler).await {
+        Err(TransferError::Transit(error)) => {
+            let _ = wormhole
+                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                .await;
+            Err(TransferError::Transit(error))
+        },
+        other => other,
+    }?;
+
+    receive_transit_ack(&mut transit, checksum).await
+}
+
+/**
+ * Like [`send_file_with_metadata`], but skip the holes of a sparse file
+ *
+ * `data` lists the regions of the file that contain data, in ascending order. Everything in between
+ * reads as zeros. If the other side supports [`EXTENSION_SPARSE`], only these regions are transferred.
+ * Otherwise, the whole file is sent as usual.
+ */
+#[allow(clippy::too_many_arguments)]
+pub async fn send_sparse_file<F, N, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    file: &mut F,
+    file_name: N,
+    file_size: u64,
+    metadata: OfferMetadata,
+    data: &[Range<u64>],
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    F: AsyncRead + AsyncSeek + Unpin,
+    N: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    with_deadline(
+        wormhole.deadline(),
+        Box::pin(send_sparse_file_inner(
+            wormhole,
+            relay_urls,
+            file,
+            file_name.into(),
+            file_size,
+            metadata,
+            data,
+            progress_handler,
+        )),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_sparse_file_inner<F, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    file: &mut F,
+    file_name: PathBuf,
+    file_size: u64,
+    metadata: OfferMetadata,
+    data: &[Range<u64>],
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    F: AsyncRead + AsyncSeek + Unpin,
+    H: FnMut(u64, u64) + 'static,
+{
+    let sparse = AppVersion::negotiate(wormhole)
+        .iter()
+        .any(|extension| extension == EXTENSION_SPARSE);
+    let offer = if sparse {
+        PeerMessage::new_offer_sparse_file(file_name, file_size, metadata)
+    } else {
+        PeerMessage::new_offer_file(file_name, file_size, metadata)
+    };
+    let mut transit = match offer_and_connect(wormhole, relay_urls, offer).await? {
+        Some(transit) => transit,
+        None => return Ok(Some(TransferStats::already_present())),
+    };
+
+    debug!("Beginning file transfer (sparse: {})", sparse);
+
+    let checksum = if sparse {
+        send_sparse_records(&mut transit, file, file_size, data, progress_handler).await
+    } else {
+        match file.seek(std::io::SeekFrom::Start(0)).await {
+            Ok(_) => send_records(&mut transit, file, file_size, progress_handler).await,
+            Err(error) => Err(error.into()),
+        }
+    };
+    let checksum = match checksum {
+        Err(TransferError::Transit(error)) => {
+            let _ = wormhole
+                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                .await;
+            Err(TransferError::Transit(error))
+        },
+        other => other,
+    }?;
+
+    receive_transit_ack(&mut transit, checksum).await
+}
+
+/**
+ * Send our transit hints and the `offer`, wait for the other side to accept and connect to it
+ *
+ * Returns `None` if the other side already has the file. It may only say so if the offer contains its hash.
+ */
+async fn offer_and_connect(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    mut offer: PeerMessage,
+) -> Result<Option<StripedTransit>, TransferError> {
+    let mut streams = 1;
+    if let PeerMessage::Offer(OfferType::File {
+        filesize,
+        streams: offered_streams,
+        ..
+    }) = &mut offer
+    {
+        if *filesize >= MULTI_STREAM_MIN_SIZE
+            && AppVersion::negotiate(wormhole)
+                .iter()
+                .any(|extension| extension == EXTENSION_MULTI_STREAM)
+        {
+            streams = TRANSIT_STREAMS;
+            *offered_streams = Some(streams);
+        }
+    }
+    let offers_hash = matches!(
+        offer,
+        PeerMessage::Offer(OfferType::File {
+            sha256: Some(_),
+            ..
+        })
+    );
+    let connector = transit::init(transit::Ability::all_abilities(), relay_urls).await?;
+    let mut more_connectors = Vec::new();
+    for _ in 1..streams {
+        more_connectors.push(connector.another().await?);
+    }
 
     // We want to do some transit
     debug!("Sending transit message '{:?}", connector.our_hints());
@@ -219,7 +717,7 @@ where
         .send(
             PeerMessage::new_transit(
                 connector.our_abilities().to_vec(),
-                (**connector.our_hints()).clone().into(),
+                connector.our_hints_for(None),
             )
             .serialize_vec(),
         )
@@ -227,13 +725,11 @@ where
 
     // Send file offer message.
     debug!("Sending file offer");
-    wormhole
-        .send(PeerMessage::new_offer_file(file_name, file_size).serialize_vec())
-        .await?;
+    wormhole.send(offer.serialize_vec()).await?;
 
     // Wait for their transit response
     let (their_abilities, their_hints): (Vec<transit::Ability>, transit::Hints) =
-        match serde_json::from_slice(&wormhole.receive().await?)? {
+        match receive_peer_message(wormhole).await? {
             PeerMessage::Transit(transit) => {
                 debug!("received transit message: {:?}", transit);
                 (transit.abilities_v1, transit.hints_v1.into())
@@ -252,15 +748,21 @@ where
 
     {
         // Wait for file_ack
-        let fileack_msg = serde_json::from_slice(&wormhole.receive().await?)?;
+        let fileack_msg = receive_answer(wormhole).await?;
         debug!("received file ack message: {:?}", fileack_msg);
 
         match fileack_msg {
+            PeerMessage::Answer(AnswerType::FileAck(msg))
+                if msg == FILE_ACK_IDENTICAL && offers_hash =>
+            {
+                debug!("The other side already has the file");
+                return Ok(None);
+            },
             PeerMessage::Answer(AnswerType::FileAck(msg)) => {
-                ensure!(msg == "ok", TransferError::AckError);
+                ensure!(msg == "ok", TransferError::OfferRejected { reason: msg });
             },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
+            PeerMessage::Error(reason) => {
+                bail!(TransferError::OfferRejected { reason });
             },
             _ => {
                 let error = TransferError::unexpected_message("answer/file_ack", fileack_msg);
@@ -272,37 +774,181 @@ where
         }
     }
 
-    let mut transit = match connector
+    let their_abilities = Arc::new(their_abilities);
+    match connector
         .leader_connect(
-            wormhole.key().derive_transit_key(wormhole.appid()),
-            Arc::new(their_abilities),
+            wormhole.derive_transit_key(),
+            their_abilities.clone(),
             Arc::new(their_hints),
         )
         .await
     {
-        Ok(transit) => transit,
+        Ok(mut transit) => {
+            transit.set_record_cipher(AppVersion::negotiate_record_cipher(wormhole));
+            connect_more_streams(wormhole, more_connectors, &their_abilities, transit, true)
+                .await
+                .map(Some)
+        },
         Err(error) => {
             let error = TransferError::TransitConnect(error);
             let _ = wormhole
                 .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
                 .await;
-            return Err(error);
+            Err(error)
         },
-    };
+    }
+}
 
-    debug!("Beginning file transfer");
+/**
+ * Establish the remaining connections of a multi-stream transfer, see [`EXTENSION_MULTI_STREAM`]
+ *
+ * Both sides exchange hints for all of the `connectors` first, then they are connected concurrently. The `n`th
+ * connection uses a transit key derived with the purpose `transit_stream_{n}`, so that it cannot be confused
+ * with any other one. `their_first_abilities` are the ones the other side announced for the first connection.
+ *
+ * Failed connections are left out. Since both sides must stripe over the same connections, they tell each other
+ * over the first connection which ones worked for them, and only keep those that worked for both.
+ */
+async fn connect_more_streams(
+    wormhole: &mut Wormhole,
+    connectors: Vec<TransitConnector>,
+    their_first_abilities: &[transit::Ability],
+    mut transit: Transit,
+    is_leader: bool,
+) -> Result<StripedTransit, TransferError> {
+    if connectors.is_empty() {
+        return Ok(StripedTransit::from(transit));
+    }
 
-    // 11. send the file as encrypted records.
-    let checksum = match send_records(&mut transit, file, file_size, progress_handler).await {
-        Err(TransferError::Transit(error)) => {
+    for connector in &connectors {
+        wormhole
+            .send(
+                PeerMessage::new_transit(
+                    connector.our_abilities().to_vec(),
+                    connector.our_hints_for(Some(their_first_abilities)),
+                )
+                .serialize_vec(),
+            )
+            .await?;
+    }
+    let mut their_transits = Vec::with_capacity(connectors.len());
+    for _ in &connectors {
+        their_transits.push(match receive_peer_message(wormhole).await? {
+            PeerMessage::Transit(transit) => (transit.abilities_v1, transit.hints_v1.into()),
+            PeerMessage::Error(err) => {
+                bail!(TransferError::PeerError(err));
+            },
+            other => {
+                let error = TransferError::unexpected_message("transit", other);
+                let _ = wormhole
+                    .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                    .await;
+                bail!(error)
+            },
+        });
+    }
+
+    let transit_key = wormhole.derive_transit_key();
+    let more =
+        futures::future::join_all(connectors.into_iter().zip(their_transits).enumerate().map(
+            |(index, (connector, (their_abilities, their_hints)))| {
+                let index = index + 1;
+                debug!("Establishing transit connection #{}", index);
+                let transit_key =
+                    transit_key.derive_subkey_from_purpose(&format!("transit_stream_{}", index));
+                let their_abilities: Arc<Vec<transit::Ability>> = Arc::new(their_abilities);
+                let their_hints: Arc<transit::Hints> = Arc::new(their_hints);
+                async move {
+                    let result = if is_leader {
+                        connector
+                            .leader_connect(transit_key, their_abilities, their_hints)
+                            .await
+                    } else {
+                        connector
+                            .follower_connect(transit_key, their_abilities, their_hints)
+                            .await
+                    };
+                    result
+                        .map_err(|error| warn!("Transit connection #{} failed: {}", index, error))
+                        .ok()
+                }
+            },
+        ))
+        .await;
+
+    let ours = more
+        .iter()
+        .map(|transit| transit.is_some() as u8)
+        .collect::<Vec<u8>>();
+    transit.send_record(&ours).await?;
+    let theirs = transit.receive_record().await?;
+    ensure!(
+        theirs.len() == ours.len(),
+        TransferError::Protocol("Wrong number of transit connections".into())
+    );
+
+    let cipher = transit.record_cipher();
+    let mut striped = StripedTransit::from(transit);
+    for (transit, theirs) in more.into_iter().zip(theirs.iter()) {
+        /* Dropping the others closes them */
+        if let (Some(mut transit), 1) = (transit, theirs) {
+            transit.set_record_cipher(cipher);
+            striped.push(transit);
+        }
+    }
+    if striped.streams() < ours.len() + 1 {
+        warn!(
+            "Only {} of {} transit connections worked",
+            striped.streams(),
+            ours.len() + 1
+        );
+    }
+
+    Ok(striped)
+}
+
+/**
+ * Receive the next message from the other side, within the [`Wormhole::timeout`]
+ *
+ * On timeout, the other side is told that we gave up, so that it doesn't wait for us in turn.
+ */
+async fn receive_peer_message(wormhole: &mut Wormhole) -> Result<PeerMessage, TransferError> {
+    let timeout = wormhole.timeout();
+    receive_peer_message_within(wormhole, timeout).await
+}
+
+/** Like [`receive_peer_message`], but for answers that may need a decision by the user, see [`Wormhole::answer_timeout`] */
+async fn receive_answer(wormhole: &mut Wormhole) -> Result<PeerMessage, TransferError> {
+    let timeout = wormhole.answer_timeout();
+    receive_peer_message_within(wormhole, timeout).await
+}
+
+async fn receive_peer_message_within(
+    wormhole: &mut Wormhole,
+    timeout: Option<std::time::Duration>,
+) -> Result<PeerMessage, TransferError> {
+    match wormhole.receive_with_timeout(timeout).await {
+        Ok(message) => Ok(serde_json::from_slice(&message)?),
+        Err(WormholeError::Timeout) => {
+            let error = TransferError::Timeout;
             let _ = wormhole
                 .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
                 .await;
-            Err(TransferError::Transit(error))
+            Err(error)
         },
-        other => other,
-    }?;
+        Err(error) => Err(error.into()),
+    }
+}
 
+/**
+ * Wait for the transit ack from the other side, and compare their checksum with ours
+ *
+ * Returns the statistics the other side sent along, if any.
+ */
+async fn receive_transit_ack(
+    transit: &mut StripedTransit,
+    checksum: Vec<u8>,
+) -> Result<Option<TransferStats>, TransferError> {
     // 13. wait for the transit ack with sha256 sum from the peer.
     debug!("sent file. Waiting for ack");
     let transit_ack = transit.receive_record().await?;
@@ -312,7 +958,57 @@ where
         TransferError::Checksum
     );
     debug!("transfer complete!");
-    Ok(())
+    Ok(transit_ack_msg.stats())
+}
+
+/**
+ * How [`send_folder_with_compression`] packs the folder
+ *
+ * The receiver gets the archive as a single file, so the name offered for it should end in
+ * `.tar` or `.tar.gz` respectively.
+ */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FolderCompression {
+    /** A plain tar archive. Best for content that is already compressed, like videos or images */
+    Store,
+    /** A gzip compressed tar archive, with a compression level from 0 (fastest) to 9 (smallest) */
+    Gzip(u32),
+}
+
+impl Default for FolderCompression {
+    fn default() -> Self {
+        FolderCompression::Store
+    }
+}
+
+/* Write the tar archive of `folder_path` into `writer`. This must produce the same bytes every time */
+fn write_folder_archive(
+    folder_path: &std::path::Path,
+    writer: impl std::io::Write,
+    compression: FolderCompression,
+) -> std::io::Result<()> {
+    fn write_tar(
+        folder_path: &std::path::Path,
+        writer: impl std::io::Write,
+    ) -> std::io::Result<()> {
+        let mut builder = tar::Builder::new(writer);
+        builder.mode(tar::HeaderMode::Deterministic);
+        builder.follow_symlinks(false);
+        builder.append_dir_all("", folder_path)?;
+        builder.finish()
+    }
+
+    match compression {
+        FolderCompression::Store => write_tar(folder_path, writer),
+        FolderCompression::Gzip(level) => {
+            /* The gzip header has no time stamp by default, so the output stays deterministic */
+            let mut encoder =
+                flate2::write::GzEncoder::new(writer, flate2::Compression::new(level.min(9)));
+            write_tar(folder_path, &mut encoder)?;
+            encoder.finish().map(std::mem::drop)
+        },
+    }
 }
 
 /// Send a folder to the other side
@@ -322,18 +1018,67 @@ where
 /// unpack it. But it's better than nothing
 pub async fn send_folder<N, M, H>(
     wormhole: &mut Wormhole,
-    relay_url: &RelayUrl,
+    relay_urls: &[RelayUrl],
     folder_path: N,
     folder_name: M,
     progress_handler: H,
-) -> Result<(), TransferError>
+) -> Result<Option<TransferStats>, TransferError>
+where
+    N: Into<PathBuf>,
+    M: Into<PathBuf>,
+    H: FnMut(u64, u64) + 'static,
+{
+    send_folder_with_compression(
+        wormhole,
+        relay_urls,
+        folder_path,
+        folder_name,
+        FolderCompression::Store,
+        progress_handler,
+    )
+    .await
+}
+
+/// Like [`send_folder`], but optionally compress the archive, see [`FolderCompression`]
+pub async fn send_folder_with_compression<N, M, H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    folder_path: N,
+    folder_name: M,
+    compression: FolderCompression,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
 where
     N: Into<PathBuf>,
     M: Into<PathBuf>,
     H: FnMut(u64, u64) + 'static,
 {
-    let connector = transit::init(transit::Ability::all_abilities(), relay_url).await?;
-    let folder_path = folder_path.into();
+    with_deadline(
+        wormhole.deadline(),
+        Box::pin(send_folder_inner(
+            wormhole,
+            relay_urls,
+            folder_path.into(),
+            folder_name.into(),
+            compression,
+            progress_handler,
+        )),
+    )
+    .await
+}
+
+async fn send_folder_inner<H>(
+    wormhole: &mut Wormhole,
+    relay_urls: &[RelayUrl],
+    folder_path: PathBuf,
+    folder_name: PathBuf,
+    compression: FolderCompression,
+    progress_handler: H,
+) -> Result<Option<TransferStats>, TransferError>
+where
+    H: FnMut(u64, u64) + 'static,
+{
+    let connector = transit::init(transit::Ability::all_abilities(), relay_urls).await?;
 
     if !folder_path.is_dir() {
         panic!(
@@ -348,15 +1093,12 @@ where
         .send(
             PeerMessage::new_transit(
                 connector.our_abilities().to_vec(),
-                (**connector.our_hints()).clone().into(),
+                connector.our_hints_for(None),
             )
             .serialize_vec(),
         )
         .await?;
 
-    use tar::Builder;
-    // use sha2::{digest::FixedOutput, Digest, Sha256};
-
     /* Helper struct stolen from https://docs.rs/count-write/0.1.0 */
     struct CountWrite<W> {
         inner: W,
@@ -384,36 +1126,43 @@ where
         folder_path.display()
     );
     let folder_path2 = folder_path.clone();
-    let (length, sha256sum_initial) = async_std::task::spawn_blocking(move || {
+    let (length, sha256sum_initial) = match async_std::task::spawn_blocking(move || {
         let mut hasher = Sha256::new();
         let mut counter = CountWrite {
             inner: &mut hasher,
             count: 0,
         };
-        let mut builder = Builder::new(&mut counter);
-
-        builder.mode(tar::HeaderMode::Deterministic);
-        builder.follow_symlinks(false);
-        /* A hasher should never fail writing */
-        builder.append_dir_all("", folder_path2).unwrap();
-        builder.finish().unwrap();
+        /* A hasher never fails writing, but reading the files might */
+        write_folder_archive(&folder_path2, &mut counter, compression)?;
 
-        std::mem::drop(builder);
         let count = counter.count;
         std::mem::drop(counter);
-        (count, hasher.finalize_fixed())
+        std::io::Result::Ok((count, hasher.finalize_fixed()))
     })
-    .await;
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => {
+            let error = TransferError::IO(error);
+            let _ = wormhole
+                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                .await;
+            return Err(error);
+        },
+    };
 
     // Send file offer message.
     debug!("Sending file offer");
     wormhole
-        .send(PeerMessage::new_offer_file(folder_name, length).serialize_vec())
+        .send(
+            PeerMessage::new_offer_file(folder_name, length, OfferMetadata::default())
+                .serialize_vec(),
+        )
         .await?;
 
     // Wait for their transit response
     let (their_abilities, their_hints): (Vec<transit::Ability>, transit::Hints) =
-        match serde_json::from_slice(&wormhole.receive().await?)? {
+        match receive_peer_message(wormhole).await? {
             PeerMessage::Transit(transit) => {
                 debug!("received transit message: {:?}", transit);
                 (transit.abilities_v1, transit.hints_v1.into())
@@ -432,15 +1181,15 @@ where
 
     {
         // Wait for file_ack
-        let fileack_msg = serde_json::from_slice(&wormhole.receive().await?)?;
+        let fileack_msg = receive_answer(wormhole).await?;
         debug!("received file ack message: {:?}", fileack_msg);
 
         match fileack_msg {
             PeerMessage::Answer(AnswerType::FileAck(msg)) => {
-                ensure!(msg == "ok", TransferError::AckError);
+                ensure!(msg == "ok", TransferError::OfferRejected { reason: msg });
             },
-            PeerMessage::Error(err) => {
-                bail!(TransferError::PeerError(err));
+            PeerMessage::Error(reason) => {
+                bail!(TransferError::OfferRejected { reason });
             },
             _ => {
                 let error = TransferError::unexpected_message("answer/file_ack", fileack_msg);
@@ -454,13 +1203,16 @@ where
 
     let mut transit = match connector
         .leader_connect(
-            wormhole.key().derive_transit_key(wormhole.appid()),
+            wormhole.derive_transit_key(),
             Arc::new(their_abilities),
             Arc::new(their_hints),
         )
         .await
     {
-        Ok(transit) => transit,
+        Ok(mut transit) => {
+            transit.set_record_cipher(AppVersion::negotiate_record_cipher(wormhole));
+            StripedTransit::from(transit)
+        },
         Err(error) => {
             let error = TransferError::TransitConnect(error);
             let _ = wormhole
@@ -480,8 +1232,8 @@ where
 
     impl<A: std::io::Write, B: std::io::Write> std::io::Write for BroadcastWriter<A, B> {
         fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
-            let n = self.primary.write(data).unwrap();
-            self.secondary.write_all(&data[..n]).unwrap();
+            let n = self.primary.write(data)?;
+            self.secondary.write_all(&data[..n])?;
             Ok(n)
         }
 
@@ -491,53 +1243,73 @@ where
     }
 
     // 11. send the file as encrypted records.
-    use futures::{AsyncReadExt, AsyncWriteExt};
-    let (mut reader, mut writer) = futures_ringbuf::RingBuffer::new(4096).split();
+    use futures::{SinkExt, TryStreamExt};
+    /* The tar'ing happens on a separate thread, and the data is piped over through this channel.
+     * Once we drop our end (e.g. because the transfer failed), writing on the other side fails
+     * and the thread terminates.
+     */
+    let (writer, reader) = futures::channel::mpsc::channel::<std::io::Result<Vec<u8>>>(4);
+    let mut reader = reader.into_async_read();
 
-    struct BlockingWrite<W>(std::pin::Pin<Box<W>>);
+    struct ChannelWrite(futures::channel::mpsc::Sender<std::io::Result<Vec<u8>>>);
 
-    impl<W: AsyncWrite + Send + Unpin> std::io::Write for BlockingWrite<W> {
+    impl std::io::Write for ChannelWrite {
         fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-            let mut writer = self.0.as_mut();
-            futures::executor::block_on(AsyncWriteExt::write(&mut writer, buf))
+            futures::executor::block_on(self.0.send(Ok(buf.to_vec()))).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "The transfer has been aborted",
+                )
+            })?;
+            Ok(buf.len())
         }
 
         fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-            let mut writer = self.0.as_mut();
-            futures::executor::block_on(AsyncWriteExt::flush(&mut writer))
+            Ok(())
         }
     }
 
     let file_sender = async_std::task::spawn_blocking(move || {
         let mut hasher = Sha256::new();
         let mut hash_writer = BroadcastWriter {
-            primary: BlockingWrite(Box::pin(&mut writer)),
+            primary: ChannelWrite(writer),
             secondary: &mut hasher,
         };
-        let mut builder = Builder::new(&mut hash_writer);
-
-        builder.mode(tar::HeaderMode::Deterministic);
-        builder.follow_symlinks(false);
-        builder.append_dir_all("", folder_path).unwrap();
-        builder.finish().unwrap();
-
-        std::mem::drop(builder);
+        write_folder_archive(&folder_path, &mut hash_writer, compression)?;
         std::mem::drop(hash_writer);
 
         std::io::Result::Ok(hasher.finalize_fixed())
     });
 
-    let checksum = match send_records(&mut transit, &mut reader, length, progress_handler).await {
+    let checksum = send_records(&mut transit, &mut reader, length, progress_handler).await;
+    /* Hang up, so that the thread will terminate in all cases */
+    std::mem::drop(reader);
+    let sha256sum = file_sender.await;
+    /* If reading the files failed, that's the more interesting error to report */
+    let checksum = match (checksum, sha256sum) {
+        (_, Err(error)) if error.kind() != std::io::ErrorKind::BrokenPipe => {
+            Err(TransferError::IO(error))
+        },
+        (Ok(checksum), Ok(sha256sum)) => Ok((checksum, sha256sum)),
+        (Err(error), _) => Err(error),
+        (Ok(_), Err(error)) => Err(TransferError::IO(error)),
+    };
+    let (checksum, sha256sum) = match checksum {
         Err(TransferError::Transit(error)) => {
             let _ = wormhole
                 .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
                 .await;
             Err(TransferError::Transit(error))
         },
+        Err(TransferError::IO(error)) => {
+            let error = TransferError::IO(error);
+            let _ = wormhole
+                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                .await;
+            Err(error)
+        },
         other => other,
     }?;
-    /* This should always be ready by now, but just in case */
-    let sha256sum = file_sender.await.unwrap();
 
     /* Check if the hash sum still matches what we advertized. Otherwise, tell the other side and bail out */
     if sha256sum != sha256sum_initial {
@@ -548,16 +1320,55 @@ where
         bail!(error)
     }
 
-    // 13. wait for the transit ack with sha256 sum from the peer.
-    debug!("sent file. Waiting for ack");
-    let transit_ack = transit.receive_record().await?;
-    let transit_ack_msg = serde_json::from_slice::<TransitAck>(&transit_ack)?;
-    ensure!(
-        transit_ack_msg.sha256 == hex::encode(checksum),
-        TransferError::Checksum
-    );
-    debug!("transfer complete!");
-    Ok(())
+    receive_transit_ack(&mut transit, checksum).await
+}
+
+/**
+ * Send a text message to the other side
+ *
+ * This waits until the other side acknowledged having received the message, or fails with
+ * [`TransferError::Timeout`] if this doesn't happen within `timeout`.
+ */
+pub async fn send_message(
+    wormhole: &mut Wormhole,
+    message: impl Into<String>,
+    timeout: std::time::Duration,
+) -> Result<(), TransferError> {
+    wormhole
+        .send(PeerMessage::new_offer_message(message).serialize_vec())
+        .await?;
+
+    debug!("Sent message. Waiting for ack");
+    let answer = async_std::future::timeout(timeout, wormhole.receive())
+        .await
+        .map_err(|_| TransferError::Timeout)??;
+    match serde_json::from_slice(&answer)? {
+        PeerMessage::Answer(AnswerType::MessageAck(ack)) if ack == "ok" => Ok(()),
+        PeerMessage::Answer(AnswerType::MessageAck(reason)) => {
+            Err(TransferError::OfferRejected { reason })
+        },
+        PeerMessage::Error(reason) => Err(TransferError::OfferRejected { reason }),
+        other => {
+            let error = TransferError::unexpected_message("message_ack", other);
+            let _ = wormhole
+                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                .await;
+            Err(error)
+        },
+    }
+}
+
+/**
+ * Something the other side offered us
+ *
+ * See [`request_offer`].
+ */
+#[must_use]
+pub enum Offer<'a> {
+    /// A text message. It has already been acknowledged.
+    Message(String),
+    /// A file or folder, waiting to be accepted or rejected
+    File(Box<ReceiveRequest<'a>>),
 }
 
 /**
@@ -565,12 +1376,95 @@ where
  *
  * This method waits for an offer message and builds up a [`ReceiveRequest`](ReceiveRequest).
  * It will also start building a TCP connection to the other side using the transit protocol.
+ * Text messages are not supported here, use [`request_offer`] if you expect them.
  */
 pub async fn request_file<'a>(
     wormhole: &'a mut Wormhole,
-    relay_url: &RelayUrl,
+    relay_urls: &[RelayUrl],
 ) -> Result<ReceiveRequest<'a>, TransferError> {
-    let connector = transit::init(transit::Ability::all_abilities(), relay_url).await?;
+    match request_offer(wormhole, relay_urls).await? {
+        Offer::File(request) => Ok(*request),
+        Offer::Message(_) => bail!(TransferError::UnsupportedOffer),
+    }
+}
+
+/**
+ * Wait for the next offer from the other side
+ *
+ * Text messages get acknowledged and returned directly, files are returned as [`ReceiveRequest`](ReceiveRequest).
+ * Once the offer has been dealt with, the wormhole stays open and this may be called again to
+ * receive the next one, until the other side closes the connection.
+ */
+pub async fn request_offer<'a>(
+    wormhole: &'a mut Wormhole,
+    relay_urls: &[RelayUrl],
+) -> Result<Offer<'a>, TransferError> {
+    /* The sender usually sends its transit message together with the offer */
+    let mut their_transit: Option<(Vec<transit::Ability>, transit::Hints)> = None;
+
+    // 3. receive offer message from peer
+    let (filename, filesize, metadata, sparse, sha256, streams) = loop {
+        let maybe_offer = receive_peer_message(wormhole).await?;
+        debug!("Received offer message '{:?}'", &maybe_offer);
+
+        match maybe_offer {
+            PeerMessage::Transit(transit) => {
+                debug!("received transit message: {:?}", transit);
+                their_transit = Some((transit.abilities_v1, transit.hints_v1.into()));
+            },
+            PeerMessage::Offer(OfferType::Message(message)) => {
+                wormhole
+                    .send(PeerMessage::new_message_ack("ok").serialize_vec())
+                    .await?;
+                return Ok(Offer::Message(message));
+            },
+            PeerMessage::Offer(OfferType::File {
+                filename,
+                filesize,
+                metadata,
+                sparse,
+                sha256,
+                streams,
+            }) => break (filename, filesize, metadata, sparse, sha256, streams),
+            PeerMessage::Offer(OfferType::Directory {
+                mut dirname,
+                zipsize,
+                ..
+            }) => {
+                dirname.set_extension("zip");
+                break (
+                    dirname,
+                    zipsize,
+                    OfferMetadata::default(),
+                    false,
+                    None,
+                    None,
+                );
+            },
+            PeerMessage::Offer(_) => bail!(TransferError::UnsupportedOffer),
+            PeerMessage::Error(err) => {
+                bail!(TransferError::PeerError(err));
+            },
+            other => {
+                let error = TransferError::unexpected_message("offer", other);
+                let _ = wormhole
+                    .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+                    .await;
+                bail!(error)
+            },
+        }
+    };
+
+    let streams = streams.unwrap_or(1);
+    if !(1..=MAX_TRANSIT_STREAMS).contains(&streams) {
+        let error = TransferError::UnsupportedOffer;
+        let _ = wormhole
+            .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
+            .await;
+        bail!(error)
+    }
+
+    let connector = transit::init(transit::Ability::all_abilities(), relay_urls).await?;
 
     // send the transit message
     debug!("Sending transit message '{:?}", connector.our_hints());
@@ -578,15 +1472,17 @@ pub async fn request_file<'a>(
         .send(
             PeerMessage::new_transit(
                 connector.our_abilities().to_vec(),
-                (**connector.our_hints()).clone().into(),
+                connector
+                    .our_hints_for(their_transit.as_ref().map(|(abilities, _)| &abilities[..])),
             )
             .serialize_vec(),
         )
         .await?;
 
-    // receive transit message
-    let (their_abilities, their_hints): (Vec<transit::Ability>, transit::Hints) =
-        match serde_json::from_slice(&wormhole.receive().await?)? {
+    // receive transit message, if it didn't come with the offer
+    let (their_abilities, their_hints) = match their_transit {
+        Some(their_transit) => their_transit,
+        None => match receive_peer_message(wormhole).await? {
             PeerMessage::Transit(transit) => {
                 debug!("received transit message: {:?}", transit);
                 (transit.abilities_v1, transit.hints_v1.into())
@@ -601,34 +1497,6 @@ pub async fn request_file<'a>(
                     .await;
                 bail!(error)
             },
-        };
-
-    // 3. receive file offer message from peer
-    let maybe_offer = serde_json::from_slice(&wormhole.receive().await?)?;
-    debug!("Received offer message '{:?}'", &maybe_offer);
-
-    let (filename, filesize) = match maybe_offer {
-        PeerMessage::Offer(offer_type) => match offer_type {
-            OfferType::File { filename, filesize } => (filename, filesize),
-            OfferType::Directory {
-                mut dirname,
-                zipsize,
-                ..
-            } => {
-                dirname.set_extension("zip");
-                (dirname, zipsize)
-            },
-            _ => bail!(TransferError::UnsupportedOffer),
-        },
-        PeerMessage::Error(err) => {
-            bail!(TransferError::PeerError(err));
-        },
-        _ => {
-            let error = TransferError::unexpected_message("offer", maybe_offer);
-            let _ = wormhole
-                .send(PeerMessage::Error(format!("{}", error)).serialize_vec())
-                .await;
-            bail!(error)
         },
     };
 
@@ -636,12 +1504,16 @@ pub async fn request_file<'a>(
         wormhole,
         filename,
         filesize,
+        metadata,
+        sparse,
+        sha256,
+        streams,
         connector,
         their_abilities: Arc::new(their_abilities),
         their_hints: Arc::new(their_hints),
     };
 
-    Ok(req)
+    Ok(Offer::File(Box::new(req)))
 }
 
 /**
@@ -656,6 +1528,14 @@ pub struct ReceiveRequest<'a> {
     /// **Security warning:** this is untrusted and unverified input
     pub filename: PathBuf,
     pub filesize: u64,
+    /// **Security warning:** this is untrusted and unverified input, too
+    pub metadata: OfferMetadata,
+    /* The sender skips the holes, see [`EXTENSION_SPARSE`] */
+    sparse: bool,
+    /* The hex encoded hash of the file, see [`EXTENSION_SKIP_IDENTICAL`] */
+    sha256: Option<String>,
+    /* The number of transit connections, see [`EXTENSION_MULTI_STREAM`] */
+    streams: u32,
     their_abilities: Arc<Vec<transit::Ability>>,
     their_hints: Arc<transit::Hints>,
 }
@@ -671,6 +1551,49 @@ impl<'a> ReceiveRequest<'a> {
         progress_handler: F,
         content_handler: &mut W,
     ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        let deadline = self.wormhole.deadline();
+        with_deadline(
+            deadline,
+            Box::pin(self.accept_inner(progress_handler, content_handler, None)),
+        )
+        .await
+    }
+
+    /**
+     * Accept the file offer, and make sure it is on disk before telling the other side
+     *
+     * Like [`accept`](Self::accept), but `file` is synced to disk before the transfer is acknowledged. `content_handler`
+     * usually writes to `file` (note that `&File` implements [`AsyncWrite`] too). Syncing the directory entry of a newly
+     * created file is up to the caller.
+     */
+    pub async fn accept_and_sync<F, W>(
+        self,
+        progress_handler: F,
+        content_handler: &mut W,
+        file: &async_std::fs::File,
+    ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        W: AsyncWrite + Unpin,
+    {
+        let deadline = self.wormhole.deadline();
+        with_deadline(
+            deadline,
+            Box::pin(self.accept_inner(progress_handler, content_handler, Some(file))),
+        )
+        .await
+    }
+
+    async fn accept_inner<F, W>(
+        self,
+        progress_handler: F,
+        content_handler: &mut W,
+        sync: Option<&async_std::fs::File>,
+    ) -> Result<(), TransferError>
     where
         F: FnMut(u64, u64) + 'static,
         W: AsyncWrite + Unpin,
@@ -681,18 +1604,23 @@ impl<'a> ReceiveRequest<'a> {
             .send(PeerMessage::new_file_ack("ok").serialize_vec())
             .await?;
 
-        let mut transit = match self
+        let mut more_connectors = Vec::new();
+        for _ in 1..self.streams {
+            more_connectors.push(self.connector.another().await?);
+        }
+        let transit = match self
             .connector
             .follower_connect(
-                self.wormhole
-                    .key()
-                    .derive_transit_key(self.wormhole.appid()),
+                self.wormhole.derive_transit_key(),
                 self.their_abilities.clone(),
                 self.their_hints.clone(),
             )
             .await
         {
-            Ok(transit) => transit,
+            Ok(mut transit) => {
+                transit.set_record_cipher(AppVersion::negotiate_record_cipher(self.wormhole));
+                transit
+            },
             Err(error) => {
                 let error = TransferError::TransitConnect(error);
                 let _ = self
@@ -702,14 +1630,24 @@ impl<'a> ReceiveRequest<'a> {
                 return Err(error);
             },
         };
+        let mut transit = connect_more_streams(
+            self.wormhole,
+            more_connectors,
+            &self.their_abilities,
+            transit,
+            false,
+        )
+        .await?;
 
         debug!("Beginning file transfer");
         // TODO here's the right position for applying the output directory and to check for malicious (relative) file paths
         match tcp_file_receive(
             &mut transit,
             self.filesize,
+            self.sparse,
             progress_handler,
             content_handler,
+            sync,
         )
         .await
         {
@@ -724,6 +1662,51 @@ impl<'a> ReceiveRequest<'a> {
         }
     }
 
+    /**
+     * Accept the file offer, and receive the file into memory
+     *
+     * Check [`filesize`](Self::filesize) before calling this, since the whole file will be held in memory.
+     */
+    pub async fn accept_to_vec<F>(self, progress_handler: F) -> Result<Vec<u8>, TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+    {
+        let mut buffer = Vec::new();
+        self.accept(progress_handler, &mut buffer).await?;
+        Ok(buffer)
+    }
+
+    /**
+     * Whether `file` has the same content as the offered one
+     *
+     * This is always `false` unless the sender included the file's hash in the offer, see
+     * [`EXTENSION_SKIP_IDENTICAL`]. If it is `true`, [`skip_identical`](Self::skip_identical) may be
+     * used instead of accepting the offer.
+     */
+    pub async fn is_identical_to(
+        &self,
+        file: &mut (impl AsyncRead + Unpin),
+    ) -> Result<bool, TransferError> {
+        match &self.sha256 {
+            Some(sha256) => Ok(hash_file(file).await?.eq_ignore_ascii_case(sha256)),
+            None => Ok(false),
+        }
+    }
+
+    /**
+     * Tell the sender that we already have the file, so that it need not be sent
+     *
+     * Only call this after [`is_identical_to`](Self::is_identical_to) confirmed it.
+     */
+    pub async fn skip_identical(self) -> Result<(), TransferError> {
+        ensure!(self.sha256.is_some(), TransferError::UnsupportedOffer);
+        self.wormhole
+            .send(PeerMessage::new_file_ack(FILE_ACK_IDENTICAL).serialize_vec())
+            .await?;
+
+        Ok(())
+    }
+
     /**
      * Reject the file offer
      *
@@ -742,7 +1725,7 @@ impl<'a> ReceiveRequest<'a> {
 // encrypt and send the file to tcp stream and return the sha256 sum
 // of the file before encryption.
 async fn send_records<F>(
-    transit: &mut Transit,
+    transit: &mut StripedTransit,
     file: &mut (impl AsyncRead + Unpin),
     file_size: u64,
     mut progress_handler: F,
@@ -769,7 +1752,7 @@ where
     let mut sent_size = 0;
     loop {
         // read a block of up to 4096 bytes
-        let n = file.read(&mut plaintext[..]).await?;
+        let n = read_chunk(file, &mut plaintext[..]).await?;
 
         // send the encrypted record
         transit.send_record(&plaintext[0..n]).await?;
@@ -783,6 +1766,7 @@ where
             break;
         }
     }
+    transit.flush().await?;
 
     ensure!(
         sent_size == file_size,
@@ -795,9 +1779,108 @@ where
     Ok(hasher.finalize_fixed().to_vec())
 }
 
+/* Read until `buffer` is full or `file` ends, and return how much was read. A single `read` may
+ * return less at any time (pipes, network file systems, …), which must not be mistaken for the end.
+ */
+async fn read_chunk(
+    file: &mut (impl AsyncRead + Unpin),
+    buffer: &mut [u8],
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+/* With the sparse extension, every record starts with one of these tags */
+const SPARSE_DATA: u8 = 0;
+/* Followed by the length of the hole as big endian u64 */
+const SPARSE_HOLE: u8 = 1;
+
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/**
+ * Like [`send_records`], but only send the `data` regions of the file, and skip the holes in between
+ */
+async fn send_sparse_records<F>(
+    transit: &mut StripedTransit,
+    file: &mut (impl AsyncRead + AsyncSeek + Unpin),
+    file_size: u64,
+    data: &[Range<u64>],
+    mut progress_handler: F,
+) -> Result<Vec<u8>, TransferError>
+where
+    F: FnMut(u64, u64) + 'static,
+{
+    progress_handler(0, file_size);
+
+    let mut hasher = Sha256::default();
+    let mut record = Box::new([0u8; 4097]);
+    record[0] = SPARSE_DATA;
+    let mut position = 0;
+
+    /* A hole up to `end`. The zeros still need to go into the checksum */
+    async fn skip_to(
+        transit: &mut StripedTransit,
+        hasher: &mut Sha256,
+        position: &mut u64,
+        end: u64,
+    ) -> Result<(), TransferError> {
+        if end <= *position {
+            return Ok(());
+        }
+        let mut hole = vec![SPARSE_HOLE];
+        hole.extend_from_slice(&(end - *position).to_be_bytes());
+        transit.send_record(&hole).await?;
+        while *position < end {
+            let n = (end - *position).min(ZEROS.len() as u64);
+            hasher.update(&ZEROS[..n as usize]);
+            *position += n;
+        }
+        Ok(())
+    }
+
+    for region in data {
+        let end = region.end.min(file_size);
+        if end <= position.max(region.start) {
+            continue;
+        }
+        skip_to(transit, &mut hasher, &mut position, region.start).await?;
+        file.seek(std::io::SeekFrom::Start(position)).await?;
+
+        while position < end {
+            let n = (end - position).min(4096) as usize;
+            let read = file.read(&mut record[1..=n]).await?;
+            ensure!(
+                read > 0,
+                TransferError::FileSize {
+                    sent_size: position,
+                    file_size
+                }
+            );
+            transit.send_record(&record[..=read]).await?;
+            hasher.update(&record[1..=read]);
+            position += read as u64;
+            progress_handler(position, file_size);
+        }
+    }
+    skip_to(transit, &mut hasher, &mut position, file_size).await?;
+    transit.flush().await?;
+    progress_handler(position, file_size);
+
+    Ok(hasher.finalize_fixed().to_vec())
+}
+
 async fn receive_records<F, W>(
     filesize: u64,
-    transit: &mut Transit,
+    sparse: bool,
+    transit: &mut StripedTransit,
     mut progress_handler: F,
     content_handler: &mut W,
 ) -> Result<Vec<u8>, TransferError>
@@ -808,7 +1891,8 @@ where
     let mut hasher = Sha256::default();
     let total = filesize;
 
-    let mut remaining_size = filesize as usize;
+    /* Count in u64 rather than usize, files may be larger than the address space on 32-bit targets */
+    let mut remaining_size: u64 = filesize;
 
     // Might not need to do this here, since `accept()` is where they'd know the filesize
     // already...
@@ -816,17 +1900,56 @@ where
 
     while remaining_size > 0 {
         // 3. decrypt the vector 'enc_packet' with the key.
-        let plaintext = transit.receive_record().await?;
-
-        content_handler.write_all(&plaintext).await?;
-
-        // 4. calculate a rolling sha256 sum of the decrypted output.
-        hasher.update(&plaintext);
+        let record = transit.receive_record().await?;
+
+        let record_size = match record.split_first() {
+            Some((&SPARSE_HOLE, length)) if sparse => {
+                let length = <[u8; 8]>::try_from(length)
+                    .map_err(|_| TransferError::protocol("Received a malformed hole record"))?;
+                let length = u64::from_be_bytes(length);
+                ensure!(
+                    length <= remaining_size,
+                    TransferError::FileSize {
+                        sent_size: total - remaining_size + length,
+                        file_size: total,
+                    }
+                );
+                /* We can't seek on the writer, so write the zeros out */
+                let mut written = 0;
+                while written < length {
+                    let n = (length - written).min(ZEROS.len() as u64) as usize;
+                    content_handler.write_all(&ZEROS[..n]).await?;
+                    hasher.update(&ZEROS[..n]);
+                    written += n as u64;
+                }
+                length
+            },
+            Some((&SPARSE_DATA, plaintext)) if sparse => {
+                content_handler.write_all(plaintext).await?;
+                hasher.update(plaintext);
+                u64::try_from(plaintext.len())
+                    .map_err(|_| TransferError::protocol("Received an oversized record"))?
+            },
+            _ if sparse => bail!(TransferError::protocol("Received an unknown record type")),
+            _ => {
+                content_handler.write_all(&record).await?;
 
-        remaining_size -= plaintext.len();
+                // 4. calculate a rolling sha256 sum of the decrypted output.
+                hasher.update(&record);
 
-        let remaining = remaining_size as u64;
-        progress_handler(total - remaining, total);
+                u64::try_from(record.len())
+                    .map_err(|_| TransferError::protocol("Received an oversized record"))?
+            },
+        };
+        remaining_size =
+            remaining_size
+                .checked_sub(record_size)
+                .ok_or_else(|| TransferError::FileSize {
+                    sent_size: total - remaining_size + record_size,
+                    file_size: total,
+                })?;
+
+        progress_handler(total - remaining_size, total);
     }
 
     debug!("done");
@@ -835,10 +1958,12 @@ where
 }
 
 async fn tcp_file_receive<F, W>(
-    transit: &mut Transit,
+    transit: &mut StripedTransit,
     filesize: u64,
+    sparse: bool,
     progress_handler: F,
     content_handler: &mut W,
+    sync: Option<&async_std::fs::File>,
 ) -> Result<(), TransferError>
 where
     F: FnMut(u64, u64) + 'static,
@@ -847,15 +1972,30 @@ where
     // 5. receive encrypted records
     // now skey and rkey can be used. skey is used by the tx side, rkey is used
     // by the rx side for symmetric encryption.
-    let checksum = receive_records(filesize, transit, progress_handler, content_handler).await?;
+    let start = std::time::Instant::now();
+    let checksum =
+        receive_records(filesize, sparse, transit, progress_handler, content_handler).await?;
+    let stats = TransferStats::new(filesize, start.elapsed());
+
+    /* Don't acknowledge anything that might still get lost */
+    content_handler.flush().await?;
+    if let Some(file) = sync {
+        debug!("Syncing the file to disk");
+        file.sync_all().await?;
+    }
 
     let sha256sum = hex::encode(checksum.as_slice());
     debug!("sha256 sum: {:?}", sha256sum);
 
     // 6. verify sha256 sum by sending an ack message to peer along with checksum.
     transit
-        .send_record(&TransitAck::new("ok", &sha256sum).serialize_vec())
+        .send_record(
+            &TransitAck::new("ok", &sha256sum)
+                .with_stats(stats)
+                .serialize_vec(),
+        )
         .await?;
+    transit.flush().await?;
 
     // 7. close socket.
     // well, no need, it gets dropped when it goes out of scope.
@@ -878,13 +2018,48 @@ mod test {
 
     #[test]
     fn test_offer_file() {
-        let f1 = PeerMessage::new_offer_file("somefile.txt", 34556);
+        let f1 = PeerMessage::new_offer_file("somefile.txt", 34556, OfferMetadata::default());
         assert_eq!(
             f1.serialize(),
             "{\"offer\":{\"file\":{\"filename\":\"somefile.txt\",\"filesize\":34556}}}"
         );
     }
 
+    #[test]
+    fn test_offer_sparse_file() {
+        let f1 = PeerMessage::new_offer_sparse_file("disk.img", 34556, OfferMetadata::default());
+        assert_eq!(
+            f1.serialize(),
+            "{\"offer\":{\"file\":{\"filename\":\"disk.img\",\"filesize\":34556,\"sparse\":true}}}"
+        );
+        assert_eq!(
+            serde_json::from_str::<PeerMessage>(&f1.serialize()).unwrap(),
+            f1
+        );
+    }
+
+    #[test]
+    fn test_offer_file_metadata() {
+        let metadata = OfferMetadata::default()
+            .mime_type("text/plain")
+            .mtime(1600000000);
+        let f1 = PeerMessage::new_offer_file("somefile.txt", 34556, metadata.clone());
+        assert_eq!(
+            f1.serialize(),
+            "{\"offer\":{\"file\":{\"filename\":\"somefile.txt\",\"filesize\":34556,\"mime-type\":\"text/plain\",\"mtime\":1600000000}}}"
+        );
+        assert_eq!(
+            serde_json::from_str::<PeerMessage>(&f1.serialize()).unwrap(),
+            f1
+        );
+
+        /* Unknown fields from future versions are ignored */
+        let f2: PeerMessage = serde_json::from_str(
+            "{\"offer\":{\"file\":{\"filename\":\"somefile.txt\",\"filesize\":34556,\"mime-type\":\"text/plain\",\"mtime\":1600000000,\"color\":\"blue\"}}}"
+        ).unwrap();
+        assert_eq!(f2, f1);
+    }
+
     #[test]
     fn test_offer_directory() {
         let d1 = PeerMessage::new_offer_directory("somedirectory", "zipped", 45, 1234, 10);
@@ -910,5 +2085,42 @@ mod test {
     fn test_transit_ack() {
         let f1 = TransitAck::new("ok", "deadbeaf");
         assert_eq!(f1.serialize(), "{\"ack\":\"ok\",\"sha256\":\"deadbeaf\"}");
+        assert_eq!(f1.stats(), None);
+    }
+
+    #[test]
+    fn test_transit_ack_stats() {
+        let stats = TransferStats::new(1234, std::time::Duration::from_millis(5678));
+        let f1 = TransitAck::new("ok", "deadbeaf").with_stats(stats);
+        assert_eq!(
+            f1.serialize(),
+            "{\"ack\":\"ok\",\"bytes-received\":1234,\"elapsed-ms\":5678,\"sha256\":\"deadbeaf\"}"
+        );
+        let f2: TransitAck = serde_json::from_str(&f1.serialize()).unwrap();
+        assert_eq!(f2.stats(), Some(stats));
+    }
+
+    #[test]
+    fn test_folder_archive_compression() {
+        use std::io::Read;
+
+        let folder = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src/core");
+        let archive = |compression| {
+            let mut archive = Vec::new();
+            write_folder_archive(&folder, &mut archive, compression).unwrap();
+            archive
+        };
+
+        let tar = archive(FolderCompression::Store);
+        let gzip = archive(FolderCompression::Gzip(9));
+        /* Both passes over the folder must yield the same bytes */
+        assert_eq!(gzip, archive(FolderCompression::Gzip(9)));
+        assert!(gzip.len() < tar.len());
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(&gzip[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, tar);
     }
 }
@@ -0,0 +1,162 @@
+//! Send the same file to several recipients, each with their own code
+
+use super::{send_file_or_folder, AppVersion, TransferError, TransferStats};
+use crate::{
+    rendezvous::RendezvousServer, transit::RelayUrl, AppConfig, Code, Wormhole, WormholeError,
+    WormholeWelcome,
+};
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+
+/**
+ * Send a file or folder to many recipients, one after another
+ *
+ * This is like running `wormhole send` over and over again: every recipient gets a fresh wormhole
+ * with a new code. Unlike reusing the same code (which is what the `send-many` CLI command does),
+ * an attacker only ever gets one try at guessing each code.
+ *
+ * Use [`next_recipient`](Self::next_recipient) to drive the loop yourself, or [`run`](Self::run).
+ */
+#[derive(Clone, Debug)]
+pub struct SendToMany {
+    config: AppConfig<AppVersion>,
+    code_length: usize,
+    relay_urls: Vec<RelayUrl>,
+    file_path: PathBuf,
+    file_name: PathBuf,
+}
+
+impl SendToMany {
+    pub fn new(
+        config: AppConfig<AppVersion>,
+        code_length: usize,
+        relay_urls: Vec<RelayUrl>,
+        file_path: impl Into<PathBuf>,
+        file_name: impl Into<PathBuf>,
+    ) -> Self {
+        SendToMany {
+            config,
+            code_length,
+            relay_urls,
+            file_path: file_path.into(),
+            file_name: file_name.into(),
+        }
+    }
+
+    /**
+     * Allocate a new code for the next recipient
+     *
+     * Show the code from [`Recipient::welcome`] to the user, then call [`Recipient::send`].
+     */
+    pub async fn next_recipient(&self) -> Result<Recipient, TransferError> {
+        let (welcome, connector) =
+            Wormhole::connect_without_code(self.config.clone(), self.code_length).await?;
+        Ok(self.recipient(welcome, Box::pin(connector)))
+    }
+
+    /**
+     * [`next_recipient`](Self::next_recipient), but with an already connected [`RendezvousServer`]
+     *
+     * See [`Wormhole::connect_without_code_via`].
+     */
+    pub async fn next_recipient_via(
+        &self,
+        server: RendezvousServer,
+        welcome: Option<String>,
+    ) -> Result<Recipient, TransferError> {
+        let (welcome, connector) = Wormhole::connect_without_code_via(
+            server,
+            welcome,
+            self.config.clone(),
+            self.code_length,
+        )
+        .await?;
+        Ok(self.recipient(welcome, Box::pin(connector)))
+    }
+
+    fn recipient(
+        &self,
+        welcome: WormholeWelcome,
+        connector: BoxFuture<'static, Result<Wormhole, WormholeError>>,
+    ) -> Recipient {
+        Recipient {
+            welcome,
+            connector,
+            relay_urls: self.relay_urls.clone(),
+            file_path: self.file_path.clone(),
+            file_name: self.file_name.clone(),
+        }
+    }
+
+    /**
+     * Send the file to up to `count` recipients, one after another
+     *
+     * `on_code` gets called with each new code. Failed transfers don't stop the loop, they are
+     * reported in the result. Failing to allocate a new code does.
+     */
+    pub async fn run(
+        &self,
+        count: usize,
+        mut on_code: impl FnMut(&WormholeWelcome),
+    ) -> Result<Vec<RecipientResult>, TransferError> {
+        let mut results = Vec::with_capacity(count);
+        for _ in 0..count {
+            let recipient = self.next_recipient().await?;
+            on_code(&recipient.welcome);
+            let code = recipient.welcome.code.clone();
+            let result = recipient.send(|_, _| {}).await;
+            if let Err(error) = &result {
+                log::warn!("Sending to {} failed: {}", code, error);
+            }
+            results.push(RecipientResult { code, result });
+        }
+        Ok(results)
+    }
+}
+
+/**
+ * A recipient of a [`SendToMany`], waiting for the other side to enter the code
+ */
+#[must_use]
+pub struct Recipient {
+    pub welcome: WormholeWelcome,
+    connector: BoxFuture<'static, Result<Wormhole, WormholeError>>,
+    relay_urls: Vec<RelayUrl>,
+    file_path: PathBuf,
+    file_name: PathBuf,
+}
+
+impl std::fmt::Debug for Recipient {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("Recipient")
+            .field("welcome", &self.welcome)
+            .finish()
+    }
+}
+
+impl Recipient {
+    /** Wait for the other side to connect, and send them the file */
+    pub async fn send<H>(self, progress_handler: H) -> Result<Option<TransferStats>, TransferError>
+    where
+        H: FnMut(u64, u64) + 'static,
+    {
+        let mut wormhole = self.connector.await?;
+        let result = send_file_or_folder(
+            &mut wormhole,
+            &self.relay_urls,
+            self.file_path.as_os_str(),
+            self.file_name.as_os_str(),
+            progress_handler,
+        )
+        .await;
+        wormhole.close().await;
+        result
+    }
+}
+
+/** How sending to one recipient of a [`SendToMany`] went */
+#[derive(Debug)]
+pub struct RecipientResult {
+    pub code: Code,
+    pub result: Result<Option<TransferStats>, TransferError>,
+}
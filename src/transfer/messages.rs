@@ -1,13 +1,14 @@
 //! Over-the-wire messages for the file transfer (including transit)
 //!
 //! The transit protocol does not specify how to deliver the information to
-//! the other side, so it is up to the file transfer to do that. hfoo
+//! the other side, so it is up to the file transfer to do that. The transit
+//! message types themselves live in [`transit`](crate::transit).
 
-use crate::transit::{self, Ability, DirectHint};
+use crate::transit::{self, Hint, TransitType};
 use serde_derive::{Deserialize, Serialize};
 #[cfg(test)]
 use serde_json::json;
-use std::{collections::HashSet, path::PathBuf};
+use std::path::PathBuf;
 
 /**
  * The type of message exchanged over the wormhole for this protocol
@@ -30,10 +31,51 @@ impl PeerMessage {
         PeerMessage::Offer(OfferType::Message(msg.into()))
     }
 
-    pub fn new_offer_file(name: impl Into<PathBuf>, size: u64) -> Self {
+    pub fn new_offer_file(name: impl Into<PathBuf>, size: u64, metadata: OfferMetadata) -> Self {
         PeerMessage::Offer(OfferType::File {
             filename: name.into(),
             filesize: size,
+            metadata,
+            sparse: false,
+            sha256: None,
+            streams: None,
+        })
+    }
+
+    /**
+     * Offer a file along with its hash, so that the other side can skip it if it already has it
+     *
+     * See [`EXTENSION_SKIP_IDENTICAL`](crate::transfer::EXTENSION_SKIP_IDENTICAL).
+     */
+    pub fn new_offer_file_with_sha256(
+        name: impl Into<PathBuf>,
+        size: u64,
+        metadata: OfferMetadata,
+        sha256: impl Into<String>,
+    ) -> Self {
+        PeerMessage::Offer(OfferType::File {
+            filename: name.into(),
+            filesize: size,
+            metadata,
+            sparse: false,
+            sha256: Some(sha256.into()),
+            streams: None,
+        })
+    }
+
+    /** Offer a file whose holes will be skipped, see [`EXTENSION_SPARSE`](crate::transfer::EXTENSION_SPARSE) */
+    pub fn new_offer_sparse_file(
+        name: impl Into<PathBuf>,
+        size: u64,
+        metadata: OfferMetadata,
+    ) -> Self {
+        PeerMessage::Offer(OfferType::File {
+            filename: name.into(),
+            filesize: size,
+            metadata,
+            sparse: true,
+            sha256: None,
+            streams: None,
         })
     }
 
@@ -89,6 +131,17 @@ pub enum OfferType {
     File {
         filename: PathBuf,
         filesize: u64,
+        #[serde(flatten)]
+        metadata: OfferMetadata,
+        /** Only ever set if both sides support the sparse extension */
+        #[serde(default, skip_serializing_if = "is_false")]
+        sparse: bool,
+        /** The hex encoded SHA-256 of the file. Only ever set if both sides support the skip-identical extension */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        /** The number of transit connections to use. Only ever set if both sides support the multi-stream extension */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        streams: Option<u32>,
     },
     Directory {
         dirname: PathBuf,
@@ -101,99 +154,59 @@ pub enum OfferType {
     Unknown,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "snake_case")]
-pub enum AnswerType {
-    MessageAck(String),
-    FileAck(String),
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 /**
- * A set of hints for both sides to find each other
+ * Optional information about an offered file
+ *
+ * None of this is part of the protocol, other clients will neither send it nor look at it. Unset fields are not
+ * sent at all, and unknown ones are ignored, so more may be added later on.
  */
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub struct TransitType {
-    pub abilities_v1: Vec<Ability>,
-    pub hints_v1: Vec<Hint>,
-}
-
-impl From<transit::Hints> for Vec<Hint> {
-    fn from(hints: transit::Hints) -> Self {
-        hints
-            .direct_tcp
-            .into_iter()
-            .map(Hint::DirectTcpV1)
-            .chain(std::iter::once(Hint::new_relay(hints.relay)))
-            .collect()
-    }
-}
-
-impl Into<transit::Hints> for Vec<Hint> {
-    fn into(self) -> transit::Hints {
-        let mut direct_tcp = HashSet::new();
-        let mut relay = HashSet::new();
-
-        /* There is only one "relay hint", though it may contain multiple
-         * items. Yes, this is inconsistent and weird, watch your step.
-         */
-        for hint in self {
-            match hint {
-                Hint::DirectTcpV1(hint) => {
-                    direct_tcp.insert(hint);
-                },
-                Hint::DirectUdtV1(_) => unimplemented!(),
-                Hint::RelayV1(RelayHint { hints }) => relay.extend(hints),
-            }
-        }
-
-        transit::Hints { direct_tcp, relay }
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-#[serde(rename_all = "kebab-case", tag = "type")]
 #[non_exhaustive]
-pub enum Hint {
-    DirectTcpV1(DirectHint),
-    DirectUdtV1(DirectHint),
-    /* Weirdness alarm: a "relay hint" contains multiple "direct hints". This means
-     * that there may be multiple direct hints, but if there are multiple relay hints
-     * it's still only one item because it internally has a list.
-     */
-    RelayV1(RelayHint),
+pub struct OfferMetadata {
+    /** The MIME type of the content, like `text/plain` */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    /** A free-form description for the user */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /** Last modification time, in seconds since the Unix epoch */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
 }
 
-impl Hint {
-    pub fn new_direct_tcp(_priority: f32, hostname: &str, port: u16) -> Self {
-        Hint::DirectTcpV1(DirectHint {
-            hostname: hostname.to_string(),
-            port,
-        })
+impl OfferMetadata {
+    pub fn mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.mime_type = Some(mime_type.into());
+        self
     }
 
-    pub fn new_direct_udt(_priority: f32, hostname: &str, port: u16) -> Self {
-        Hint::DirectUdtV1(DirectHint {
-            hostname: hostname.to_string(),
-            port,
-        })
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
     }
 
-    pub fn new_relay(h: HashSet<DirectHint>) -> Self {
-        Hint::RelayV1(RelayHint {
-            hints: h.into_iter().collect(),
-        })
+    pub fn mtime(mut self, mtime: u64) -> Self {
+        self.mtime = Some(mtime);
+        self
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub struct RelayHint {
-    pub hints: Vec<DirectHint>,
+#[serde(rename_all = "snake_case")]
+pub enum AnswerType {
+    MessageAck(String),
+    FileAck(String),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::transit::{Ability, DirectHint};
 
     #[test]
     fn test_transit() {
@@ -0,0 +1,229 @@
+//! `wormhole-transfer:` URIs, to share codes as links or QR codes
+//!
+//! The format is `wormhole-transfer:<code>[?version=0][&rendezvous=<url>][&role=leader|follower]`, with the
+//! code and the rendezvous URL percent-encoded. See the [magic-wormhole-protocols] for the specification.
+//!
+//! [magic-wormhole-protocols]: https://github.com/magic-wormhole/magic-wormhole-protocols/blob/main/uri-scheme.md
+
+use crate::{Code, ParseCodeError};
+use std::{borrow::Cow, fmt, str::FromStr};
+
+/** The URI scheme, including the colon */
+pub const URI_SCHEME: &str = "wormhole-transfer:";
+
+/**
+ * A code to a file transfer, as a `wormhole-transfer:` URI
+ *
+ * Parse one with [`FromStr`], generate one with [`Display`](fmt::Display). The [`code`](Self::code) can
+ * then be used with [`Wormhole::connect_with_code`](crate::Wormhole::connect_with_code), using the
+ * [`rendezvous_server`](Self::rendezvous_server) if there is one.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WormholeTransferUri {
+    pub code: Code,
+    /** The rendezvous server to use, if it isn't the default one */
+    pub rendezvous_server: Option<String>,
+    /**
+     * Whether the one following the URI has to send the file (leader), instead of receiving it
+     *
+     * This is almost never set, since the one who shows the URI usually is the sender.
+     */
+    pub is_leader: bool,
+}
+
+/** What is wrong with a `wormhole-transfer:` URI */
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseUriError {
+    #[error("The URI must start with '{}'", URI_SCHEME)]
+    Scheme,
+    #[error("Version {} of the URI format is not supported", _0)]
+    Version(String),
+    #[error("Unknown role '{}', must be 'leader' or 'follower'", _0)]
+    Role(String),
+    #[error("Malformed percent-encoding")]
+    Encoding,
+    #[error("Invalid code")]
+    Code(
+        #[from]
+        #[source]
+        ParseCodeError,
+    ),
+}
+
+impl WormholeTransferUri {
+    pub fn new(code: Code) -> Self {
+        WormholeTransferUri {
+            code,
+            rendezvous_server: None,
+            is_leader: false,
+        }
+    }
+
+    pub fn rendezvous_server(mut self, rendezvous_server: impl Into<String>) -> Self {
+        self.rendezvous_server = Some(rendezvous_server.into());
+        self
+    }
+
+    pub fn leader(mut self, is_leader: bool) -> Self {
+        self.is_leader = is_leader;
+        self
+    }
+}
+
+impl FromStr for WormholeTransferUri {
+    type Err = ParseUriError;
+
+    fn from_str(uri: &str) -> Result<Self, ParseUriError> {
+        let uri = match uri.get(..URI_SCHEME.len()) {
+            Some(scheme) if scheme.eq_ignore_ascii_case(URI_SCHEME) => &uri[URI_SCHEME.len()..],
+            _ => return Err(ParseUriError::Scheme),
+        };
+        let (code, query) = split_at_char(uri, '?');
+
+        let mut parsed = WormholeTransferUri::new(Code::parse(&percent_decode(code)?)?);
+        for (key, value) in query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| split_at_char(pair, '='))
+        {
+            let value = percent_decode(value)?;
+            match key {
+                "version" if value != "0" => return Err(ParseUriError::Version(value.into())),
+                "rendezvous" => parsed.rendezvous_server = Some(value.into()),
+                "role" => {
+                    parsed.is_leader = match &*value {
+                        "leader" => true,
+                        "follower" => false,
+                        _ => return Err(ParseUriError::Role(value.into())),
+                    }
+                },
+                /* Ignore unknown parameters, for future expansion */
+                _ => (),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for WormholeTransferUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", URI_SCHEME, percent_encode(&self.code))?;
+        let mut separator = '?';
+        if let Some(rendezvous_server) = &self.rendezvous_server {
+            write!(
+                f,
+                "{}rendezvous={}",
+                separator,
+                percent_encode(rendezvous_server)
+            )?;
+            separator = '&';
+        }
+        if self.is_leader {
+            write!(f, "{}role=leader", separator)?;
+        }
+        Ok(())
+    }
+}
+
+/* Split at the first `separator`, which is left out. Without one, everything is in the first part */
+fn split_at_char(input: &str, separator: char) -> (&str, &str) {
+    match input.find(separator) {
+        Some(index) => (&input[..index], &input[index + separator.len_utf8()..]),
+        None => (input, ""),
+    }
+}
+
+/* Everything but the RFC 3986 unreserved characters gets encoded */
+fn percent_encode(input: &str) -> Cow<'_, str> {
+    let unreserved = |byte: u8| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte);
+    if input.bytes().all(unreserved) {
+        return Cow::Borrowed(input);
+    }
+    let mut encoded = String::with_capacity(input.len() * 3);
+    for byte in input.bytes() {
+        if unreserved(byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+fn percent_decode(input: &str) -> Result<Cow<'_, str>, ParseUriError> {
+    if !input.contains('%') {
+        return Ok(Cow::Borrowed(input));
+    }
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [
+                bytes.next().ok_or(ParseUriError::Encoding)?,
+                bytes.next().ok_or(ParseUriError::Encoding)?,
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(|_| ParseUriError::Encoding)?;
+            decoded.push(u8::from_str_radix(hex, 16).map_err(|_| ParseUriError::Encoding)?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded)
+        .map(Cow::Owned)
+        .map_err(|_| ParseUriError::Encoding)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uri_roundtrip() {
+        let uri = WormholeTransferUri::new(Code("4-hurricane-equipment".into()));
+        assert_eq!(uri.to_string(), "wormhole-transfer:4-hurricane-equipment");
+        assert_eq!(uri.to_string().parse(), Ok(uri));
+
+        let uri = WormholeTransferUri::new(Code("8-correct-horse-battery-staple".into()))
+            .rendezvous_server("ws://localhost:4000/v1")
+            .leader(true);
+        assert_eq!(
+            uri.to_string(),
+            "wormhole-transfer:8-correct-horse-battery-staple?rendezvous=ws%3A%2F%2Flocalhost%3A4000%2Fv1&role=leader"
+        );
+        assert_eq!(uri.to_string().parse(), Ok(uri));
+    }
+
+    #[test]
+    fn test_uri_parse() {
+        let uri: WormholeTransferUri =
+            "WORMHOLE-TRANSFER:4-p%C3%A4ssword?version=0&role=follower&foo=bar"
+                .parse()
+                .unwrap();
+        assert_eq!(uri.code, Code("4-pässword".into()));
+        assert_eq!(uri.rendezvous_server, None);
+        assert!(!uri.is_leader);
+
+        assert_eq!(
+            "wormhole:4-foo".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::Scheme)
+        );
+        assert_eq!(
+            "wormhole-transfer:4-foo?version=1".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::Version("1".into()))
+        );
+        assert_eq!(
+            "wormhole-transfer:4-foo?role=bystander".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::Role("bystander".into()))
+        );
+        assert_eq!(
+            "wormhole-transfer:4-foo%2".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::Encoding)
+        );
+        assert_eq!(
+            "wormhole-transfer:foo".parse::<WormholeTransferUri>(),
+            Err(ParseUriError::Code(ParseCodeError::Nameplate))
+        );
+    }
+}
@@ -12,27 +12,61 @@
 //!
 //! **Notice:** while the resulting TCP connection is naturally bi-directional, the handshake is not symmetric. There *must* be one
 //! "leader" side and one "follower" side (formerly called "sender" and "receiver").
-
-use crate::{Key, KeyPurpose};
+//!
+//! Transit is not tied to the file transfer, you can build your own protocols on top of it:
+//! [`init`] a [`TransitConnector`], send its hints to the other side (for example as a [`TransitType`] over the
+//! [`Wormhole`](crate::Wormhole)), and connect using [`leader_connect`](TransitConnector::leader_connect) or
+//! [`follower_connect`](TransitConnector::follower_connect) with the key from
+//! [`Wormhole::derive_transit_key`](crate::Wormhole::derive_transit_key). The resulting [`Transit`] is an encrypted
+//! record pipe, see [`Transit::send_record`] and [`Transit::receive_record`].
+
+use crate::{Key, KeyPurpose, SecurityError};
 use serde_derive::{Deserialize, Serialize};
 
+use crate::transcript::Direction;
 use async_std::{
     io::{prelude::WriteExt, ReadExt},
     net::{TcpListener, TcpStream},
 };
 #[allow(unused_imports)] /* We need them for the docs */
-use futures::{future::TryFutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use futures::{
+    future::{FutureExt, TryFutureExt},
+    Sink, SinkExt, Stream, StreamExt, TryStreamExt,
+};
 use log::*;
-use std::{collections::HashSet, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+use subtle::ConstantTimeEq;
 use xsalsa20poly1305 as secretbox;
 use xsalsa20poly1305::aead::{Aead, NewAead};
 
+mod websocket;
+
 /// ULR to a default hosted relay server. Please don't abuse or DOS.
 pub const DEFAULT_RELAY_SERVER: &str = "tcp:transit.magic-wormhole.io:4001";
+/// How long relay connections wait by default, to give direct connections a head start.
+///
+/// See [`TransitConnector::set_relay_delay`].
+pub const DEFAULT_RELAY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+/// How often direct connections are attempted while hole punching.
+///
+/// See [`TransitConnector::set_hole_punching`].
+pub const HOLE_PUNCHING_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+/// How large received records may be by default, as sent over the wire (including nonce and authentication tag).
+///
+/// See [`Transit::set_max_record_size`].
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 16 * 1024 * 1024;
 // No need to make public, it's hard-coded anyways (:
 // Open an issue if you want an API for this
 // Use <stun.stunprotocol.org:3478> for non-production testing
+#[cfg(not(test))]
 const PUBLIC_STUN_SERVER: &str = "stun.piegames.de:3478";
+/* The tests stay off the network. Nothing listens on the discard port, so the query fails right away */
+#[cfg(test)]
+const PUBLIC_STUN_SERVER: &str = "127.0.0.1:9";
 
 #[derive(Debug)]
 pub struct TransitKey;
@@ -86,10 +120,16 @@ enum TransitHandshakeError {
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 pub enum TransitError {
-    #[error("Cryptography error. This is probably an implementation bug, but may also be caused by an attack.")]
-    Crypto,
-    #[error("Wrong nonce received, got {:x?} but expected {:x?}. This is probably an implementation bug, but may also be caused by an attack.", _0, _1)]
-    Nonce(Box<[u8]>, Box<[u8]>),
+    /// Received a record that doesn't come from our peer, or could not encrypt one. See [`SecurityError`]
+    #[error("Security error")]
+    Security(
+        #[from]
+        #[source]
+        SecurityError,
+    ),
+    /// The other side sent something it shouldn't have, like a record larger than [`Transit::max_record_size`]
+    #[error("Protocol error: {}", _0)]
+    Protocol(Box<str>),
     #[error("IO error")]
     IO(
         #[from]
@@ -128,6 +168,14 @@ pub enum Ability {
     DirectUdtV1,
     /** Try to meet the other side at a relay. */
     RelayV1,
+    /**
+     * Try to meet the other side at a relay, tunneled over WebSocket.
+     *
+     * Same as [`Ability::RelayV1`], but for relays given as `wss://` (or `ws://`) [`RelayUrl`]s. This gets through
+     * networks which only allow outgoing HTTPS connections. The relay glues both ends together no matter how they
+     * reached it, so the other side may use plain TCP for the same relay.
+     */
+    RelayWebsocketV1,
     /* TODO Fix once https://github.com/serde-rs/serde/issues/912 is done */
     #[serde(other)]
     Other,
@@ -135,7 +183,12 @@ pub enum Ability {
 
 impl Ability {
     pub fn all_abilities() -> Vec<Ability> {
-        vec![Self::DirectTcpV1, Self::DirectUdtV1, Self::RelayV1]
+        vec![
+            Self::DirectTcpV1,
+            Self::DirectUdtV1,
+            Self::RelayV1,
+            Self::RelayWebsocketV1,
+        ]
     }
 
     /**
@@ -157,7 +210,12 @@ impl Ability {
      * the Rust implementation yet).
      */
     pub fn force_relay() -> Vec<Ability> {
-        vec![Self::RelayV1]
+        vec![Self::RelayV1, Self::RelayWebsocketV1]
+    }
+
+    /** Whether this is one of the relay abilities */
+    fn is_relay(&self) -> bool {
+        matches!(self, Self::RelayV1 | Self::RelayWebsocketV1)
     }
 }
 
@@ -165,6 +223,8 @@ impl Ability {
 pub struct Hints {
     pub direct_tcp: HashSet<DirectHint>,
     pub relay: HashSet<DirectHint>,
+    /** URLs of relays for [`Ability::RelayWebsocketV1`] */
+    pub relay_websocket: HashSet<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash, derive_more::Display)]
@@ -199,15 +259,156 @@ impl TryFrom<&DirectHint> for std::net::SocketAddr {
     }
 }
 
+/**
+ * A set of hints for both sides to find each other
+ *
+ * This is the wire format of our [`Hints`] and abilities. The transit protocol does not specify how to deliver
+ * it to the other side: the file transfer wraps it in its own messages, other protocols may send it over the
+ * wormhole however they like.
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TransitType {
+    pub abilities_v1: Vec<Ability>,
+    pub hints_v1: Vec<Hint>,
+}
+
+impl From<Hints> for Vec<Hint> {
+    fn from(hints: Hints) -> Self {
+        hints
+            .direct_tcp
+            .into_iter()
+            .map(Hint::DirectTcpV1)
+            .chain(std::iter::once(Hint::new_relay(hints.relay)))
+            .chain(
+                hints
+                    .relay_websocket
+                    .into_iter()
+                    .map(|url| Hint::RelayWebsocketV1 { url }),
+            )
+            .collect()
+    }
+}
+
+impl From<Vec<Hint>> for Hints {
+    fn from(hints: Vec<Hint>) -> Self {
+        let mut direct_tcp = HashSet::new();
+        let mut relay = HashSet::new();
+        let mut relay_websocket = HashSet::new();
+
+        /* There is only one "relay hint", though it may contain multiple
+         * items. Yes, this is inconsistent and weird, watch your step.
+         */
+        for hint in hints {
+            match hint {
+                Hint::DirectTcpV1(hint) => {
+                    direct_tcp.insert(hint);
+                },
+                Hint::RelayV1(RelayHint { hints }) => relay.extend(hints),
+                Hint::RelayWebsocketV1 { url } => {
+                    relay_websocket.insert(url);
+                },
+                /* Nothing we could connect to */
+                Hint::DirectUdtV1(_) | Hint::Other => {},
+            }
+        }
+
+        Hints {
+            direct_tcp,
+            relay,
+            relay_websocket,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+#[non_exhaustive]
+pub enum Hint {
+    DirectTcpV1(DirectHint),
+    DirectUdtV1(DirectHint),
+    /* Weirdness alarm: a "relay hint" contains multiple "direct hints". This means
+     * that there may be multiple direct hints, but if there are multiple relay hints
+     * it's still only one item because it internally has a list.
+     */
+    RelayV1(RelayHint),
+    /** A relay reachable over WebSocket, see [`Ability::RelayWebsocketV1`] */
+    RelayWebsocketV1 {
+        url: String,
+    },
+    /** Hints of unknown types, from newer clients */
+    #[serde(other)]
+    Other,
+}
+
+impl Hint {
+    pub fn new_direct_tcp(_priority: f32, hostname: &str, port: u16) -> Self {
+        Hint::DirectTcpV1(DirectHint {
+            hostname: hostname.to_string(),
+            port,
+        })
+    }
+
+    pub fn new_direct_udt(_priority: f32, hostname: &str, port: u16) -> Self {
+        Hint::DirectUdtV1(DirectHint {
+            hostname: hostname.to_string(),
+            port,
+        })
+    }
+
+    pub fn new_relay(h: HashSet<DirectHint>) -> Self {
+        Hint::RelayV1(RelayHint {
+            hints: h.into_iter().collect(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RelayHint {
+    pub hints: Vec<DirectHint>,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 enum HostType {
     Direct,
     Relay,
 }
 
+impl From<HostType> for crate::metrics::TransitPath {
+    fn from(host_type: HostType) -> Self {
+        match host_type {
+            HostType::Direct => Self::Direct,
+            HostType::Relay => Self::Relay,
+        }
+    }
+}
+
+/**
+ * A transit relay server, like `tcp:HOSTNAME:PORT`
+ *
+ * Relays which are reachable over WebSocket are given as `wss://HOSTNAME[:PORT][/PATH]` (or `ws://`), see
+ * [`Ability::RelayWebsocketV1`].
+ *
+ * Private relays may require a token, which is given as `tcp:HOSTNAME:PORT?token=TOKEN`. It is sent as
+ * `authenticate TOKEN\n` right after connecting, before the relay handshake. The token is never
+ * included in our hints, so the peer won't learn it. This means that the peer can only meet us on
+ * such a relay if it got the same token by other means, e.g. configured with the same relay URL.
+ */
+#[derive(Clone, Debug)]
 pub struct RelayUrl {
     pub host: String,
     pub port: u16,
+    pub token: Option<String>,
+    /** The whole URL, if this relay is reached over WebSocket. `host` and `port` are taken from it */
+    pub websocket: Option<String>,
+}
+
+impl RelayUrl {
+    /** Authenticate to this relay with the given token. It must not contain any line breaks */
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
 }
 
 impl FromStr for RelayUrl {
@@ -215,12 +416,46 @@ impl FromStr for RelayUrl {
 
     fn from_str(url: &str) -> Result<Self, &'static str> {
         // TODO use proper URL parsing
+        let (url, token) = match url.find('?') {
+            Some(question_mark) => match url[question_mark + 1..].strip_prefix("token=") {
+                Some(token) if !token.is_empty() && !token.contains(char::is_whitespace) => {
+                    (&url[..question_mark], Some(token.to_string()))
+                },
+                _ => return Err("Incorrect relay server token, expected '?token=TOKEN'"),
+            },
+            None => (url, None),
+        };
+        for &(scheme, default_port) in &[("ws://", 80), ("wss://", 443)] {
+            if let Some(rest) = url.strip_prefix(scheme) {
+                let authority = rest.split('/').next().unwrap();
+                let (host, port) = match authority.rfind(':') {
+                    Some(colon) => (
+                        &authority[..colon],
+                        authority[colon + 1..]
+                            .parse()
+                            .map_err(|_| "Cannot parse relay url port")?,
+                    ),
+                    None => (authority, default_port),
+                };
+                if host.is_empty() {
+                    return Err("Incorrect relay server url format");
+                }
+                return Ok(RelayUrl {
+                    host: host.to_string(),
+                    port,
+                    token,
+                    websocket: Some(url.to_string()),
+                });
+            }
+        }
         let v: Vec<&str> = url.split(':').collect();
         if v.len() == 3 && v[0] == "tcp" {
             v[2].parse()
                 .map(|port| RelayUrl {
                     host: v[1].to_string(),
                     port,
+                    token,
+                    websocket: None,
                 })
                 .map_err(|_| "Cannot parse relay url port")
         } else {
@@ -293,12 +528,58 @@ async fn connect_custom(
     Ok(stream.into_inner()?.into())
 }
 
+/**
+ * Keep connecting to `dest_addr` until it works or `window` has passed
+ *
+ * If both sides do this from the port they advertised, their connection attempts will eventually
+ * cross. This opens a direct connection through NATs that drop unsolicited connections (TCP
+ * simultaneous open). The attempts start at multiples of [`HOLE_PUNCHING_INTERVAL`] on the wall
+ * clock, so that peers with synchronized clocks make theirs at the same time. There is no random
+ * jitter on purpose: each attempt stays open for up to one interval, so the attempts of both sides
+ * still overlap if their clocks are off by a few hundred milliseconds.
+ */
+pub(crate) async fn punch_hole(
+    local_addr: &socket2::SockAddr,
+    dest_addr: &socket2::SockAddr,
+    window: std::time::Duration,
+) -> std::io::Result<async_std::net::TcpStream> {
+    let deadline = std::time::Instant::now() + window;
+    loop {
+        let error = match async_std::future::timeout(
+            HOLE_PUNCHING_INTERVAL,
+            connect_custom(local_addr, dest_addr),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => err,
+            Err(_) => std::io::Error::new(std::io::ErrorKind::TimedOut, "Hole punching timed out"),
+        };
+        if std::time::Instant::now() >= deadline {
+            return Err(error);
+        }
+
+        /* Wait for the next slot */
+        let interval = HOLE_PUNCHING_INTERVAL.as_nanos();
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        async_std::task::sleep(std::time::Duration::from_nanos(
+            (interval - since_epoch % interval) as u64,
+        ))
+        .await;
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 enum StunError {
     #[error("No V4 addresses were found for the selected STUN server")]
     ServerIsV4Only,
     #[error("Connection timed out")]
     Timeout,
+    #[error("The STUN response did not contain our address")]
+    NoAddress,
     #[error("IO error")]
     IO(
         #[from]
@@ -365,11 +646,13 @@ async fn get_external_ip() -> Result<(std::net::SocketAddr, TcpStream), StunErro
         Ok(bytes)
     }
 
-    fn decode_address(buf: &[u8]) -> Result<SocketAddr, bytecodec::Error> {
+    fn decode_address(buf: &[u8]) -> Result<SocketAddr, StunError> {
         let mut decoder = MessageDecoder::<Attribute>::new();
-        let decoded = decoder.decode_from_bytes(buf)??;
+        let decoded = decoder
+            .decode_from_bytes(buf)?
+            .map_err(bytecodec::Error::from)?;
 
-        println!("Decoded message: {:?}", decoded);
+        log::trace!("Decoded STUN message: {:?}", decoded);
 
         let external_addr1 = decoded
             .get_attribute::<XorMappedAddress>()
@@ -381,9 +664,7 @@ async fn get_external_ip() -> Result<(std::net::SocketAddr, TcpStream), StunErro
         let external_addr = external_addr1
             // .or(external_addr2)
             .or(external_addr3);
-        let external_addr = external_addr.unwrap();
-
-        Ok(external_addr)
+        external_addr.ok_or(StunError::NoAddress)
     }
 
     /* Connect the plugs */
@@ -405,10 +686,12 @@ async fn get_external_ip() -> Result<(std::net::SocketAddr, TcpStream), StunErro
  * Initialize a relay handshake
  *
  * Bind a port and generate our [`Hints`]. This does not do any communication yet.
+ * All `relay_urls` are advertised to the peer and tried when connecting, so one
+ * unreachable relay server does not break the transfer.
  */
 pub async fn init(
     abilities: Vec<Ability>,
-    relay_url: &RelayUrl,
+    relay_urls: &[RelayUrl],
 ) -> Result<TransitConnector, std::io::Error> {
     let mut our_hints = Hints::default();
     let mut listener = None;
@@ -436,16 +719,7 @@ pub async fn init(
                 // https://github.com/rust-lang/rust/issues/70142
                 Err(err) | Ok(Err(err)) => {
                     log::debug!("Failed to get external address via STUN, {}", err);
-                    let socket =
-                        socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)
-                            .unwrap();
-                    set_socket_opts(&socket)?;
-
-                    socket
-                        .bind(&"[::]:0".parse::<std::net::SocketAddr>().unwrap().into())
-                        .unwrap();
-
-                    socket.into()
+                    bind_socket()?.into()
                 },
             };
 
@@ -460,8 +734,16 @@ pub async fn init(
         /* Find our ports, iterate all our local addresses, combine them with the ports and that's our hints */
         let port = socket.local_addr()?.as_socket().unwrap().port();
         let port2 = socket2.local_addr()?.port();
+        /* Not being able to list our interfaces only costs us the direct hints; the relay still works */
+        let interfaces = get_if_addrs::get_if_addrs().unwrap_or_else(|err| {
+            log::warn!("Failed to list network interfaces, {}", err);
+            Vec::new()
+        });
+        if interfaces.iter().all(|iface| iface.is_loopback()) {
+            log::warn!("No usable network interfaces found, only the relay server will be offered");
+        }
         our_hints.direct_tcp.extend(
-            get_if_addrs::get_if_addrs()?
+            interfaces
                 .iter()
                 .filter(|iface| !iface.is_loopback())
                 .flat_map(|ip|
@@ -481,20 +763,51 @@ pub async fn init(
         listener = Some((socket, socket2));
     }
 
-    if abilities.contains(&Ability::RelayV1) {
-        our_hints.relay.insert(DirectHint {
-            hostname: relay_url.host.clone(),
-            port: relay_url.port,
-        });
+    /* Keyed by the relay's hint (as string) or WebSocket URL */
+    let mut relay_tokens = HashMap::new();
+    for relay_url in relay_urls {
+        let key = match &relay_url.websocket {
+            Some(url) if abilities.contains(&Ability::RelayWebsocketV1) => {
+                our_hints.relay_websocket.insert(url.clone());
+                url.clone()
+            },
+            None if abilities.contains(&Ability::RelayV1) => {
+                let hint = DirectHint {
+                    hostname: relay_url.host.clone(),
+                    port: relay_url.port,
+                };
+                let key = hint.to_string();
+                our_hints.relay.insert(hint);
+                key
+            },
+            _ => continue,
+        };
+        if let Some(token) = &relay_url.token {
+            relay_tokens.insert(key, token.clone());
+        }
     }
 
     Ok(TransitConnector {
         sockets: listener,
         our_abilities: Arc::new(abilities),
         our_hints: Arc::new(our_hints),
+        relay_tokens: Arc::new(relay_tokens),
+        relay_delay: DEFAULT_RELAY_DELAY,
+        hole_punching: None,
+        side: Arc::new(hex::encode(rand::random::<[u8; 8]>())),
     })
 }
 
+/** A new socket on some free port, for connecting to the other side */
+fn bind_socket() -> Result<socket2::Socket, std::io::Error> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::STREAM, None)?;
+    set_socket_opts(&socket)?;
+
+    socket.bind(&"[::]:0".parse::<std::net::SocketAddr>().unwrap().into())?;
+
+    Ok(socket)
+}
+
 #[derive(derive_more::From)]
 enum MaybeConnectedSocket {
     #[from]
@@ -527,18 +840,125 @@ pub struct TransitConnector {
     sockets: Option<(MaybeConnectedSocket, TcpListener)>,
     our_abilities: Arc<Vec<Ability>>,
     our_hints: Arc<Hints>,
+    /* Only for our own relays, they don't go into the hints */
+    relay_tokens: Arc<HashMap<String, String>>,
+    relay_delay: std::time::Duration,
+    hole_punching: Option<std::time::Duration>,
+    /* Our side for the relay handshake, hex encoded */
+    side: Arc<String>,
 }
 
 impl TransitConnector {
+    /**
+     * Another connector like this one, for one more connection to the same peer
+     *
+     * It listens on new ports, but offers the same addresses and relay servers as this one. Unlike [`init`],
+     * it does not do a STUN query, so it is ready right away. The settings are copied as well.
+     */
+    pub async fn another(&self) -> Result<TransitConnector, std::io::Error> {
+        let mut our_hints = Hints {
+            direct_tcp: HashSet::new(),
+            ..(*self.our_hints).clone()
+        };
+        let sockets = match &self.sockets {
+            Some(_) => {
+                let socket = bind_socket()?;
+                let socket2 = TcpListener::bind("[::]:0").await?;
+                let port = socket.local_addr()?.as_socket().unwrap().port();
+                let port2 = socket2.local_addr()?.port();
+                let hostnames = self
+                    .our_hints
+                    .direct_tcp
+                    .iter()
+                    .map(|hint| &hint.hostname)
+                    .collect::<HashSet<_>>();
+                for hostname in hostnames {
+                    our_hints.direct_tcp.insert(DirectHint {
+                        hostname: hostname.clone(),
+                        port,
+                    });
+                    our_hints.direct_tcp.insert(DirectHint {
+                        hostname: hostname.clone(),
+                        port: port2,
+                    });
+                }
+                Some((socket.into(), socket2))
+            },
+            None => None,
+        };
+
+        Ok(TransitConnector {
+            sockets,
+            our_abilities: self.our_abilities.clone(),
+            our_hints: Arc::new(our_hints),
+            relay_tokens: self.relay_tokens.clone(),
+            relay_delay: self.relay_delay,
+            hole_punching: self.hole_punching,
+            side: self.side.clone(),
+        })
+    }
+
+    /**
+     * Give direct connections a head start over relayed ones
+     *
+     * If both sides can connect directly, connecting to the relay server is delayed by this amount. Usually,
+     * direct connections will have succeeded by then (if they work at all), which reduces the load on the relay
+     * and gives a faster connection. Defaults to [`DEFAULT_RELAY_DELAY`].
+     */
+    pub fn set_relay_delay(&mut self, relay_delay: std::time::Duration) {
+        self.relay_delay = relay_delay;
+    }
+
+    /**
+     * Retry direct connections for some time, to get through NATs
+     *
+     * Usually, each hint of the other side is tried once. With hole punching, failed attempts are
+     * repeated every [`HOLE_PUNCHING_INTERVAL`] until `window` has passed. If the other side does the
+     * same, the attempts of both sides will eventually cross and open a connection through NATs that
+     * would otherwise drop it. This only helps if both sides enable it, and you probably want to
+     * increase the [relay delay](Self::set_relay_delay) to match. The attempts are aligned to the wall
+     * clock, so both sides' clocks should agree within a few hundred milliseconds. Disabled by default.
+     */
+    pub fn set_hole_punching(&mut self, window: Option<std::time::Duration>) {
+        self.hole_punching = window;
+    }
+
+    /**
+     * Use a fixed transit side instead of a random one
+     *
+     * The side identifies us to the relay server. This is meant for reproducible tests, the two peers
+     * must not use the same side.
+     */
+    #[cfg(any(test, feature = "testing"))]
+    pub fn set_side(&mut self, side: [u8; 8]) {
+        self.side = Arc::new(hex::encode(side));
+    }
+
     pub fn our_abilities(&self) -> &Arc<Vec<Ability>> {
         &self.our_abilities
     }
 
-    /** Send this one to the other side */
+    /** All our hints, see [`our_hints_for`](Self::our_hints_for) for what to send to the other side */
     pub fn our_hints(&self) -> &Arc<Hints> {
         &self.our_hints
     }
 
+    /**
+     * Our hints, as they should be sent to the other side
+     *
+     * Older clients fail on hint types they don't know, so the [`Ability::RelayWebsocketV1`] ones are only
+     * included if `their_abilities` contain it. Pass `None` if we send first and don't know them yet.
+     */
+    pub fn our_hints_for(&self, their_abilities: Option<&[Ability]>) -> Vec<Hint> {
+        let mut hints = (*self.our_hints).clone();
+        if !their_abilities.map_or(false, |abilities| {
+            abilities.contains(&Ability::RelayWebsocketV1)
+        }) {
+            hints.relay_websocket.clear();
+        }
+        hints.into()
+    }
+
     /**
      * Connect to the other side, as sender.
      */
@@ -548,36 +968,27 @@ impl TransitConnector {
         their_abilities: Arc<Vec<Ability>>,
         their_hints: Arc<Hints>,
     ) -> Result<Transit, TransitConnectError> {
-        let Self {
-            sockets,
-            our_abilities,
-            our_hints,
-        } = self;
+        let our_abilities = self.our_abilities.clone();
         let transit_key = Arc::new(transit_key);
 
         let start = std::time::Instant::now();
         let mut connection_stream = Box::pin(
-            Self::connect(
-                true,
-                transit_key,
-                our_abilities.clone(),
-                our_hints,
-                their_abilities,
-                their_hints,
-                sockets,
-            )
-            .filter_map(|result| async {
-                match result {
-                    Ok(val) => Some(val),
-                    Err(err) => {
-                        log::debug!("Some leader handshake failed: {:?}", err);
-                        None
-                    },
-                }
-            }),
+            self.connect(true, transit_key, their_abilities, their_hints)
+                .await
+                .filter_map(|result| async {
+                    match result {
+                        Ok(val) => Some(val),
+                        Err(err) => {
+                            log::debug!("Some leader handshake failed: {:?}", err);
+                            crate::metrics::metrics()
+                                .handshake_failed(crate::metrics::HandshakeKind::Transit);
+                            None
+                        },
+                    }
+                }),
         );
 
-        let (mut transit, host_type) = async_std::future::timeout(
+        let (mut transit, mut host_type) = async_std::future::timeout(
             std::time::Duration::from_secs(60),
             connection_stream.next(),
         )
@@ -606,9 +1017,14 @@ impl TransitConnector {
                 while let Some((new_transit, new_host_type)) = connection_stream.next().await {
                     /* We already got a connection, so we're only interested in direct ones */
                     if new_host_type == HostType::Direct {
-                        transit = new_transit;
+                        std::mem::replace(&mut transit, new_transit)
+                            .nevermind()
+                            .await;
+                        host_type = new_host_type;
                         log::debug!("Found direct connection; using that instead.");
                         break;
+                    } else {
+                        new_transit.nevermind().await;
                     }
                 }
             })
@@ -618,16 +1034,22 @@ impl TransitConnector {
             log::debug!("Established direct transit connection");
         }
 
-        /* Cancel all remaining non-finished handshakes. We could send "nevermind" to explicitly tell
-         * the other side (probably, this is mostly for relay server statistics), but eeh, nevermind :)
+        /* Tell the losers that already finished their handshake that we picked somebody else,
+         * and cancel all remaining non-finished handshakes.
          */
+        while let Some(Some((loser, _))) = connection_stream.next().now_or_never() {
+            loser.nevermind().await;
+        }
         std::mem::drop(connection_stream);
 
+        /* Only the winner gets a "go". */
+        record(Direction::Sent, b"go\n");
         transit.socket.write_all(b"go\n").await?;
-        info!(
-            "Established transit connection to '{}'",
-            transit.socket.peer_addr().unwrap()
-        );
+        crate::metrics::metrics().transit_established(host_type.into());
+        match transit.socket.peer_addr() {
+            Some(addr) => info!("Established transit connection to '{}'", addr),
+            None => info!("Established transit connection ({:?})", host_type),
+        }
 
         Ok(transit)
     }
@@ -641,32 +1063,22 @@ impl TransitConnector {
         their_abilities: Arc<Vec<Ability>>,
         their_hints: Arc<Hints>,
     ) -> Result<Transit, TransitConnectError> {
-        let Self {
-            sockets,
-            our_abilities,
-            our_hints,
-        } = self;
         let transit_key = Arc::new(transit_key);
 
         let mut connection_stream = Box::pin(
-            Self::connect(
-                false,
-                transit_key,
-                our_abilities,
-                our_hints,
-                their_abilities,
-                their_hints,
-                sockets,
-            )
-            .filter_map(|result| async {
-                match result {
-                    Ok(val) => Some(val),
-                    Err(err) => {
-                        log::debug!("Some follower handshake failed: {:?}", err);
-                        None
-                    },
-                }
-            }),
+            self.connect(false, transit_key, their_abilities, their_hints)
+                .await
+                .filter_map(|result| async {
+                    match result {
+                        Ok(val) => Some(val),
+                        Err(err) => {
+                            log::debug!("Some follower handshake failed: {:?}", err);
+                            crate::metrics::metrics()
+                                .handshake_failed(crate::metrics::HandshakeKind::Transit);
+                            None
+                        },
+                    }
+                }),
         );
 
         let transit = match async_std::future::timeout(
@@ -676,6 +1088,7 @@ impl TransitConnector {
         .await
         {
             Ok(Some((transit, host_type))) => {
+                crate::metrics::metrics().transit_established(host_type.into());
                 log::debug!(
                     "Established a {} transit connection.",
                     if host_type == HostType::Direct {
@@ -709,19 +1122,63 @@ impl TransitConnector {
      * If the receiving end of the channel for the results is closed before all futures in the return
      * value are cancelled/dropped.
      */
-    fn connect(
+    async fn connect(
+        self,
         is_leader: bool,
         transit_key: Arc<Key<TransitKey>>,
-        our_abilities: Arc<Vec<Ability>>,
-        our_hints: Arc<Hints>,
         their_abilities: Arc<Vec<Ability>>,
         their_hints: Arc<Hints>,
-        socket: Option<(MaybeConnectedSocket, TcpListener)>,
     ) -> impl Stream<Item = Result<(Transit, HostType), TransitHandshakeError>> + 'static {
+        let Self {
+            sockets: socket,
+            our_abilities,
+            our_hints,
+            relay_tokens,
+            relay_delay,
+            hole_punching,
+            side: tside,
+        } = self;
         assert!(socket.is_some() == our_abilities.contains(&Ability::DirectTcpV1));
 
-        // 8. listen for connections on the port and simultaneously try connecting to the peer port.
-        let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
+        /* Don't connect to the same address twice, and not to ourselves either */
+        let our_addresses = resolve_hints(&our_hints.direct_tcp).await;
+        let direct_addresses = resolve_hints(&their_hints.direct_tcp)
+            .await
+            .into_iter()
+            .filter(|addr| !our_addresses.contains(addr))
+            /* Nobody should have that many IP addresses, even with NATing */
+            .take(10)
+            .collect::<Vec<_>>();
+
+        /* Skip relays that we already know by another name */
+        let mut relay_addresses = Vec::new();
+        let mut relay_hints = Vec::new();
+        for hint in our_hints
+            .relay
+            .iter()
+            .chain(their_hints.relay.iter().take(4))
+        {
+            let addresses = resolve_hints(std::iter::once(hint)).await;
+            if addresses.iter().any(|addr| relay_addresses.contains(addr)) {
+                log::debug!("Skipping duplicate relay {}", hint);
+                continue;
+            }
+            relay_addresses.extend(addresses);
+            relay_hints.push(hint.clone());
+        }
+        let websocket_relays = our_hints
+            .relay_websocket
+            .iter()
+            .chain(their_hints.relay_websocket.iter().take(4))
+            .cloned()
+            .collect::<HashSet<_>>();
+
+        /* Only wait for direct connections if there are any to wait for */
+        let relay_delay = if socket.is_some() && !direct_addresses.is_empty() {
+            relay_delay
+        } else {
+            std::time::Duration::from_secs(0)
+        };
 
         /* Iterator of futures yielding a connection. They'll be then mapped with the handshake, collected into
          * a Vec and polled concurrently.
@@ -729,7 +1186,7 @@ impl TransitConnector {
         use futures::future::BoxFuture;
         type BoxIterator<T> = Box<dyn Iterator<Item = T>>;
         type ConnectorFuture =
-            BoxFuture<'static, Result<(TcpStream, HostType), TransitHandshakeError>>;
+            BoxFuture<'static, Result<(TransitStream, HostType), TransitHandshakeError>>;
         let mut connectors: BoxIterator<ConnectorFuture> = Box::new(std::iter::empty());
 
         /* Create direct connection sockets, if we support it. If peer doesn't support it, their list of hints will
@@ -740,20 +1197,20 @@ impl TransitConnector {
             /* Connect to each hint of the peer */
             connectors = Box::new(
                 connectors.chain(
-                    their_hints
-                        .direct_tcp
-                        .clone()
+                    direct_addresses
                         .into_iter()
-                        /* Nobody should have that many IP addresses, even with NATing */
-                        .take(10)
-                        .map(move |hint| {
+                        .map(move |dest_addr| {
                             let local_addr = local_addr.clone();
                             async move {
-                                let dest_addr = std::net::SocketAddr::try_from(&hint)?;
                                 log::debug!("Connecting directly to {}", dest_addr);
-                                let socket = connect_custom(&local_addr, &dest_addr.into()).await?;
+                                let socket = match hole_punching {
+                                    Some(window) => {
+                                        punch_hole(&local_addr, &dest_addr.into(), window).await?
+                                    },
+                                    None => connect_custom(&local_addr, &dest_addr.into()).await?,
+                                };
                                 log::debug!("Connected to {}!", dest_addr);
-                                Ok((socket, HostType::Direct))
+                                Ok((socket.into(), HostType::Direct))
                             }
                         })
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
@@ -764,29 +1221,65 @@ impl TransitConnector {
             None
         };
 
-        /* Relay hints. Make sure that both sides adverize it, since it is fine to support it without providing own hints. */
-        if our_abilities.contains(&Ability::RelayV1) && their_abilities.contains(&Ability::RelayV1)
-        {
-            /* Collect intermediate into HashSet for deduplication */
-            let relay_hints = our_hints
-                .relay
-                .clone()
-                .into_iter()
-                .take(2)
-                .chain(their_hints.relay.clone().into_iter().take(2))
-                .collect::<HashSet<DirectHint>>();
+        /* Relay hints. Make sure that both sides adverize it, since it is fine to support it without providing own hints.
+         * It does not matter how the other side gets to the relay.
+         */
+        let they_relay = their_abilities.iter().any(Ability::is_relay);
+        if our_abilities.contains(&Ability::RelayWebsocketV1) && they_relay {
+            let relay_tokens = relay_tokens.clone();
+            connectors = Box::new(
+                connectors.chain(
+                    websocket_relays
+                        .into_iter()
+                        .map(move |url| {
+                            let token = relay_tokens.get(&url).cloned();
+                            async move {
+                                if relay_delay > std::time::Duration::from_secs(0) {
+                                    async_std::task::sleep(relay_delay).await;
+                                }
+                                log::debug!("Connecting to relay {}", url);
+                                let mut transit = websocket::connect(&url).await?;
+                                log::debug!("Connected to {}!", url);
+                                if let Some(token) = token {
+                                    /* Not recorded in the transcript, it's a secret */
+                                    transit
+                                        .write_all(format!("authenticate {}\n", token).as_bytes())
+                                        .await?;
+                                }
+
+                                Ok((transit.into(), HostType::Relay))
+                            }
+                        })
+                        .map(|fut| Box::pin(fut) as ConnectorFuture),
+                ),
+            ) as BoxIterator<ConnectorFuture>;
+        }
+        if our_abilities.contains(&Ability::RelayV1) && they_relay {
             connectors = Box::new(
                 connectors.chain(
                     relay_hints
                         .into_iter()
-                        .map(|host| async move {
-                            log::debug!("Connecting to relay {}", host);
-                            let transit = TcpStream::connect((host.hostname.as_str(), host.port))
-                                .err_into::<TransitHandshakeError>()
-                                .await?;
-                            log::debug!("Connected to {}!", host);
-
-                            Ok((transit, HostType::Relay))
+                        .map(move |host| {
+                            let token = relay_tokens.get(&host.to_string()).cloned();
+                            async move {
+                                if relay_delay > std::time::Duration::from_secs(0) {
+                                    async_std::task::sleep(relay_delay).await;
+                                }
+                                log::debug!("Connecting to relay {}", host);
+                                let mut transit =
+                                    TcpStream::connect((host.hostname.as_str(), host.port))
+                                        .err_into::<TransitHandshakeError>()
+                                        .await?;
+                                log::debug!("Connected to {}!", host);
+                                if let Some(token) = token {
+                                    /* Not recorded in the transcript, it's a secret */
+                                    transit
+                                        .write_all(format!("authenticate {}\n", token).as_bytes())
+                                        .await?;
+                                }
+
+                                Ok((transit.into(), HostType::Relay))
+                            }
                         })
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
                 ),
@@ -828,7 +1321,7 @@ impl TransitConnector {
                             let transit = handshake_exchange(
                                 is_leader,
                                 tside.clone(),
-                                stream,
+                                stream.into(),
                                 HostType::Direct,
                                 transit_key.clone(),
                             )
@@ -860,6 +1353,260 @@ impl TransitConnector {
     }
 }
 
+/** Put a handshake line into the [`transcript`](crate::transcript) */
+fn record(direction: Direction, line: &[u8]) {
+    crate::transcript::record(
+        crate::transcript::Channel::Transit,
+        direction,
+        &String::from_utf8_lossy(line),
+    );
+}
+
+/**
+ * Resolve some hints, dropping duplicate addresses and hints that fail to resolve
+ *
+ * Like the `TryFrom<&DirectHint>` implementation, this maps all IPv4 addresses into IPv6.
+ */
+async fn resolve_hints<'a>(
+    hints: impl IntoIterator<Item = &'a DirectHint>,
+) -> Vec<std::net::SocketAddr> {
+    use async_std::net::ToSocketAddrs;
+
+    let mut addresses = Vec::new();
+    for hint in hints {
+        let resolved = match (hint.hostname.as_str(), hint.port).to_socket_addrs().await {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                log::debug!("Could not resolve {}, skipping: {}", hint, err);
+                continue;
+            },
+        };
+        for addr in resolved {
+            let addr = match addr {
+                std::net::SocketAddr::V4(v4) => std::net::SocketAddr::new(
+                    std::net::IpAddr::V6(v4.ip().to_ipv6_mapped()),
+                    v4.port(),
+                ),
+                std::net::SocketAddr::V6(_) => addr,
+            };
+            if !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+    }
+    addresses
+}
+
+/**
+ * Connect two transit endpoints to each other over the loopback interface
+ *
+ * Nothing besides the local machine is involved: no rendezvous server, no relay. Both sides do the regular
+ * handshake with a random key, and then talk over the encrypted record layer as usual. This is useful to
+ * measure its throughput, or for tests. Returns the leader and the follower side.
+ */
+pub async fn loopback() -> Result<(Transit, Transit), TransitConnectError> {
+    let key = Arc::new(Key::<TransitKey>::new(Box::new(
+        secretbox::Key::clone_from_slice(&rand::random::<[u8; 32]>()),
+    )));
+    let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let (leader_socket, (follower_socket, _)) =
+        futures::try_join!(TcpStream::connect(address), listener.accept())?;
+
+    let leader = async {
+        let mut transit = handshake_exchange(
+            true,
+            tside.clone(),
+            leader_socket.into(),
+            HostType::Direct,
+            key.clone(),
+        )
+        .await?;
+        record(Direction::Sent, b"go\n");
+        transit.socket.write_all(b"go\n").await?;
+        Ok::<_, TransitHandshakeError>(transit)
+    };
+    let follower = handshake_exchange(
+        false,
+        tside.clone(),
+        follower_socket.into(),
+        HostType::Direct,
+        key.clone(),
+    );
+    futures::try_join!(leader, follower).map_err(|error: TransitHandshakeError| {
+        log::debug!("Loopback handshake failed: {:?}", error);
+        TransitConnectError::Handshake
+    })
+}
+
+/**
+ * The authenticated encryption used for the records of a [`Transit`]
+ *
+ * Both sides must use the same one, and they must agree on it before sending any record
+ * (for example via the [`Wormhole`](crate::Wormhole)'s version exchange). See [`Transit::set_record_cipher`].
+ */
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum RecordCipher {
+    /** NaCl's secretbox with 24 byte nonces. This is the default, and what every client supports */
+    XSalsa20Poly1305,
+    /**
+     * ChaCha20-Poly1305 (RFC 8439) with 12 byte nonces
+     *
+     * This is faster on platforms without optimized Salsa20 implementations. The records look the same,
+     * except for the shorter nonce: it is the last twelve bytes of the big endian record counter.
+     */
+    ChaCha20Poly1305,
+}
+
+impl Default for RecordCipher {
+    fn default() -> Self {
+        RecordCipher::XSalsa20Poly1305
+    }
+}
+
+impl RecordCipher {
+    /** The number of nonce bytes that precede each record on the wire */
+    fn nonce_size(self) -> usize {
+        match self {
+            RecordCipher::XSalsa20Poly1305 => secretbox::NONCE_SIZE,
+            RecordCipher::ChaCha20Poly1305 => 12,
+        }
+    }
+
+    /** The part of the record counter that is sent along */
+    fn wire_nonce(self, nonce: &secretbox::Nonce) -> &[u8] {
+        &nonce[nonce.len() - self.nonce_size()..]
+    }
+
+    fn encrypt(
+        self,
+        key: &secretbox::Key,
+        nonce: &secretbox::Nonce,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, xsalsa20poly1305::aead::Error> {
+        match self {
+            RecordCipher::XSalsa20Poly1305 => {
+                secretbox::XSalsa20Poly1305::new(key).encrypt(nonce, plaintext)
+            },
+            RecordCipher::ChaCha20Poly1305 => {
+                chacha20poly1305::ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+                    .encrypt(
+                        chacha20poly1305::Nonce::from_slice(self.wire_nonce(nonce)),
+                        plaintext,
+                    )
+            },
+        }
+    }
+
+    fn decrypt(
+        self,
+        key: &secretbox::Key,
+        nonce: &secretbox::Nonce,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, xsalsa20poly1305::aead::Error> {
+        match self {
+            RecordCipher::XSalsa20Poly1305 => {
+                secretbox::XSalsa20Poly1305::new(key).decrypt(nonce, ciphertext)
+            },
+            RecordCipher::ChaCha20Poly1305 => {
+                chacha20poly1305::ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+                    .decrypt(
+                        chacha20poly1305::Nonce::from_slice(self.wire_nonce(nonce)),
+                        ciphertext,
+                    )
+            },
+        }
+    }
+}
+
+/** The connection underneath a [`Transit`] */
+enum TransitStream {
+    Tcp(TcpStream),
+    Websocket(Box<websocket::WebsocketStream>),
+}
+
+impl From<TcpStream> for TransitStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<websocket::WebsocketStream> for TransitStream {
+    fn from(stream: websocket::WebsocketStream) -> Self {
+        Self::Websocket(Box::new(stream))
+    }
+}
+
+impl TransitStream {
+    /** The address of the other end, unless it is hidden behind a WebSocket */
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            Self::Websocket(_) => None,
+        }
+    }
+
+    /** Close the connection right away. Errors are irrelevant */
+    fn shutdown(&self) {
+        match self {
+            Self::Tcp(stream) => {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            },
+            /* Needs `&mut`, but it gets dropped right after anyways, which closes it */
+            Self::Websocket(_) => {},
+        }
+    }
+}
+
+impl futures::io::AsyncRead for TransitStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => std::pin::Pin::new(stream).poll_read(cx, buf),
+            Self::Websocket(stream) => std::pin::Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl futures::io::AsyncWrite for TransitStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => std::pin::Pin::new(stream).poll_write(cx, buf),
+            Self::Websocket(stream) => std::pin::Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => std::pin::Pin::new(stream).poll_flush(cx),
+            Self::Websocket(stream) => std::pin::Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => std::pin::Pin::new(stream).poll_close(cx),
+            Self::Websocket(stream) => std::pin::Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
 /**
  * An established Transit connection.
  *
@@ -868,7 +1615,7 @@ impl TransitConnector {
  */
 pub struct Transit {
     /** Raw transit connection */
-    socket: TcpStream,
+    socket: TransitStream,
     /** Our key, used for sending */
     pub skey: Key<TransitTxKey>,
     /** Their key, used for receiving */
@@ -881,64 +1628,146 @@ pub struct Transit {
      * We'll count as receiver and track if messages come in in order
      */
     pub rnonce: secretbox::Nonce,
+    /** The encryption of the records, in both directions */
+    cipher: RecordCipher,
+    max_record_size: usize,
 }
 
 impl Transit {
+    /** As the leader, reject a connection that did not win. Errors are irrelevant, we don't want it anyways */
+    async fn nevermind(mut self) {
+        record(Direction::Sent, b"nevermind\n");
+        let _ = self.socket.write_all(b"nevermind\n").await;
+        self.socket.shutdown();
+    }
+
+    /** The encryption currently used for the records */
+    pub fn record_cipher(&self) -> RecordCipher {
+        self.cipher
+    }
+
+    /**
+     * Change the encryption of the records
+     *
+     * Both sides must switch to the same cipher before the first record is sent, since the records
+     * themselves don't say how they are encrypted. The record keys stay the same, each connection
+     * only ever uses one cipher with them.
+     */
+    pub fn set_record_cipher(&mut self, cipher: RecordCipher) {
+        debug_assert!(
+            self.snonce == Default::default() && self.rnonce == Default::default(),
+            "The record cipher must not change after the first record"
+        );
+        self.cipher = cipher;
+    }
+
+    /** The largest record we accept, see [`set_max_record_size`](Self::set_max_record_size) */
+    pub fn max_record_size(&self) -> usize {
+        self.max_record_size
+    }
+
+    /**
+     * Reject larger records
+     *
+     * Records announce their length up front, which would otherwise be trusted up to 4 GiB. Larger ones
+     * fail with [`TransitError::Protocol`] before anything is allocated for them. The limit applies to the records
+     * as sent over the wire, i.e. including nonce and authentication tag. Defaults to [`DEFAULT_MAX_RECORD_SIZE`].
+     */
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.max_record_size = max_record_size;
+    }
+
     /** Receive and decrypt one message from the other side. */
     pub async fn receive_record(&mut self) -> Result<Box<[u8]>, TransitError> {
-        Transit::receive_record_inner(&mut self.socket, &self.rkey, &mut self.rnonce).await
+        Transit::receive_record_inner(
+            &mut self.socket,
+            &self.rkey,
+            &mut self.rnonce,
+            self.cipher,
+            self.max_record_size,
+        )
+        .await
     }
 
     async fn receive_record_inner(
         socket: &mut (impl futures::io::AsyncRead + Unpin),
         rkey: &Key<TransitRxKey>,
         nonce: &mut secretbox::Nonce,
+        cipher: RecordCipher,
+        max_record_size: usize,
     ) -> Result<Box<[u8]>, TransitError> {
+        use std::io::{Error, ErrorKind};
         let enc_packet = {
             // 1. read 4 bytes from the stream. This represents the length of the encrypted packet.
             let length = {
                 let mut length_arr: [u8; 4] = [0; 4];
                 socket.read_exact(&mut length_arr[..]).await?;
-                u32::from_be_bytes(length_arr) as usize
+                u32::from_be_bytes(length_arr)
             };
+            let length_usize = usize::try_from(length)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "record too large"))?;
+            ensure!(
+                length_usize <= max_record_size,
+                TransitError::Protocol(
+                    format!(
+                        "Record of {} bytes is larger than the limit of {} bytes",
+                        length, max_record_size
+                    )
+                    .into()
+                )
+            );
 
             // 2. read that many bytes into an array (or a vector?)
-            let mut buffer = Vec::with_capacity(length);
-            let len = socket.take(length as u64).read_to_end(&mut buffer).await?;
-            use std::io::{Error, ErrorKind};
+            let mut buffer = Vec::with_capacity(length_usize);
+            let len = socket
+                .take(u64::from(length))
+                .read_to_end(&mut buffer)
+                .await?;
             ensure!(
-                len == length,
+                len == length_usize,
                 Error::new(ErrorKind::UnexpectedEof, "failed to read whole message")
             );
             buffer
         };
+        ensure!(
+            enc_packet.len() >= cipher.nonce_size(),
+            Error::new(ErrorKind::InvalidData, "record too short")
+        );
 
         // 3. decrypt the vector 'enc_packet' with the key.
         let plaintext = {
-            let (received_nonce, ciphertext) = enc_packet.split_at(secretbox::NONCE_SIZE);
-            {
-                // Nonce check
-                ensure!(
-                    nonce.as_slice() == received_nonce,
-                    TransitError::Nonce(received_nonce.into(), nonce.as_slice().into()),
-                );
+            let (received_nonce, ciphertext) = enc_packet.split_at(cipher.nonce_size());
+            // Nonce check: records must come in order, without replays or gaps
+            let expected_nonce = cipher.wire_nonce(nonce);
+            ensure!(
+                expected_nonce == received_nonce,
+                SecurityError::Nonce(received_nonce.into(), expected_nonce.into()),
+            );
 
-                crate::util::sodium_increment_be(nonce);
-            }
+            let plaintext = cipher
+                .decrypt(secretbox::Key::from_slice(rkey), nonce, ciphertext)
+                /* TODO replace with (SecurityError::Decryption) after the next xsalsa20poly1305 update */
+                .map_err(|_| SecurityError::Decryption)?;
 
-            let cipher = secretbox::XSalsa20Poly1305::new(secretbox::Key::from_slice(rkey));
-            cipher
-                .decrypt(secretbox::Nonce::from_slice(received_nonce), ciphertext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
+            /* Only count records that actually came from the other side */
+            crate::util::sodium_increment_be(nonce);
+            plaintext
         };
 
+        crate::metrics::metrics().transit_bytes_received(plaintext.len() as u64);
         Ok(plaintext.into_boxed_slice())
     }
 
     /** Send an encrypted message to the other side */
     pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
-        Transit::send_record_inner(&mut self.socket, &self.skey, plaintext, &mut self.snonce).await
+        Transit::send_record_inner(
+            &mut self.socket,
+            &self.skey,
+            plaintext,
+            &mut self.snonce,
+            self.cipher,
+        )
+        .await
     }
 
     async fn send_record_inner(
@@ -946,28 +1775,25 @@ impl Transit {
         skey: &Key<TransitTxKey>,
         plaintext: &[u8],
         nonce: &mut secretbox::Nonce,
+        cipher: RecordCipher,
     ) -> Result<(), TransitError> {
-        let sodium_key = secretbox::Key::from_slice(skey);
-
-        let ciphertext = {
-            let nonce_le = secretbox::Nonce::from_slice(nonce);
-
-            let cipher = secretbox::XSalsa20Poly1305::new(sodium_key);
-            cipher
-                .encrypt(nonce_le, plaintext)
-                /* TODO replace with (TransitError::Crypto) after the next xsalsa20poly1305 update */
-                .map_err(|_| TransitError::Crypto)?
-        };
+        let ciphertext = cipher
+            .encrypt(secretbox::Key::from_slice(skey), nonce, plaintext)
+            /* TODO replace with (SecurityError::Encryption) after the next xsalsa20poly1305 update */
+            .map_err(|_| SecurityError::Encryption)?;
 
         // send the encrypted record
-        socket
-            .write_all(&((ciphertext.len() + nonce.len()) as u32).to_be_bytes())
-            .await?;
-        socket.write_all(nonce).await?;
+        let wire_nonce = cipher.wire_nonce(nonce);
+        let length = u32::try_from(ciphertext.len() + wire_nonce.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "record too large")
+        })?;
+        socket.write_all(&length.to_be_bytes()).await?;
+        socket.write_all(wire_nonce).await?;
         socket.write_all(&ciphertext).await?;
 
         crate::util::sodium_increment_be(nonce);
 
+        crate::metrics::metrics().transit_bytes_sent(plaintext.len() as u64);
         Ok(())
     }
 
@@ -975,21 +1801,24 @@ impl Transit {
     pub fn split(
         self,
     ) -> (
-        impl futures::sink::Sink<Box<[u8]>>,
+        impl futures::sink::Sink<Box<[u8]>, Error = TransitError>,
         impl futures::stream::Stream<Item = Result<Box<[u8]>, TransitError>>,
     ) {
         use futures::io::AsyncReadExt;
 
+        let cipher = self.cipher;
+        let max_record_size = self.max_record_size;
         let (reader, writer) = self.socket.split();
         (
             futures::sink::unfold(
                 (writer, self.skey, self.snonce),
-                |(mut writer, skey, mut nonce), plaintext: Box<[u8]>| async move {
+                move |(mut writer, skey, mut nonce), plaintext: Box<[u8]>| async move {
                     Transit::send_record_inner(
                         &mut writer,
                         &skey as &Key<TransitTxKey>,
                         &plaintext,
                         &mut nonce,
+                        cipher,
                     )
                     .await
                     .map(|()| (writer, skey, nonce))
@@ -997,14 +1826,226 @@ impl Transit {
             ),
             futures::stream::try_unfold(
                 (reader, self.rkey, self.rnonce),
-                |(mut reader, rkey, mut nonce)| async move {
-                    Transit::receive_record_inner(&mut reader, &rkey, &mut nonce)
-                        .await
-                        .map(|record| Some((record, (reader, rkey, nonce))))
+                move |(mut reader, rkey, mut nonce)| async move {
+                    Transit::receive_record_inner(
+                        &mut reader,
+                        &rkey,
+                        &mut nonce,
+                        cipher,
+                        max_record_size,
+                    )
+                    .await
+                    .map(|record| Some((record, (reader, rkey, nonce))))
                 },
             ),
         )
     }
+
+    /**
+     * Convert the transit connection into an [`AsyncRead`](futures::io::AsyncRead)/[`AsyncWrite`](futures::io::AsyncWrite) pair
+     *
+     * This streams bytes over the record layer: writes are sent as records of at most [`MAX_STREAM_RECORD_SIZE`]
+     * bytes, and reads return the content of the received records. Record boundaries are not preserved. The
+     * reader ends when the other side closes the connection. Flush the writer to make sure that everything has
+     * been sent.
+     */
+    pub fn into_io(
+        self,
+    ) -> (
+        impl futures::io::AsyncRead + Unpin,
+        impl futures::io::AsyncWrite + Unpin,
+    ) {
+        let (sink, stream) = self.split();
+        let reader = Box::pin(
+            stream
+                /* Empty records would look like the end of the stream */
+                .try_filter(|record| futures::future::ready(!record.is_empty()))
+                .map_err(TransitError::into_io_error)
+                /* A closed connection is the regular end of the stream */
+                .take_while(|record| {
+                    futures::future::ready(!matches!(record, Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof))
+                }),
+        )
+        .into_async_read();
+        let writer = RecordWriter {
+            sink: Box::pin(sink.sink_map_err(TransitError::into_io_error)),
+        };
+        (reader, writer)
+    }
+}
+
+/**
+ * Several [`Transit`] connections, used as one record pipe
+ *
+ * A single TCP connection often cannot fill links with a high bandwidth-delay product. Records are
+ * therefore sent round-robin over all connections, and received in the same order, which puts them
+ * back together. Both sides must add the same connections in the same order. Each connection has its
+ * own keys and nonces, so they must have been established with different transit keys.
+ *
+ * All connections are written and read concurrently: while one of them is busy, records keep going
+ * out over (and coming in from) the others. Sent records may thus still be on their way when
+ * [`send_record`](Self::send_record) returns, use [`flush`](Self::flush) to wait for them.
+ */
+pub struct StripedTransit {
+    senders: Vec<RecordSink>,
+    receivers: Vec<RecordStream>,
+    /** Records that were read ahead of their turn, one per connection */
+    ahead: Vec<Option<Result<Box<[u8]>, TransitError>>>,
+    /** Where the next record gets sent */
+    send_index: usize,
+    /** Where the next record comes from */
+    receive_index: usize,
+}
+
+type RecordSink = std::pin::Pin<Box<dyn Sink<Box<[u8]>, Error = TransitError> + Send>>;
+type RecordStream = std::pin::Pin<Box<dyn Stream<Item = Result<Box<[u8]>, TransitError>> + Send>>;
+
+impl From<Transit> for StripedTransit {
+    fn from(transit: Transit) -> Self {
+        let mut striped = StripedTransit {
+            senders: Vec::new(),
+            receivers: Vec::new(),
+            ahead: Vec::new(),
+            send_index: 0,
+            receive_index: 0,
+        };
+        striped.push(transit);
+        striped
+    }
+}
+
+impl StripedTransit {
+    /** Add another connection. This must happen before the first record */
+    pub fn push(&mut self, transit: Transit) {
+        let (sink, stream) = transit.split();
+        self.senders.push(Box::pin(sink));
+        self.receivers.push(Box::pin(stream));
+        self.ahead.push(None);
+    }
+
+    /** The number of connections */
+    pub fn streams(&self) -> usize {
+        self.senders.len()
+    }
+
+    /** Receive and decrypt the next message from the other side */
+    pub async fn receive_record(&mut self) -> Result<Box<[u8]>, TransitError> {
+        let index = self.receive_index;
+        let Self {
+            receivers, ahead, ..
+        } = self;
+        let record = futures::future::poll_fn(|cx| {
+            /* Read ahead on the other connections, so that their senders don't stall */
+            for (other, (receiver, slot)) in receivers.iter_mut().zip(ahead.iter_mut()).enumerate()
+            {
+                if other != index && slot.is_none() {
+                    if let std::task::Poll::Ready(record) = receiver.as_mut().poll_next(cx) {
+                        *slot = Some(record.unwrap_or_else(|| Err(closed())));
+                    }
+                }
+            }
+            match ahead[index].take() {
+                Some(record) => std::task::Poll::Ready(record),
+                None => receivers[index]
+                    .as_mut()
+                    .poll_next(cx)
+                    .map(|record| record.unwrap_or_else(|| Err(closed()))),
+            }
+        })
+        .await?;
+        self.receive_index = (self.receive_index + 1) % self.receivers.len();
+        Ok(record)
+    }
+
+    /**
+     * Send an encrypted message to the other side, over the next connection
+     *
+     * This only waits until that connection is ready to take it, see [`flush`](Self::flush).
+     */
+    pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
+        let index = self.send_index;
+        let senders = &mut self.senders;
+        futures::future::poll_fn(|cx| {
+            /* Keep the other connections going while this one is busy */
+            for (other, sender) in senders.iter_mut().enumerate() {
+                if other != index {
+                    if let std::task::Poll::Ready(Err(error)) = sender.as_mut().poll_flush(cx) {
+                        return std::task::Poll::Ready(Err(error));
+                    }
+                }
+            }
+            senders[index].as_mut().poll_ready(cx)
+        })
+        .await?;
+        senders[index].as_mut().start_send(plaintext.into())?;
+        self.send_index = (self.send_index + 1) % self.senders.len();
+        Ok(())
+    }
+
+    /** Wait until all records have been sent */
+    pub async fn flush(&mut self) -> Result<(), TransitError> {
+        futures::future::try_join_all(self.senders.iter_mut().map(|sender| sender.flush())).await?;
+        Ok(())
+    }
+}
+
+/* A connection of a [`StripedTransit`] ended, although more records were expected */
+fn closed() -> TransitError {
+    std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "The transit connection was closed",
+    )
+    .into()
+}
+
+/** The maximum number of bytes per record sent by the writer from [`Transit::into_io`] */
+pub const MAX_STREAM_RECORD_SIZE: usize = 64 * 1024;
+
+impl TransitError {
+    fn into_io_error(self) -> std::io::Error {
+        match self {
+            TransitError::IO(err) => err,
+            other => std::io::Error::new(std::io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/** Writes bytes as records into a [`Sink`], see [`Transit::into_io`] */
+struct RecordWriter<S> {
+    sink: std::pin::Pin<Box<S>>,
+}
+
+impl<S> futures::io::AsyncWrite for RecordWriter<S>
+where
+    S: Sink<Box<[u8]>, Error = std::io::Error>,
+{
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return std::task::Poll::Ready(Ok(0));
+        }
+        futures::ready!(self.sink.as_mut().poll_ready(cx))?;
+        let len = buf.len().min(MAX_STREAM_RECORD_SIZE);
+        self.sink.as_mut().start_send(buf[..len].into())?;
+        std::task::Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.sink.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        self.sink.as_mut().poll_close(cx)
+    }
 }
 
 /**
@@ -1019,7 +2060,7 @@ impl Transit {
 async fn handshake_exchange(
     is_leader: bool,
     tside: Arc<String>,
-    mut socket: TcpStream,
+    mut socket: TransitStream,
     host_type: HostType,
     key: Arc<Key<TransitKey>>,
 ) -> Result<Transit, TransitHandshakeError> {
@@ -1042,33 +2083,35 @@ async fn handshake_exchange(
         trace!("initiating relay handshake");
 
         let sub_key = key.derive_subkey_from_purpose::<crate::GenericKey>("transit_relay_token");
-        socket
-            .write_all(format!("please relay {} for side {}\n", sub_key.to_hex(), tside).as_bytes())
-            .await?;
+        let tx = format!("please relay {} for side {}\n", sub_key.to_hex(), tside);
+        record(Direction::Sent, tx.as_bytes());
+        socket.write_all(tx.as_bytes()).await?;
         let mut rx = [0u8; 3];
         socket.read_exact(&mut rx).await?;
+        record(Direction::Received, &rx);
         let ok_msg: [u8; 3] = *b"ok\n";
-        ensure!(ok_msg == rx, TransitHandshakeError::RelayHandshakeFailed);
+        ensure!(
+            bool::from(ok_msg.ct_eq(&rx)),
+            TransitHandshakeError::RelayHandshakeFailed
+        );
     }
 
     if is_leader {
         // for transmit mode, send send_handshake_msg and compare.
         // the received message with send_handshake_msg
-        socket
-            .write_all(
-                format!(
-                    "transit sender {} ready\n\n",
-                    key.derive_subkey_from_purpose::<crate::GenericKey>("transit_sender")
-                        .to_hex()
-                )
-                .as_bytes(),
-            )
-            .await?;
+        let tx = format!(
+            "transit sender {} ready\n\n",
+            key.derive_subkey_from_purpose::<crate::GenericKey>("transit_sender")
+                .to_hex()
+        );
+        record(Direction::Sent, tx.as_bytes());
+        socket.write_all(tx.as_bytes()).await?;
 
         // The received message "transit sender $hash ready\n\n" has exactly 89 bytes
         // TODO do proper line parsing one day, this is atrocious
         let mut rx: [u8; 89] = [0; 89];
         socket.read_exact(&mut rx).await?;
+        record(Direction::Received, &rx);
 
         let expected_rx_handshake = format!(
             "transit receiver {} ready\n\n",
@@ -1076,28 +2119,26 @@ async fn handshake_exchange(
                 .to_hex()
         );
         ensure!(
-            &rx[..] == expected_rx_handshake.as_bytes(),
+            bool::from(rx[..].ct_eq(expected_rx_handshake.as_bytes())),
             TransitHandshakeError::HandshakeFailed,
         );
     } else {
         // for receive mode, send receive_handshake_msg and compare.
         // the received message with send_handshake_msg
-        socket
-            .write_all(
-                format!(
-                    "transit receiver {} ready\n\n",
-                    key.derive_subkey_from_purpose::<crate::GenericKey>("transit_receiver")
-                        .to_hex(),
-                )
-                .as_bytes(),
-            )
-            .await?;
+        let tx = format!(
+            "transit receiver {} ready\n\n",
+            key.derive_subkey_from_purpose::<crate::GenericKey>("transit_receiver")
+                .to_hex(),
+        );
+        record(Direction::Sent, tx.as_bytes());
+        socket.write_all(tx.as_bytes()).await?;
 
         // The received message "transit receiver $hash ready\n\n" has exactly 87 bytes
         // Three bytes for the "go\n" ack
         // TODO do proper line parsing one day, this is atrocious
         let mut rx: [u8; 90] = [0; 90];
         socket.read_exact(&mut rx).await?;
+        record(Direction::Received, &rx);
 
         let expected_tx_handshake = format!(
             "transit sender {} ready\n\ngo\n",
@@ -1105,7 +2146,7 @@ async fn handshake_exchange(
                 .to_hex(),
         );
         ensure!(
-            &rx[..] == expected_tx_handshake.as_bytes(),
+            bool::from(rx[..].ct_eq(expected_tx_handshake.as_bytes())),
             TransitHandshakeError::HandshakeFailed
         );
     }
@@ -1116,5 +2157,7 @@ async fn handshake_exchange(
         rkey,
         snonce: Default::default(),
         rnonce: Default::default(),
+        cipher: RecordCipher::default(),
+        max_record_size: DEFAULT_MAX_RECORD_SIZE,
     })
 }
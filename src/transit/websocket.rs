@@ -0,0 +1,101 @@
+//! Transit relay connections tunneled over WebSocket, see [`Ability::RelayWebsocketV1`](super::Ability::RelayWebsocketV1)
+//!
+//! The relay sees the same byte stream as over TCP, only chopped into binary messages. Message boundaries carry
+//! no meaning, the records are framed by their length prefix as usual.
+
+use async_tungstenite::{async_std::ConnectStream, tungstenite as ws2, WebSocketStream};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Sink, Stream,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+fn into_io_error(error: ws2::Error) -> io::Error {
+    match error {
+        ws2::Error::Io(error) => error,
+        ws2::Error::ConnectionClosed | ws2::Error::AlreadyClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        },
+        error => io::Error::new(io::ErrorKind::Other, error),
+    }
+}
+
+/** Connect to the relay at `url`, which may be `ws://` or `wss://` */
+pub(super) async fn connect(url: &str) -> io::Result<WebsocketStream> {
+    let (inner, _response) = async_tungstenite::async_std::connect_async(url)
+        .await
+        .map_err(into_io_error)?;
+    Ok(WebsocketStream {
+        inner,
+        read_buffer: Vec::new(),
+        read_offset: 0,
+    })
+}
+
+/** A WebSocket connection as a byte stream */
+pub(super) struct WebsocketStream {
+    inner: WebSocketStream<ConnectStream>,
+    /* The rest of the last received message */
+    read_buffer: Vec<u8>,
+    read_offset: usize,
+}
+
+impl AsyncRead for WebsocketStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.read_offset == self.read_buffer.len() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(ws2::Message::Binary(data))) => {
+                    self.read_buffer = data;
+                    self.read_offset = 0;
+                },
+                /* Pings are answered by tungstenite, nothing else is expected from a relay */
+                Some(Ok(ws2::Message::Close(_))) | None => return Poll::Ready(Ok(0)),
+                Some(Ok(_)) => {},
+                Some(Err(ws2::Error::ConnectionClosed)) => return Poll::Ready(Ok(0)),
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+
+        let this = &mut *self;
+        let len = buf.len().min(this.read_buffer.len() - this.read_offset);
+        buf[..len].copy_from_slice(&this.read_buffer[this.read_offset..this.read_offset + len]);
+        this.read_offset += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for WebsocketStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(ws2::Message::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+        /* Nobody flushes a TCP stream, so push the message out right away. Errors will show up on the next call */
+        let _ = Pin::new(&mut self.inner).poll_flush(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
@@ -6,9 +6,10 @@ macro_rules! ensure {
     };
 }
 
+#[allow(unused_macros)]
 macro_rules! bail {
     ($err:expr $(,)?) => {
-        return std::result::Result::Err($err.into());
+        return std::result::Result::Err($err.into())
     };
 }
 